@@ -1,23 +1,37 @@
+ORG 0000H;
+LXI SP,0FFFFH;
+; counter = 0x00
 MVI A,00H;
 STA 8000H;
-MVI A,FFH;
-STA 8001H;
 MOV B,A;
+; limit = 0xFF
+MVI A,0FFH;
+STA 8001H;
+MOV C,A;
+; status = 0x05
 MVI A,05H;
 STA 8002H;
-MOV C,A;
-CMP B;
-JZ SKIP_0;
-JNC SKIP_0;
-MVI D,AAH;
-SKIP_0:
-MOV A,C;
-CMP B;
-JZ SKIP_1;
-JC SKIP_1;
-MVI E,BBH;
-SKIP_1:
+MOV D,A;
+; if(counter < limit)
+MOV A,B;
+CMP C;
+JNC _SKIP_0;
+; reg D = 0xAA
+MVI D,0AAH;
+_SKIP_0:
+; if(status > limit)
+MOV A,D;
+CMP C;
+JZ _SKIP_1;
+JC _SKIP_1;
+; reg E = 0xBB
+MVI E,0BBH;
+_SKIP_1:
+; if(A == B)
 CMP B;
-JNZ SKIP_2;
-MVI H,CCH;
-SKIP_2:
+JNZ _SKIP_2;
+; reg H = 0xCC
+MVI H,0CCH;
+_SKIP_2:
+HLT;
+END;
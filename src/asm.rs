@@ -0,0 +1,262 @@
+// src/asm.rs
+
+use std::fmt;
+
+/// The 8-bit ALU operations that have both a register/M form (`ADD B`) and
+/// an immediate form (`ADI 05H`).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AluOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Cmp,
+}
+
+impl AluOp {
+    fn mnemonic(self) -> &'static str {
+        match self {
+            AluOp::Add => "ADD",
+            AluOp::Sub => "SUB",
+            AluOp::And => "ANA",
+            AluOp::Or => "ORA",
+            AluOp::Xor => "XRA",
+            AluOp::Cmp => "CMP",
+        }
+    }
+
+    fn immediate_mnemonic(self) -> &'static str {
+        match self {
+            AluOp::Add => "ADI",
+            AluOp::Sub => "SUI",
+            AluOp::And => "ANI",
+            AluOp::Or => "ORI",
+            AluOp::Xor => "XRI",
+            AluOp::Cmp => "CPI",
+        }
+    }
+}
+
+/// A memory address operand: a fixed address, or a data label plus a byte
+/// offset in relocatable output.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Address {
+    Fixed(u16),
+    Label(String, u16),
+}
+
+impl Address {
+    /// The address `by` bytes further on
+    pub fn offset(&self, by: u16) -> Address {
+        match self {
+            Address::Fixed(address) => Address::Fixed(address.wrapping_add(by)),
+            Address::Label(name, offset) => Address::Label(name.clone(), offset + by),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Fixed(address) => write!(f, "{}", hex_number(&format!("{:04X}", address))),
+            Address::Label(name, 0) => write!(f, "{}", name),
+            Address::Label(name, offset) => write!(f, "{}+{}", name, offset),
+        }
+    }
+}
+
+/// One line of generated assembly. Registers and pairs are written as they
+/// appear in the output (`A`, `M`, `H`, `PSW`), immediates as their hex
+/// digits without the `H` suffix, and jump and call targets as label names
+/// or addresses.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AsmInstruction {
+    Comment(String),
+    // The source line the following code was generated from, or none for
+    // code of no statement. Writes nothing; see `source_map`.
+    Source(Option<usize>),
+    Label(String),
+    Org(u16),
+    End,
+
+    // Data transfer
+    Mov { dest: String, src: String },
+    Mvi { dest: String, value: String },
+    Lxi { pair: String, value: String },
+    // LXI of a memory address, such as a variable's
+    LxiAddress { pair: String, address: Address },
+    Lda(Address),
+    Sta(Address),
+    Lhld(Address),
+    Shld(Address),
+    Ldax(String),
+    Stax(String),
+    Xchg,
+    Sphl,
+    Pchl,
+    Push(String),
+    Pop(String),
+    In(String),
+    Out(String),
+
+    // Arithmetic and logic
+    Alu(AluOp, String),
+    AluImmediate(AluOp, String),
+    Adc(String),
+    Dad(String),
+    Inx(String),
+    Dcx(String),
+    Inr(String),
+    Dcr(String),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Daa,
+    Cma,
+    Cmc,
+
+    // Control flow
+    Jmp(String),
+    Jz(String),
+    Jnz(String),
+    Jc(String),
+    Jnc(String),
+    Call(String),
+    Ret,
+    Rst(u8),
+    Nop,
+    // Replaced with NOPs up to the boundary once instruction sizes are final
+    Align(u16),
+    Ei,
+    Di,
+    Rim,
+    Sim,
+    Hlt,
+
+    // Data definitions
+    Db(Vec<u8>),
+    Dw(Vec<u16>),
+    Ds(u16),
+}
+
+impl fmt::Display for AsmInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmInstruction::Comment(text) => write!(f, "; {}", text),
+            AsmInstruction::Source(_) => Ok(()),
+            AsmInstruction::Label(name) => write!(f, "{}:", name),
+            AsmInstruction::Org(address) => {
+                write!(f, "ORG {};", hex_number(&format!("{:04X}", address)))
+            }
+            AsmInstruction::End => write!(f, "END;"),
+            AsmInstruction::Mov { dest, src } => write!(f, "MOV {},{};", dest, src),
+            AsmInstruction::Mvi { dest, value } => write!(f, "MVI {},{};", dest, hex_number(value)),
+            AsmInstruction::Lxi { pair, value } => write!(f, "LXI {},{};", pair, hex_number(value)),
+            AsmInstruction::LxiAddress { pair, address } => write!(f, "LXI {},{};", pair, address),
+            AsmInstruction::Lda(address) => write!(f, "LDA {};", address),
+            AsmInstruction::Sta(address) => write!(f, "STA {};", address),
+            AsmInstruction::Lhld(address) => write!(f, "LHLD {};", address),
+            AsmInstruction::Shld(address) => write!(f, "SHLD {};", address),
+            AsmInstruction::Ldax(pair) => write!(f, "LDAX {};", pair),
+            AsmInstruction::Stax(pair) => write!(f, "STAX {};", pair),
+            AsmInstruction::Xchg => write!(f, "XCHG;"),
+            AsmInstruction::Sphl => write!(f, "SPHL;"),
+            AsmInstruction::Pchl => write!(f, "PCHL;"),
+            AsmInstruction::Push(pair) => write!(f, "PUSH {};", pair),
+            AsmInstruction::Pop(pair) => write!(f, "POP {};", pair),
+            AsmInstruction::In(port) => write!(f, "IN {};", hex_number(port)),
+            AsmInstruction::Out(port) => write!(f, "OUT {};", hex_number(port)),
+            AsmInstruction::Alu(op, register) => write!(f, "{} {};", op.mnemonic(), register),
+            AsmInstruction::AluImmediate(op, value) => {
+                write!(f, "{} {};", op.immediate_mnemonic(), hex_number(value))
+            }
+            AsmInstruction::Adc(register) => write!(f, "ADC {};", register),
+            AsmInstruction::Dad(pair) => write!(f, "DAD {};", pair),
+            AsmInstruction::Inx(pair) => write!(f, "INX {};", pair),
+            AsmInstruction::Dcx(pair) => write!(f, "DCX {};", pair),
+            AsmInstruction::Inr(register) => write!(f, "INR {};", register),
+            AsmInstruction::Dcr(register) => write!(f, "DCR {};", register),
+            AsmInstruction::Rlc => write!(f, "RLC;"),
+            AsmInstruction::Rrc => write!(f, "RRC;"),
+            AsmInstruction::Ral => write!(f, "RAL;"),
+            AsmInstruction::Rar => write!(f, "RAR;"),
+            AsmInstruction::Daa => write!(f, "DAA;"),
+            AsmInstruction::Cma => write!(f, "CMA;"),
+            AsmInstruction::Cmc => write!(f, "CMC;"),
+            AsmInstruction::Jmp(target) => write!(f, "JMP {};", target),
+            AsmInstruction::Jz(target) => write!(f, "JZ {};", target),
+            AsmInstruction::Jnz(target) => write!(f, "JNZ {};", target),
+            AsmInstruction::Jc(target) => write!(f, "JC {};", target),
+            AsmInstruction::Jnc(target) => write!(f, "JNC {};", target),
+            AsmInstruction::Call(target) => write!(f, "CALL {};", target),
+            AsmInstruction::Ret => write!(f, "RET;"),
+            AsmInstruction::Rst(vector) => write!(f, "RST {};", vector),
+            AsmInstruction::Nop => write!(f, "NOP;"),
+            AsmInstruction::Align(boundary) => {
+                write!(f, "; align {}", hex_number(&format!("{:04X}", boundary)))
+            }
+            AsmInstruction::Ei => write!(f, "EI;"),
+            AsmInstruction::Di => write!(f, "DI;"),
+            AsmInstruction::Rim => write!(f, "RIM;"),
+            AsmInstruction::Sim => write!(f, "SIM;"),
+            AsmInstruction::Hlt => write!(f, "HLT;"),
+            AsmInstruction::Db(values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| hex_number(&format!("{:02X}", value)))
+                    .collect();
+                write!(f, "DB {};", values.join(","))
+            }
+            AsmInstruction::Dw(values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| hex_number(&format!("{:04X}", value)))
+                    .collect();
+                write!(f, "DW {};", values.join(","))
+            }
+            AsmInstruction::Ds(size) => write!(f, "DS {};", hex_number(&format!("{:04X}", size))),
+        }
+    }
+}
+
+/// Hex digits as an assembler number, e.g. 05H. Digits starting with a letter
+/// get a leading 0 (0FFH), which assemblers need to tell them from a name.
+pub fn hex_number(digits: &str) -> String {
+    if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}H", digits)
+    } else {
+        format!("{}H", digits)
+    }
+}
+
+/// Writes instructions out as assembly text, one per line.
+pub fn serialize(instructions: &[AsmInstruction]) -> String {
+    instructions
+        .iter()
+        .filter(|instruction| !matches!(instruction, AsmInstruction::Source(_)))
+        .map(|instruction| format!("{}\n", instruction))
+        .collect()
+}
+
+/// Pairs each line `serialize` writes after a `Source` marker with that
+/// marker's source line, one "asm_line c85_line" pair per line, both 1-based.
+/// Lines of no statement are left out.
+pub fn source_map(instructions: &[AsmInstruction]) -> String {
+    let mut map = String::from("; asm line -> c85 line\n");
+    let mut source = None;
+    let mut line = 0;
+    for instruction in instructions {
+        match instruction {
+            AsmInstruction::Source(marked) => source = *marked,
+            _ => {
+                line += 1;
+                if let Some(source) = source {
+                    map.push_str(&format!("{} {}\n", line, source));
+                }
+            }
+        }
+    }
+    map
+}
@@ -1,167 +1,2441 @@
 // src/codegen.rs
 
-use crate::parser::{Statement, BinaryOperator, Condition};
+use crate::asm::{self, Address, AluOp, AsmInstruction};
+use crate::parser::{
+    BinaryOperator, Condition, Guard, Operand, RotateDirection, Statement, collect_constants,
+    hex_value, is_8bit_register, is_16bit_register,
+};
+use crate::peephole;
+use crate::semantic::{self, block_effects};
 use std::collections::HashMap;
 
+/// Options that control how assembly is generated.
+#[derive(Debug, Clone)]
+pub struct GenerateOptions {
+    /// Append `HLT` after the program so execution stops cleanly.
+    pub emit_halt: bool,
+    /// Address the code is loaded at, emitted as an `ORG` directive.
+    pub code_origin: u16,
+    /// Address static variables and arrays are allocated from. Trainer kits
+    /// put their RAM in different places.
+    pub data_start: u16,
+    /// Address the stack pointer is set to before the program runs, or `None`
+    /// to keep whatever the monitor left in SP.
+    pub stack_top: Option<u16>,
+    /// Precede each statement's instructions with a comment showing its source.
+    pub comments: bool,
+    /// Address of the monitor routine `print` calls with the value in A.
+    /// Trainer ROMs differ; the default is the RST 7 vector.
+    pub print_routine: u16,
+    /// Refer to variables and arrays by label and define them with `DB`,
+    /// `DW` and `DS` after the code, instead of at fixed addresses from
+    /// `data_start`, so the assembler can place the program anywhere.
+    pub relocatable: bool,
+    /// The processor the code must run on.
+    pub target: Target,
+    /// Reject variables that are widened to 16 bits but also used as 8-bit
+    /// operands, instead of only warning about them.
+    pub strict: bool,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        GenerateOptions {
+            emit_halt: true,
+            code_origin: 0x0000,
+            data_start: 0x8000,
+            stack_top: Some(0xFFFF),
+            comments: true,
+            print_routine: 0x0038,
+            relocatable: false,
+            target: Target::Intel8085,
+            strict: false,
+        }
+    }
+}
+
+/// The instruction set to generate code for. The 8085 runs all 8080 code
+/// and adds a few instructions of its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Intel8080,
+    Intel8085,
+}
+
+/// Instructions the 8080 doesn't have
+const INTEL_8085_ONLY: [&str; 2] = ["RIM", "SIM"];
+
 /// Generates 8085 assembly code from a list of Statements.
-pub fn generate(statements: &[Statement]) -> String {
-    let mut asm_code = String::new();
-    let mut static_vars: HashMap<String, u16> = HashMap::new();
-    let mut var_to_register: HashMap<String, String> = HashMap::new();
-    let mut next_address = 0x8000u16;
+pub fn generate(statements: &[Statement], options: &GenerateOptions) -> Result<String, String> {
+    let asm_code = asm::serialize(&generate_instructions(statements, &[], options)?);
+
+    if options.target == Target::Intel8080 {
+        check_8080(&asm_code)?;
+    }
+
+    Ok(asm_code)
+}
+
+/// Generates the code of `statements` like `generate` and maps each line of
+/// it to a source line; `lines` holds the line of every statement in the
+/// order `parser::parse_with_lines` returns them.
+pub fn source_map(
+    statements: &[Statement],
+    lines: &[usize],
+    options: &GenerateOptions,
+) -> Result<String, String> {
+    Ok(asm::source_map(&generate_instructions(
+        statements, lines, options,
+    )?))
+}
+
+/// The instructions of the whole program, after the peephole pass. With
+/// `lines`, each statement's code is preceded by a `Source` marker.
+fn generate_instructions(
+    statements: &[Statement],
+    lines: &[usize],
+    options: &GenerateOptions,
+) -> Result<Vec<AsmInstruction>, String> {
+    let mut asm_code = Vec::new();
+    // One counter numbers the labels of the whole program, main and functions
+    // alike. Each construct claims its number before generating its body and
+    // the counter never goes back, so nested blocks can't reuse a label.
     let mut label_counter = 0;
-    let registers = vec!["A", "B", "C", "D", "E"];
-    let mut register_idx = 0;
 
     // First pass: allocate addresses and assign registers for static variables
-    allocate_static_vars(statements, &mut static_vars, &mut next_address, &mut var_to_register, &registers, &mut register_idx);
+    let Allocation {
+        static_vars,
+        var_to_register,
+        arrays,
+        next_address,
+    } = allocate(statements, options.data_start);
+    let mut constants = HashMap::new();
+    // Duplicate constants have already been rejected by the parser
+    let _ = collect_constants(statements, &mut constants);
+    let mut statement_lines = HashMap::new();
+    map_lines(statements, &mut lines.iter().copied(), &mut statement_lines);
+    let symbols = Symbols {
+        static_vars,
+        var_to_register,
+        arrays,
+        constants,
+        program: statements,
+        relocatable: options.relocatable,
+        lines: statement_lines,
+    };
+
+    asm_code.push(AsmInstruction::Org(options.code_origin));
+    // CALL and PUSH write below SP, which is undefined at reset
+    if let Some(top) = options.stack_top {
+        asm_code.push(AsmInstruction::Lxi {
+            pair: "SP".to_string(),
+            value: format!("{:04X}", top),
+        });
+    }
 
     // Second pass: generate code
     for statement in statements {
-        generate_statement(statement, &static_vars, &var_to_register, &mut asm_code, &mut label_counter);
+        generate_statement(
+            statement,
+            &symbols,
+            options,
+            &mut asm_code,
+            &mut label_counter,
+            &mut Vec::new(),
+        )?;
+    }
+
+    if !symbols.lines.is_empty() {
+        asm_code.push(AsmInstruction::Source(None));
+    }
+    // A return at the end of main has already halted
+    let returned = statements
+        .iter()
+        .rfind(|statement| !matches!(statement, Statement::FunctionDef { .. }));
+    if options.emit_halt && !matches!(returned, Some(Statement::Return { .. })) {
+        asm_code.push(AsmInstruction::Hlt);
+    }
+
+    // Subroutines go after main so that main runs first
+    let mut functions = Vec::new();
+    collect_functions(statements, &mut functions);
+    for (function, name, body) in functions {
+        mark_source(function, &symbols, &mut asm_code);
+        asm_code.push(AsmInstruction::Label(name.clone()));
+        for statement in body {
+            generate_statement(
+                statement,
+                &symbols,
+                options,
+                &mut asm_code,
+                &mut label_counter,
+                &mut Vec::new(),
+            )?;
+        }
+        mark_source(function, &symbols, &mut asm_code);
+        asm_code.push(AsmInstruction::Ret);
+    }
+
+    if !symbols.lines.is_empty() {
+        asm_code.push(AsmInstruction::Source(None));
+    }
+    data_definitions(&symbols, options, &mut asm_code)?;
+    asm_code.push(AsmInstruction::End);
+
+    // Drop moves that don't change anything
+    let data_region = options.data_start..u16::try_from(next_address).unwrap_or(u16::MAX);
+    let asm_code = peephole::optimize(asm_code, data_region);
+    let asm_code = expand_alignment(asm_code, options.code_origin)?;
+    check_layout(&asm_code, options, next_address)?;
+    Ok(asm_code)
+}
+
+/// Pairs every statement with its line, visiting them in the order
+/// `parser::parse_with_lines` lists the lines. Folding keeps the shape of the
+/// AST, so the order still holds for the statements codegen sees.
+fn map_lines(
+    statements: &[Statement],
+    lines: &mut impl Iterator<Item = usize>,
+    map: &mut HashMap<*const Statement, usize>,
+) {
+    for statement in statements {
+        let Some(line) = lines.next() else { return };
+        map.insert(statement, line);
+        match statement {
+            Statement::If {
+                body, else_body, ..
+            } => {
+                map_lines(body, lines, map);
+                if let Some(else_body) = else_body {
+                    map_lines(else_body, lines, map);
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                map_lines(body, lines, map);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Marks the code that follows as coming from `statement`'s line
+fn mark_source(statement: &Statement, symbols: &Symbols, asm_code: &mut Vec<AsmInstruction>) {
+    if let Some(&line) = symbols.lines.get(&std::ptr::from_ref(statement)) {
+        asm_code.push(AsmInstruction::Source(Some(line)));
+    }
+}
+
+/// Defines the data after the code. Relocatable output defines every
+/// variable and array under its own label, in allocation order; otherwise
+/// only data tables are defined, each at its fixed address. A variable named
+/// like a label or function would define that label twice, and one named
+/// like a register would be read as that register.
+fn data_definitions(
+    symbols: &Symbols,
+    options: &GenerateOptions,
+    asm_code: &mut Vec<AsmInstruction>,
+) -> Result<(), String> {
+    let mut data: Vec<(&String, u16, AsmInstruction)> = Vec::new();
+    if options.relocatable {
+        for (name, var) in &symbols.static_vars {
+            data.push((
+                name,
+                var.address,
+                if var.is_16bit {
+                    AsmInstruction::Dw(vec![0])
+                } else {
+                    AsmInstruction::Db(vec![0])
+                },
+            ));
+        }
+    }
+    for (name, array) in &symbols.arrays {
+        match &array.contents {
+            Some(contents) => data.push((name, array.address, contents.clone())),
+            None if options.relocatable => {
+                data.push((name, array.address, AsmInstruction::Ds(array.size)))
+            }
+            None => {}
+        }
+    }
+    data.sort_by_key(|(_, address, _)| *address);
+
+    if options.comments && !data.is_empty() {
+        asm_code.push(AsmInstruction::Comment("data".to_string()));
+    }
+    for (name, address, definition) in data {
+        // Assemblers read register names in any case, so `b` would mean B
+        if options.relocatable
+            && ["A", "B", "C", "D", "E", "H", "L", "M", "SP", "PSW"]
+                .contains(&name.to_uppercase().as_str())
+        {
+            return Err(format!(
+                "Variable '{}' would be read as a register name when used as a label in relocatable output. Rename it.",
+                name
+            ));
+        }
+        if asm_code.contains(&AsmInstruction::Label(name.clone())) {
+            return Err(format!(
+                "'{}' has the same name as a label or function, so its data label would be defined twice. Rename one of them.",
+                name
+            ));
+        }
+        if !options.relocatable {
+            asm_code.push(AsmInstruction::Org(address));
+        }
+        asm_code.push(AsmInstruction::Label(name.clone()));
+        asm_code.push(definition);
+    }
+    Ok(())
+}
+
+/// Replaces each align() with the NOPs that bring the code, which starts at
+/// `origin`, up to its boundary. This runs after the peephole pass, so the
+/// sizes counted are those of the final code.
+fn expand_alignment(
+    instructions: Vec<AsmInstruction>,
+    origin: u16,
+) -> Result<Vec<AsmInstruction>, String> {
+    let mut expanded = Vec::with_capacity(instructions.len());
+    let mut address = u32::from(origin);
+    for instruction in instructions {
+        match &instruction {
+            AsmInstruction::Align(boundary) => {
+                let boundary = u32::from(*boundary);
+                let padding = (boundary - address % boundary) % boundary;
+                expanded.extend(std::iter::repeat_n(AsmInstruction::Nop, padding as usize));
+                address += padding;
+                continue;
+            }
+            AsmInstruction::Comment(_)
+            | AsmInstruction::Source(_)
+            | AsmInstruction::Label(_)
+            | AsmInstruction::Org(_)
+            | AsmInstruction::End => {}
+            // Data follows all of the code, so nothing is left to align
+            AsmInstruction::Db(_) | AsmInstruction::Dw(_) | AsmInstruction::Ds(_) => {}
+            _ => {
+                let text = instruction.to_string();
+                address += u32::from(instruction_size(
+                    text.split([' ', ';']).next().unwrap_or(""),
+                )?);
+            }
+        }
+        expanded.push(instruction);
+    }
+    Ok(expanded)
+}
+
+/// Rejects a data region that runs past the end of memory or overlaps the
+/// code, which starts at the origin and takes up as many bytes as its
+/// instructions encode to.
+fn check_layout(
+    instructions: &[AsmInstruction],
+    options: &GenerateOptions,
+    data_end: u32,
+) -> Result<(), String> {
+    // Relocatable data follows the code wherever the assembler puts it
+    if options.relocatable {
+        return Ok(());
+    }
+    let data_start = u32::from(options.data_start);
+    if data_end > 0x10000 {
+        return Err(format!(
+            "The data region starting at {:04X}H needs {} byte(s) and runs past FFFFH. Pass a lower --data address.",
+            data_start,
+            data_end - data_start
+        ));
+    }
+
+    let mut code_size = 0u32;
+    for instruction in instructions {
+        if matches!(
+            instruction,
+            AsmInstruction::Comment(_)
+                | AsmInstruction::Source(_)
+                | AsmInstruction::Label(_)
+                | AsmInstruction::Org(_)
+                | AsmInstruction::End
+        ) {
+            continue;
+        }
+        // Data tables live in the data region, not the code
+        if matches!(
+            instruction,
+            AsmInstruction::Db(_) | AsmInstruction::Dw(_) | AsmInstruction::Ds(_)
+        ) {
+            break;
+        }
+        let text = instruction.to_string();
+        code_size += u32::from(instruction_size(
+            text.split([' ', ';']).next().unwrap_or(""),
+        )?);
+    }
+    let code_start = u32::from(options.code_origin);
+    let code_end = code_start + code_size;
+    if data_start < data_end && code_start < data_end && data_start < code_end {
+        return Err(format!(
+            "The data region {:04X}H-{:04X}H collides with the code at {:04X}H-{:04X}H. Pass a different --data or --org address.",
+            data_start,
+            data_end - 1,
+            code_start,
+            code_end - 1
+        ));
+    }
+    // With the default ORG 0000H, a program past 56 bytes covers RST 7
+    let print_routine = u32::from(options.print_routine);
+    let print_call = asm::hex_number(&format!("{:04X}", options.print_routine));
+    let calls_print = instructions.iter().any(
+        |instruction| matches!(instruction, AsmInstruction::Call(target) if *target == print_call),
+    );
+    if calls_print && code_start <= print_routine && print_routine < code_end {
+        return Err(format!(
+            "The print routine at {:04X}H is inside the code at {:04X}H-{:04X}H, so print() would call into the program. Pass --print-routine with your monitor's display routine, or a higher --org address.",
+            print_routine,
+            code_start,
+            code_end - 1
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects code that uses 8085-only instructions
+fn check_8080(asm_code: &str) -> Result<(), String> {
+    for line in asm_code.lines() {
+        let mnemonic = line.split([' ', ';']).next().unwrap_or("");
+        if INTEL_8085_ONLY.contains(&mnemonic) {
+            return Err(format!(
+                "{} is only available on the 8085, but the target is the 8080",
+                mnemonic
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Collect every function definition in the program, wherever it was written
+fn collect_functions<'a>(
+    statements: &'a [Statement],
+    functions: &mut Vec<(&'a Statement, &'a String, &'a [Statement])>,
+) {
+    for statement in statements {
+        match statement {
+            Statement::FunctionDef { name, body } => {
+                functions.push((statement, name, body));
+                collect_functions(body, functions);
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_functions(body, functions);
+                if let Some(else_body) = else_body {
+                    collect_functions(else_body, functions);
+                }
+            }
+            Statement::While { body, .. } | Statement::Repeat { body, .. } => {
+                collect_functions(body, functions);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Lists each static variable with its address and backing register, and
+/// each array with its address and size, as assembler comments, along with
+/// how much of the data region is used.
+pub fn symbol_table(statements: &[Statement], data_start: u16) -> String {
+    let Allocation {
+        static_vars,
+        var_to_register,
+        arrays,
+        next_address,
+    } = allocate(statements, data_start);
+
+    let mut symbols: Vec<_> = static_vars.iter().collect();
+    symbols.sort_by_key(|(_, var)| var.address);
+
+    let mut table = String::new();
+    table.push_str(&format!(
+        "; Data region starts at {:04X}H, {} byte(s) used\n",
+        data_start,
+        next_address - u32::from(data_start)
+    ));
+    for (variable, var) in symbols {
+        let register = var_to_register
+            .get(variable)
+            .map(String::as_str)
+            .unwrap_or("-");
+        let width = if var.is_16bit { 16 } else { 8 };
+        table.push_str(&format!(
+            "; {:<16} {:04X}H {:>2}-bit {}\n",
+            variable, var.address, width, register
+        ));
+    }
+
+    let mut arrays: Vec<_> = arrays.iter().collect();
+    arrays.sort_by_key(|(_, array)| array.address);
+    for (name, array) in arrays {
+        table.push_str(&format!(
+            "; {:<16} {:04X}H {} byte(s)\n",
+            name, array.address, array.size
+        ));
+    }
+    table
+}
+
+/// Checks the regions claimed by sized malloc() calls against each other and
+/// against the static data region, returning a warning for each overlap.
+pub fn malloc_overlaps(statements: &[Statement], data_start: u16) -> Vec<String> {
+    let mut regions = Vec::new();
+    collect_mallocs(statements, &mut regions);
+
+    let mut warnings = Vec::new();
+    let data_end = allocate(statements, data_start).next_address;
+    let data_start = u32::from(data_start);
+    for (index, &(start, end)) in regions.iter().enumerate() {
+        for &(other_start, other_end) in &regions[..index] {
+            if start < other_end && other_start < end {
+                warnings.push(format!(
+                    "malloc() region {:04X}H-{:04X}H overlaps region {:04X}H-{:04X}H",
+                    start,
+                    end - 1,
+                    other_start,
+                    other_end - 1
+                ));
+            }
+        }
+        if data_start < data_end && start < data_end && data_start < end {
+            warnings.push(format!(
+                "malloc() region {:04X}H-{:04X}H overlaps the static data region {:04X}H-{:04X}H",
+                start,
+                end - 1,
+                data_start,
+                data_end - 1
+            ));
+        }
+    }
+    warnings
+}
+
+/// Checks the deepest chain of calls against the room between the stack top
+/// and the end of the data region below it. Each call takes two bytes for
+/// its return address.
+pub fn stack_overflows(statements: &[Statement], options: &GenerateOptions) -> Vec<String> {
+    let Some(top) = options.stack_top else {
+        return Vec::new();
+    };
+    let chain = match semantic::deepest_calls(statements) {
+        Ok(chain) => chain,
+        Err(name) => {
+            return vec![format!(
+                "Function '{}' can call itself, so its stack use has no limit and may run into the data below {:04X}H.",
+                name, top
+            )];
+        }
+    };
+    let top = u32::from(top);
+    let data_start = u32::from(options.data_start);
+    let floor = if data_start < top {
+        allocate(statements, options.data_start).next_address
+    } else {
+        0
+    };
+    let room = top.saturating_sub(floor);
+    let needed = 2 * (chain.len() as u32 - 1);
+    if needed > room {
+        return vec![format!(
+            "Calls nest {} deep ({}) and need {} byte(s) of stack, but only {} byte(s) are free between the data region and the stack top {:04X}H.",
+            chain.len() - 1,
+            chain.join(" -> "),
+            needed,
+            room,
+            top
+        )];
+    }
+    Vec::new()
+}
+
+/// Collect the [start, end) range of every malloc() with a size
+fn collect_mallocs(statements: &[Statement], regions: &mut Vec<(u32, u32)>) {
+    for statement in statements {
+        match statement {
+            Statement::LoadImmediateExtended {
+                address,
+                size: Some(size),
+                ..
+            } => {
+                let start = hex_value(address).unwrap_or(0) as u32;
+                regions.push((start, start + hex_value(size).unwrap_or(0) as u32));
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_mallocs(body, regions);
+                if let Some(else_body) = else_body {
+                    collect_mallocs(else_body, regions);
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                collect_mallocs(body, regions);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Where a static variable lives in the data region
+#[derive(Debug, Clone)]
+struct StaticVar {
+    address: u16,
+    is_16bit: bool,
+}
+
+/// Where a static array lives in the data region
+#[derive(Debug, Clone)]
+struct StaticArray {
+    address: u16,
+    size: u16,
+    // The DB or DW that fills a data table; arrays start out undefined
+    contents: Option<AsmInstruction>,
+}
+
+/// The data region layout: every static variable and array, plus the first
+/// unused data address, which is past FFFFH if the region doesn't fit
+struct Allocation {
+    static_vars: HashMap<String, StaticVar>,
+    var_to_register: HashMap<String, String>,
+    arrays: HashMap<String, StaticArray>,
+    next_address: u32,
+}
+
+/// Allocate addresses and registers for every static variable, then
+/// addresses for every array after them.
+fn allocate(statements: &[Statement], data_start: u16) -> Allocation {
+    let mut static_vars: HashMap<String, StaticVar> = HashMap::new();
+    let mut var_to_register: HashMap<String, String> = HashMap::new();
+    let mut arrays: HashMap<String, StaticArray> = HashMap::new();
+    let mut next_address = u32::from(data_start);
+
+    let mut variables = Vec::new();
+    let mut declared_arrays = Vec::new();
+    allocate_static_vars(statements, &mut variables, &mut declared_arrays);
+    let mut pins = Vec::new();
+    collect_pins(statements, &mut pins);
+
+    // A is left out: every assignment and operation goes through it, so a
+    // variable kept there would need extra moves. B counts repeat loops down,
+    // so it's left out of a program that has one. @reg() still pins one there.
+    let counts_in_b = has_repeat(statements);
+    let mut free: Vec<&str> = ["B", "C", "D", "E"]
+        .into_iter()
+        .filter(|register| !(counts_in_b && *register == "B"))
+        .filter(|register| !pins.iter().any(|(_, pinned)| pinned == register))
+        .collect();
+
+    for (variable, is_16bit) in variables {
+        static_vars.insert(
+            variable.clone(),
+            StaticVar {
+                address: next_address as u16,
+                is_16bit,
+            },
+        );
+        // 16-bit variables are stored little-endian across two bytes
+        next_address += if is_16bit { 2 } else { 1 };
+
+        // A pinned variable gets its register; the others take the next free one
+        let register = match pins.iter().find(|(name, _)| *name == variable) {
+            Some((_, register)) => Some(register.clone()),
+            None if !free.is_empty() => Some(free.remove(0).to_string()),
+            None => None,
+        };
+        if let Some(register) = register {
+            var_to_register.insert(variable, register);
+        }
     }
 
-    asm_code
+    // Arrays live only in memory and are never backed by a register
+    for (name, size, contents) in declared_arrays {
+        arrays.insert(
+            name,
+            StaticArray {
+                address: next_address as u16,
+                size,
+                contents,
+            },
+        );
+        next_address += u32::from(size);
+    }
+
+    Allocation {
+        static_vars,
+        var_to_register,
+        arrays,
+        next_address,
+    }
 }
 
-/// First pass: allocate addresses and assign registers for static variables
+/// Whether a repeat loop appears anywhere in the program
+fn has_repeat(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match statement {
+        Statement::Repeat { .. } => true,
+        Statement::If {
+            body, else_body, ..
+        } => has_repeat(body) || else_body.as_deref().is_some_and(has_repeat),
+        Statement::While { body, .. } | Statement::FunctionDef { body, .. } => has_repeat(body),
+        _ => false,
+    })
+}
+
+/// Collects each @reg() pin as (variable, register). Conflicting pins have
+/// already been rejected by the semantic check.
+fn collect_pins(statements: &[Statement], pins: &mut Vec<(String, String)>) {
+    for statement in statements {
+        match statement {
+            Statement::RegisterPin { variable, register } => {
+                pins.push((variable.clone(), register.clone()))
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_pins(body, pins);
+                if let Some(else_body) = else_body {
+                    collect_pins(else_body, pins);
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                collect_pins(body, pins);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// First pass: collect static variables and arrays in declaration order. A
+/// variable is 16-bit if any of its assignments needs 16 bits.
 fn allocate_static_vars(
-    statements: &[Statement], 
-    static_vars: &mut HashMap<String, u16>, 
-    next_address: &mut u16,
-    var_to_register: &mut HashMap<String, String>,
-    registers: &[&str],
-    register_idx: &mut usize
+    statements: &[Statement],
+    variables: &mut Vec<(String, bool)>,
+    arrays: &mut Vec<(String, u16, Option<AsmInstruction>)>,
 ) {
     for statement in statements {
         match statement {
-            Statement::StaticAssignment { variable, .. } => {
-                if !static_vars.contains_key(variable) {
-                    static_vars.insert(variable.clone(), *next_address);
-                    *next_address += 1;
-                    
-                    // Assign to next available register
-                    if *register_idx < registers.len() {
-                        var_to_register.insert(variable.clone(), registers[*register_idx].to_string());
-                        *register_idx += 1;
-                    }
+            Statement::ArrayDecl { name, size } => {
+                let size = hex_value(size)
+                    .and_then(|size| u16::try_from(size).ok())
+                    .unwrap_or(0);
+                arrays.push((name.clone(), size, None));
+            }
+            Statement::DataBytes {
+                label,
+                values,
+                wide,
+            } => {
+                // Values were validated by the parser; words take two bytes
+                let size = (values.len() * if *wide { 2 } else { 1 }) as u16;
+                let values = values.iter().map(|value| hex_value(value).unwrap_or(0));
+                let contents = if *wide {
+                    AsmInstruction::Dw(values.map(|value| value as u16).collect())
+                } else {
+                    AsmInstruction::Db(values.map(|value| value as u8).collect())
+                };
+                arrays.push((label.clone(), size, Some(contents)));
+            }
+            Statement::StaticAssignment {
+                variable, is_16bit, ..
+            } => match variables.iter_mut().find(|(name, _)| name == variable) {
+                Some((_, wide)) => *wide |= *is_16bit,
+                None => variables.push((variable.clone(), *is_16bit)),
+            },
+            // A comparison result is 0x00 or 0x01
+            Statement::ComparisonAssign { variable, .. }
+                if !variables.iter().any(|(name, _)| name == variable) =>
+            {
+                variables.push((variable.clone(), false));
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                allocate_static_vars(body, variables, arrays);
+                if let Some(else_body) = else_body {
+                    allocate_static_vars(else_body, variables, arrays);
                 }
             }
-            Statement::If { body, .. } => {
-                allocate_static_vars(body, static_vars, next_address, var_to_register, registers, register_idx);
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                allocate_static_vars(body, variables, arrays);
             }
             _ => {}
         }
     }
 }
 
-/// Generate assembly for a single statement
+/// Where break and continue jump to in a loop being generated. A repeat
+/// only emits these labels when something jumps to them.
+struct LoopExits {
+    next: String,
+    end: String,
+    continued: bool,
+    broken: bool,
+}
+
+/// Generate assembly for a single statement. `loops` holds the enclosing
+/// loops, innermost last.
 fn generate_statement(
-    statement: &Statement, 
-    static_vars: &HashMap<String, u16>,
-    var_to_register: &HashMap<String, String>,
-    asm_code: &mut String, 
-    label_counter: &mut i32
-) {
+    statement: &Statement,
+    symbols: &Symbols,
+    options: &GenerateOptions,
+    asm_code: &mut Vec<AsmInstruction>,
+    label_counter: &mut i32,
+    loops: &mut Vec<LoopExits>,
+) -> Result<(), String> {
+    mark_source(statement, symbols, asm_code);
+    if options.comments
+        && let Some(source) = source_text(statement)
+    {
+        asm_code.push(AsmInstruction::Comment(source));
+    }
+
     match statement {
+        Statement::MoveImmediate { register, value } if is_16bit_register(register) => {
+            asm_code.push(AsmInstruction::Lxi {
+                pair: register.clone(),
+                value: hex_digits(value),
+            });
+        }
         Statement::MoveImmediate { register, value } => {
-            let numeric_val = value.trim_start_matches("0x").trim_start_matches("0X");
-            asm_code.push_str(&format!("MVI {},{}H;\n", register, numeric_val.to_uppercase()));
-        }
-        Statement::LoadImmediateExtended { register_pair, address } => {
-            let numeric_addr = address.trim_start_matches("0x").trim_start_matches("0X");
-            asm_code.push_str(&format!("LXI {},{}H;\n", register_pair, numeric_addr.to_uppercase()));
-        }
-        Statement::StaticAssignment { variable, value, is_16bit } => {
-            let addr = static_vars[variable];
-            let numeric_val = value.trim_start_matches("0x").trim_start_matches("0X");
-            
-            if *is_16bit {
+            asm_code.push(AsmInstruction::Mvi {
+                dest: register.clone(),
+                value: hex_digits(value),
+            });
+        }
+        Statement::LoadImmediateExtended {
+            register_pair,
+            address,
+            ..
+        } => {
+            asm_code.push(AsmInstruction::Lxi {
+                pair: register_pair.clone(),
+                value: hex_digits(address),
+            });
+        }
+        Statement::StaticAssignment {
+            variable, value, ..
+        } => {
+            // Every assignment, including a reassignment, updates both the
+            // memory copy and the backing register, so they never disagree.
+            // Store using the variable's width, which may be wider than this value
+            let StaticVar {
+                address: addr,
+                is_16bit,
+            } = *symbols
+                .static_vars
+                .get(variable)
+                .ok_or_else(|| format!("No storage was allocated for variable '{}'", variable))?;
+            let addr = data_address(variable, addr, symbols);
+            let value = hex_digits(value);
+
+            if is_16bit {
                 // For 16-bit: LXI H, value; SHLD address
-                asm_code.push_str(&format!("LXI H,{}H;\n", numeric_val.to_uppercase()));
-                asm_code.push_str(&format!("SHLD {:04X}H;\n", addr));
-                
+                asm_code.push(AsmInstruction::Lxi {
+                    pair: "H".to_string(),
+                    value,
+                });
+                asm_code.push(AsmInstruction::Shld(addr));
+
                 // If assigned to a register, load lower byte into that register
-                if let Some(reg) = var_to_register.get(variable) {
-                    asm_code.push_str(&format!("MOV {},L;\n", reg));
+                if let Some(reg) = symbols.var_to_register.get(variable) {
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: reg.clone(),
+                        src: "L".to_string(),
+                    });
                 }
             } else {
                 // For 8-bit: MVI A, value; STA address
-                asm_code.push_str(&format!("MVI A,{}H;\n", numeric_val.to_uppercase()));
-                asm_code.push_str(&format!("STA {:04X}H;\n", addr));
-                
+                asm_code.push(AsmInstruction::Mvi {
+                    dest: "A".to_string(),
+                    value,
+                });
+                asm_code.push(AsmInstruction::Sta(addr));
+
                 // If assigned to a register (and it's not A), move from A
-                if let Some(reg) = var_to_register.get(variable) {
-                    if reg != "A" {
-                        asm_code.push_str(&format!("MOV {},A;\n", reg));
-                    }
+                if let Some(reg) = symbols.var_to_register.get(variable)
+                    && reg != "A"
+                {
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: reg.clone(),
+                        src: "A".to_string(),
+                    });
+                }
+            }
+        }
+        Statement::BinaryOp {
+            register,
+            second: Operand::Reg(second),
+            ..
+        } if is_16bit_register(register) && is_16bit_register(second) => {
+            // HL + pair; DAD names the pair by its high register
+            let pair = if second == "SP" { "SP" } else { &second[..1] };
+            asm_code.push(AsmInstruction::Dad(pair.to_string()));
+        }
+        Statement::BinaryOp {
+            register, second, ..
+        } if is_16bit_register(register) => {
+            // HL + wide; adds through DE, which is saved around it
+            asm_code.push(AsmInstruction::Push("D".to_string()));
+            match locate_operand(second, symbols)? {
+                Location::Immediate(value) => {
+                    asm_code.push(AsmInstruction::Lxi {
+                        pair: "D".to_string(),
+                        value: hex_digits(&value),
+                    });
+                }
+                _ => {
+                    let (Operand::Reg(name) | Operand::Imm(name)) = second;
+                    let address = wide_variable_address(name, symbols)?;
+                    asm_code.push(AsmInstruction::Xchg);
+                    asm_code.push(AsmInstruction::Lhld(address));
+                }
+            }
+            asm_code.push(AsmInstruction::Dad("D".to_string()));
+            asm_code.push(AsmInstruction::Pop("D".to_string()));
+        }
+        Statement::RegisterMove { dest, src } => {
+            if dest == "SP" {
+                asm_code.push(AsmInstruction::Sphl);
+            } else if is_16bit_register(dest) {
+                // Copy a pair a byte at a time: DE = HL is MOV D,H and MOV E,L
+                let (dest_high, dest_low) = dest.split_at(1);
+                let (src_high, src_low) = src.split_at(1);
+                asm_code.push(AsmInstruction::Mov {
+                    dest: dest_high.to_string(),
+                    src: src_high.to_string(),
+                });
+                asm_code.push(AsmInstruction::Mov {
+                    dest: dest_low.to_string(),
+                    src: src_low.to_string(),
+                });
+            } else {
+                asm_code.push(AsmInstruction::Mov {
+                    dest: dest.clone(),
+                    src: src.clone(),
+                });
+            }
+        }
+        Statement::LoadPair {
+            register_pair,
+            variable,
+        } if symbols.arrays.contains_key(variable) => {
+            // Point the pair at an array or data table
+            asm_code.push(AsmInstruction::LxiAddress {
+                pair: register_pair[..1].to_string(),
+                address: array_address(variable, symbols)?,
+            });
+        }
+        Statement::LoadPair {
+            register_pair,
+            variable,
+        } => {
+            let address = wide_variable_address(variable, symbols)?;
+            match register_pair.as_str() {
+                "HL" => asm_code.push(AsmInstruction::Lhld(address)),
+                // LHLD only loads HL, so swap DE in around it
+                "DE" => {
+                    asm_code.push(AsmInstruction::Xchg);
+                    asm_code.push(AsmInstruction::Lhld(address));
+                    asm_code.push(AsmInstruction::Xchg);
+                }
+                _ => {
+                    asm_code.push(AsmInstruction::Push("H".to_string()));
+                    asm_code.push(AsmInstruction::Lhld(address));
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: register_pair[..1].to_string(),
+                        src: "H".to_string(),
+                    });
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: register_pair[1..].to_string(),
+                        src: "L".to_string(),
+                    });
+                    asm_code.push(AsmInstruction::Pop("H".to_string()));
                 }
             }
         }
-        Statement::BinaryOp { register, operator } => {
-            // All operations use register B as second operand
-            let instruction = match operator {
-                BinaryOperator::Add => "ADD B",
-                BinaryOperator::Sub => "SUB B",
-                BinaryOperator::And => "ANA B",
-                BinaryOperator::Or => "ORA B",
-                BinaryOperator::Xor => "XRA B",
+        Statement::BinaryOp {
+            register,
+            operator,
+            second,
+        } => {
+            let operation = match operator {
+                BinaryOperator::Add => AluOp::Add,
+                BinaryOperator::Sub => AluOp::Sub,
+                BinaryOperator::And => AluOp::And,
+                BinaryOperator::Or => AluOp::Or,
+                BinaryOperator::Xor => AluOp::Xor,
             };
-            
+
             // If register is not A, we need to move it to A first
-            if register != "A" {
-                asm_code.push_str(&format!("MOV A,{};\n", register));
-            }
-            asm_code.push_str(&format!("{};\n", instruction));
+            let location = locate_target(register, symbols)?;
+            load_accumulator(&location, asm_code);
+            alu_instruction(operation, &locate_operand(second, symbols)?, asm_code);
             // Result is in A, move back if needed
-            if register != "A" {
-                asm_code.push_str(&format!("MOV {},A;\n", register));
+            store_accumulator(&location, asm_code);
+        }
+        Statement::Rotate {
+            register,
+            direction,
+            through_carry,
+        } => {
+            let instruction = match (direction, through_carry) {
+                (RotateDirection::Left, false) => AsmInstruction::Rlc,
+                (RotateDirection::Right, false) => AsmInstruction::Rrc,
+                (RotateDirection::Left, true) => AsmInstruction::Ral,
+                (RotateDirection::Right, true) => AsmInstruction::Rar,
+            };
+
+            // Rotates only operate on A
+            let location = locate_target(register, symbols)?;
+            load_accumulator(&location, asm_code);
+            asm_code.push(instruction);
+            store_accumulator(&location, asm_code);
+        }
+        Statement::PortIn { register, port } => {
+            // IN always reads into A
+            asm_code.push(AsmInstruction::In(hex_digits(port)));
+            store_accumulator(&locate_target(register, symbols)?, asm_code);
+        }
+        Statement::PortOut { port, register } => {
+            // OUT always writes from A
+            load_accumulator(&locate(register, symbols)?, asm_code);
+            asm_code.push(AsmInstruction::Out(hex_digits(port)));
+        }
+        Statement::StoreIndirect {
+            register_pair,
+            register,
+        } => {
+            if register_pair == "HL" {
+                asm_code.push(AsmInstruction::Mov {
+                    dest: "M".to_string(),
+                    src: register.clone(),
+                });
+            } else {
+                // STAX names the pair by its high register
+                asm_code.push(AsmInstruction::Stax(register_pair[..1].to_string()));
+            }
+        }
+        Statement::LoadIndirect {
+            register,
+            register_pair,
+        } => {
+            if register_pair == "HL" {
+                asm_code.push(AsmInstruction::Mov {
+                    dest: register.clone(),
+                    src: "M".to_string(),
+                });
+            } else {
+                // LDAX names the pair by its high register
+                asm_code.push(AsmInstruction::Ldax(register_pair[..1].to_string()));
             }
         }
-        Statement::PointerIncDec { register_pair, is_increment } => {
-            let instruction = if *is_increment {
-                format!("INX {}", register_pair)
+        Statement::LoadDirect { register, address } => {
+            let address = Address::Fixed(direct_address(address));
+            asm_code.push(if register == "HL" {
+                AsmInstruction::Lhld(address)
+            } else {
+                AsmInstruction::Lda(address)
+            });
+        }
+        Statement::StoreDirect { address, register } => {
+            let address = Address::Fixed(direct_address(address));
+            asm_code.push(if register == "HL" {
+                AsmInstruction::Shld(address)
             } else {
-                format!("DCX {}", register_pair)
+                AsmInstruction::Sta(address)
+            });
+        }
+        Statement::FunctionDef { .. } => {
+            // Function bodies are emitted after main by `generate`
+        }
+        Statement::ConstDef { .. } => {
+            // Constants are substituted where they are used
+        }
+        Statement::RegisterPin { .. } => {
+            // Pins are applied by `allocate`
+        }
+        Statement::ArrayDecl { .. } | Statement::DataBytes { .. } => {
+            // Arrays are reserved by `allocate`; tables are filled by `data_definitions`
+        }
+        Statement::AssertFits { .. } => {
+            // Checked at compile time by the optimizer
+        }
+        Statement::ArrayLoad {
+            register,
+            array,
+            index,
+        } => {
+            element_address(
+                &array_address(array, symbols)?,
+                &locate(index, symbols)?,
+                asm_code,
+            );
+            asm_code.push(AsmInstruction::Mov {
+                dest: "A".to_string(),
+                src: "M".to_string(),
+            });
+            store_accumulator(&locate_target(register, symbols)?, asm_code);
+        }
+        Statement::ArrayStore {
+            array,
+            index,
+            register,
+        } => {
+            let address = array_address(array, symbols)?;
+            let index = locate(index, symbols)?;
+            match locate(register, symbols)? {
+                Location::Register(reg)
+                    if reg == "A" && !matches!(index, Location::Register(_)) =>
+                {
+                    // The address is computed in A, so keep the value on the stack
+                    asm_code.push(AsmInstruction::Push("PSW".to_string()));
+                    element_address(&address, &index, asm_code);
+                    asm_code.push(AsmInstruction::Pop("PSW".to_string()));
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: "M".to_string(),
+                        src: "A".to_string(),
+                    });
+                }
+                Location::Register(reg) => {
+                    element_address(&address, &index, asm_code);
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: "M".to_string(),
+                        src: reg,
+                    });
+                }
+                Location::Memory(value_address) => {
+                    element_address(&address, &index, asm_code);
+                    asm_code.push(AsmInstruction::Lda(value_address));
+                    asm_code.push(AsmInstruction::Mov {
+                        dest: "M".to_string(),
+                        src: "A".to_string(),
+                    });
+                }
+                Location::Immediate(value) => {
+                    element_address(&address, &index, asm_code);
+                    asm_code.push(AsmInstruction::Mvi {
+                        dest: "M".to_string(),
+                        value: hex_digits(&value),
+                    });
+                }
+            }
+        }
+        Statement::Call { name } => {
+            asm_code.push(AsmInstruction::Call(name.clone()));
+        }
+        Statement::LabelDef { name } => {
+            asm_code.push(AsmInstruction::Label(name.clone()));
+        }
+        Statement::Break | Statement::Continue => {
+            // The semantic check has rejected these outside a loop
+            let exits = loops
+                .last_mut()
+                .ok_or("break and continue must be inside a loop")?;
+            let target = if matches!(statement, Statement::Break) {
+                exits.broken = true;
+                &exits.end
+            } else {
+                exits.continued = true;
+                &exits.next
             };
-            asm_code.push_str(&format!("{};\n", instruction));
+            asm_code.push(AsmInstruction::Jmp(target.clone()));
+        }
+        Statement::Goto { name } => {
+            asm_code.push(AsmInstruction::Jmp(name.clone()));
+        }
+        Statement::Return { value } => {
+            // Halts even with --no-halt, which only drops the HLT at the end of main
+            asm_code.push(AsmInstruction::Mvi {
+                dest: "A".to_string(),
+                value: hex_digits(value),
+            });
+            asm_code.push(AsmInstruction::Hlt);
+        }
+        Statement::SetInterrupts { enabled } => {
+            asm_code.push(if *enabled {
+                AsmInstruction::Ei
+            } else {
+                AsmInstruction::Di
+            });
+        }
+        Statement::InterruptMask { set } => {
+            asm_code.push(if *set {
+                AsmInstruction::Sim
+            } else {
+                AsmInstruction::Rim
+            });
+        }
+        Statement::DecimalAdjust => {
+            asm_code.push(AsmInstruction::Daa);
+        }
+        Statement::Complement { .. } => {
+            asm_code.push(AsmInstruction::Cma);
+        }
+        Statement::ComplementCarry => {
+            asm_code.push(AsmInstruction::Cmc);
+        }
+        Statement::JumpHl => {
+            asm_code.push(AsmInstruction::Pchl);
+        }
+        Statement::Nop => {
+            asm_code.push(AsmInstruction::Nop);
+        }
+        Statement::Align { boundary } => {
+            asm_code.push(AsmInstruction::Align(*boundary));
+        }
+        Statement::Swap { .. } => {
+            asm_code.push(AsmInstruction::Xchg);
+        }
+        Statement::Restart { vector } => {
+            asm_code.push(AsmInstruction::Rst(*vector));
         }
-        Statement::If { left, condition, right, body } => {
+        Statement::StackOp {
+            register_pair,
+            is_push,
+        } => {
+            // PUSH/POP name a pair by its high register, except PSW
+            let pair = if register_pair == "PSW" {
+                "PSW"
+            } else {
+                &register_pair[..1]
+            }
+            .to_string();
+            asm_code.push(if *is_push {
+                AsmInstruction::Push(pair)
+            } else {
+                AsmInstruction::Pop(pair)
+            });
+        }
+        Statement::Print { register } => {
+            // The display routine takes its argument in A
+            load_accumulator(&locate(register, symbols)?, asm_code);
+            asm_code.push(AsmInstruction::Call(asm::hex_number(&format!(
+                "{:04X}",
+                options.print_routine
+            ))));
+        }
+        Statement::PointerIncDec {
+            register_pair,
+            is_increment,
+        } => {
+            let register = register_pair.clone();
+            asm_code.push(match (is_16bit_register(&register), *is_increment) {
+                (true, true) => AsmInstruction::Inx(register),
+                (true, false) => AsmInstruction::Dcx(register),
+                (false, true) => AsmInstruction::Inr(register),
+                (false, false) => AsmInstruction::Dcr(register),
+            });
+        }
+        Statement::If {
+            guard,
+            body,
+            else_body,
+        } => {
             let label = *label_counter;
             *label_counter += 1;
-            
-            // Resolve left and right to actual registers
-            let left_reg = var_to_register.get(left).unwrap_or(left).clone();
-            let right_reg = var_to_register.get(right).unwrap_or(right).clone();
-            
-            // Move left operand to A if not already A
-            if left_reg != "A" {
-                asm_code.push_str(&format!("MOV A,{};\n", left_reg));
-            }
-            
-            // Compare A with right operand
-            if right_reg == "A" {
-                // Comparing with itself, use CPI instead
-                asm_code.push_str("CPI 00H;\n");
-            } else {
-                asm_code.push_str(&format!("CMP {};\n", right_reg));
-            }
-            
-            // Jump based on condition
-            let jump_instruction = match condition {
-                Condition::Equal => format!("JNZ SKIP_{};\n", label),      // Jump if not zero (not equal)
-                Condition::Greater => format!("JZ SKIP_{};\nJC SKIP_{};\n", label, label), // Jump if zero or carry (<=)
-                Condition::Less => format!("JZ SKIP_{};\nJNC SKIP_{};\n", label, label),  // Jump if zero or no carry (>=)
+
+            generate_guard(
+                guard,
+                &generated_label("SKIP", label),
+                symbols,
+                asm_code,
+                label_counter,
+            )?;
+
+            // Generate body
+            for stmt in body {
+                generate_statement(stmt, symbols, options, asm_code, label_counter, loops)?;
+            }
+
+            match else_body {
+                Some(else_body) => {
+                    // Then-branch jumps over the else-branch; _END_n shares n with _SKIP_n
+                    mark_source(statement, symbols, asm_code);
+                    asm_code.push(AsmInstruction::Jmp(generated_label("END", label)));
+                    asm_code.push(AsmInstruction::Label(generated_label("SKIP", label)));
+                    if options.comments {
+                        asm_code.push(AsmInstruction::Comment("else".to_string()));
+                    }
+                    for stmt in else_body {
+                        generate_statement(stmt, symbols, options, asm_code, label_counter, loops)?;
+                    }
+                    asm_code.push(AsmInstruction::Label(generated_label("END", label)));
+                }
+                None => {
+                    // Skip label
+                    asm_code.push(AsmInstruction::Label(generated_label("SKIP", label)));
+                }
+            }
+        }
+        Statement::ConditionalAssign {
+            dest,
+            condition_left,
+            condition,
+            condition_right,
+            then_value,
+            else_value,
+        } => {
+            let label = *label_counter;
+            *label_counter += 1;
+
+            // MVI and LXI leave the flags alone, so either value can follow the compare
+            let load = |value: &str| {
+                if is_16bit_register(dest) {
+                    AsmInstruction::Lxi {
+                        pair: dest.clone(),
+                        value: hex_digits(value),
+                    }
+                } else {
+                    AsmInstruction::Mvi {
+                        dest: dest.clone(),
+                        value: hex_digits(value),
+                    }
+                }
             };
-            asm_code.push_str(&jump_instruction);
-            
+            generate_comparison(
+                condition_left,
+                condition,
+                condition_right,
+                &generated_label("SKIP", label),
+                symbols,
+                asm_code,
+                label_counter,
+            )?;
+            asm_code.push(load(then_value));
+            asm_code.push(AsmInstruction::Jmp(generated_label("END", label)));
+            asm_code.push(AsmInstruction::Label(generated_label("SKIP", label)));
+            asm_code.push(load(else_value));
+            asm_code.push(AsmInstruction::Label(generated_label("END", label)));
+        }
+        Statement::ComparisonAssign {
+            variable,
+            condition_left,
+            condition,
+            condition_right,
+        } => {
+            let label = *label_counter;
+            *label_counter += 1;
+
+            let StaticVar {
+                address: addr,
+                is_16bit,
+            } = *symbols
+                .static_vars
+                .get(variable)
+                .ok_or_else(|| format!("No storage was allocated for variable '{}'", variable))?;
+            let addr = data_address(variable, addr, symbols);
+            let register = symbols.var_to_register.get(variable);
+
+            // The compare loads A, which the code after this may still need,
+            // unless A backs the variable and takes the result anyway. A
+            // 16-bit variable is stored through HL, the way assignments do.
+            let saved: Vec<&str> = [
+                (register.is_none_or(|reg| reg != "A"), "PSW"),
+                (is_16bit, "H"),
+            ]
+            .into_iter()
+            .filter_map(|(needed, pair)| needed.then_some(pair))
+            .collect();
+            for pair in &saved {
+                asm_code.push(AsmInstruction::Push(pair.to_string()));
+            }
+
+            // MVI and LXI leave the flags alone, so either value can follow the compare
+            let load = |value: &str| {
+                if is_16bit {
+                    AsmInstruction::Lxi {
+                        pair: "H".to_string(),
+                        value: hex_digits(value),
+                    }
+                } else {
+                    AsmInstruction::Mvi {
+                        dest: "A".to_string(),
+                        value: hex_digits(value),
+                    }
+                }
+            };
+            generate_comparison(
+                condition_left,
+                condition,
+                condition_right,
+                &generated_label("SKIP", label),
+                symbols,
+                asm_code,
+                label_counter,
+            )?;
+            asm_code.push(load(if is_16bit { "0x0001" } else { "0x01" }));
+            asm_code.push(AsmInstruction::Jmp(generated_label("END", label)));
+            asm_code.push(AsmInstruction::Label(generated_label("SKIP", label)));
+            asm_code.push(load(if is_16bit { "0x0000" } else { "0x00" }));
+            asm_code.push(AsmInstruction::Label(generated_label("END", label)));
+
+            if is_16bit {
+                asm_code.push(AsmInstruction::Shld(addr));
+            } else {
+                asm_code.push(AsmInstruction::Sta(addr));
+            }
+            if let Some(reg) = register
+                && (is_16bit || reg != "A")
+            {
+                let src = if is_16bit { "L" } else { "A" };
+                asm_code.push(AsmInstruction::Mov {
+                    dest: reg.clone(),
+                    src: src.to_string(),
+                });
+            }
+            for pair in saved.iter().rev() {
+                asm_code.push(AsmInstruction::Pop(pair.to_string()));
+            }
+        }
+        Statement::While { guard, body } => {
+            let label = *label_counter;
+            *label_counter += 1;
+
+            // Re-evaluate the condition on every iteration
+            asm_code.push(AsmInstruction::Label(generated_label("LOOP", label)));
+            generate_guard(
+                guard,
+                &generated_label("SKIP", label),
+                symbols,
+                asm_code,
+                label_counter,
+            )?;
+
             // Generate body
+            loops.push(LoopExits {
+                next: generated_label("LOOP", label),
+                end: generated_label("SKIP", label),
+                continued: false,
+                broken: false,
+            });
+            for stmt in body {
+                generate_statement(stmt, symbols, options, asm_code, label_counter, loops)?;
+            }
+            loops.pop();
+
+            mark_source(statement, symbols, asm_code);
+            asm_code.push(AsmInstruction::Jmp(generated_label("LOOP", label)));
+            asm_code.push(AsmInstruction::Label(generated_label("SKIP", label)));
+        }
+        Statement::Repeat { count, body } => {
+            // B is the counter, so the body may read it but nothing may write it
+            let backed_by_counter = symbols
+                .var_to_register
+                .iter()
+                .find(|(_, register)| *register == "B")
+                .map(|(variable, _)| variable);
+            let (touched, written) = block_effects(body, symbols.program);
+            if written.iter().any(|name| {
+                name == "B"
+                    || symbols
+                        .var_to_register
+                        .get(name)
+                        .is_some_and(|register| register == "B")
+            }) {
+                return Err(format!(
+                    "The body of repeat({}) writes B, which counts the loop down. Keep B free inside the loop; a nested repeat also counts in B, so use a while loop for the inner one.",
+                    count
+                ));
+            }
+            if let Some(variable) = backed_by_counter
+                && touched.contains(variable)
+            {
+                return Err(format!(
+                    "The body of repeat({}) uses variable '{}', which is pinned to B, the loop counter. Pin it to another register, or use a while loop instead.",
+                    count, variable
+                ));
+            }
+
+            let label = *label_counter;
+            *label_counter += 1;
+
+            asm_code.push(AsmInstruction::Mvi {
+                dest: "B".to_string(),
+                value: hex_digits(count),
+            });
+            asm_code.push(AsmInstruction::Label(generated_label("LOOP", label)));
+            loops.push(LoopExits {
+                next: generated_label("NEXT", label),
+                end: generated_label("END", label),
+                continued: false,
+                broken: false,
+            });
             for stmt in body {
-                generate_statement(stmt, static_vars, var_to_register, asm_code, label_counter);
+                generate_statement(stmt, symbols, options, asm_code, label_counter, loops)?;
+            }
+            let exits = loops.pop().expect("pushed above");
+            mark_source(statement, symbols, asm_code);
+            if exits.continued {
+                asm_code.push(AsmInstruction::Label(exits.next));
+            }
+            asm_code.push(AsmInstruction::Dcr("B".to_string()));
+            asm_code.push(AsmInstruction::Jnz(generated_label("LOOP", label)));
+            if exits.broken {
+                asm_code.push(AsmInstruction::Label(exits.end));
+            }
+
+            // Reload the variable pinned to B from memory, leaving HL as it was
+            if let Some(variable) = backed_by_counter {
+                let address =
+                    data_address(variable, symbols.static_vars[variable].address, symbols);
+                asm_code.push(AsmInstruction::Push("H".to_string()));
+                asm_code.push(AsmInstruction::LxiAddress {
+                    pair: "H".to_string(),
+                    address,
+                });
+                asm_code.push(AsmInstruction::Mov {
+                    dest: "B".to_string(),
+                    src: "M".to_string(),
+                });
+                asm_code.push(AsmInstruction::Pop("H".to_string()));
             }
-            
-            // Skip label
-            asm_code.push_str(&format!("SKIP_{}:\n", label));
         }
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// The name of a label the compiler generates, e.g. `_SKIP_0`. Identifiers
+/// start with a letter, so a leading underscore can't clash with a label or
+/// function the program defines.
+fn generated_label(kind: &str, label: i32) -> String {
+    format!("_{}_{}", kind, label)
+}
+
+/// The c85 source a statement was written as, for comments in the output.
+/// Compound statements show only their header; definitions that emit no
+/// code here have none.
+fn source_text(statement: &Statement) -> Option<String> {
+    let text = match statement {
+        Statement::MoveImmediate { register, value } => format!("reg {} = {}", register, value),
+        Statement::LoadImmediateExtended {
+            register_pair,
+            address,
+            size: None,
+        } => format!("reg {} = malloc({})", register_pair, address),
+        Statement::LoadImmediateExtended {
+            register_pair,
+            address,
+            size: Some(size),
+        } => {
+            format!("reg {} = malloc({}, {})", register_pair, address, size)
+        }
+        Statement::StaticAssignment {
+            variable, value, ..
+        } => format!("{} = {}", variable, value),
+        Statement::LoadPair {
+            register_pair,
+            variable,
+        } => format!("reg {} = {}", register_pair, variable),
+        Statement::RegisterMove { dest, src } => format!("{} = {}", dest, src),
+        Statement::BinaryOp {
+            register,
+            operator,
+            second,
+        } => {
+            let symbol = match operator {
+                BinaryOperator::Add => "+=",
+                BinaryOperator::Sub => "-=",
+                BinaryOperator::And => "&=",
+                BinaryOperator::Or => "|=",
+                BinaryOperator::Xor => "^=",
+            };
+            format!("{} {} {}", register, symbol, operand_text(second))
+        }
+        Statement::StoreIndirect {
+            register_pair,
+            register,
+        } => format!("*{} = {}", register_pair, register),
+        Statement::LoadDirect { register, address } => format!("reg {} = [{}]", register, address),
+        Statement::StoreDirect { address, register } => format!("[{}] = {}", address, register),
+        Statement::LoadIndirect {
+            register,
+            register_pair,
+        } => format!("{} = *{}", register, register_pair),
+        Statement::Call { name } => format!("{}()", name),
+        Statement::LabelDef { name } => format!("label {}", name),
+        Statement::Goto { name } => format!("goto {}", name),
+        Statement::Break => "break".to_string(),
+        Statement::Continue => "continue".to_string(),
+        Statement::Return { value } => format!("return {}", value),
+        Statement::Rotate {
+            register,
+            direction,
+            through_carry,
+        } => {
+            let symbol = match (direction, through_carry) {
+                (RotateDirection::Left, false) => "<<",
+                (RotateDirection::Right, false) => ">>",
+                (RotateDirection::Left, true) => "<<<",
+                (RotateDirection::Right, true) => ">>>",
+            };
+            format!("{} {} 1", register, symbol)
+        }
+        Statement::PortIn { register, port } => format!("{} = in({})", register, port),
+        Statement::PortOut { port, register } => format!("out({}) = {}", port, register),
+        Statement::Print { register } => format!("print({})", register),
+        Statement::SetInterrupts { enabled } => {
+            format!(
+                "{}()",
+                if *enabled {
+                    "enable_interrupts"
+                } else {
+                    "disable_interrupts"
+                }
+            )
+        }
+        Statement::InterruptMask { set } => {
+            format!(
+                "{}()",
+                if *set {
+                    "set_interrupt_mask"
+                } else {
+                    "read_interrupt_mask"
+                }
+            )
+        }
+        Statement::DecimalAdjust => "decimal_adjust()".to_string(),
+        Statement::Complement { register } => format!("~{}", register),
+        Statement::ComplementCarry => "complement_carry()".to_string(),
+        Statement::JumpHl => "jump_hl()".to_string(),
+        Statement::Nop => "nop()".to_string(),
+        Statement::Align { boundary } => format!("align(0x{:X})", boundary),
+        Statement::Swap { first, second } => format!("swap({}, {})", first, second),
+        Statement::Restart { vector } => format!("rst({})", vector),
+        Statement::StackOp {
+            register_pair,
+            is_push,
+        } => {
+            format!(
+                "{}({})",
+                if *is_push { "push" } else { "pop" },
+                register_pair
+            )
+        }
+        Statement::ArrayLoad {
+            register,
+            array,
+            index,
+        } => format!("{} = {}[{}]", register, array, index),
+        Statement::ArrayStore {
+            array,
+            index,
+            register,
+        } => format!("{}[{}] = {}", array, index, register),
+        Statement::PointerIncDec {
+            register_pair,
+            is_increment,
+        } => {
+            format!(
+                "{}{}",
+                register_pair,
+                if *is_increment { "++" } else { "--" }
+            )
+        }
+        Statement::If { guard, .. } => format!("if({})", guard_text(guard)),
+        Statement::ConditionalAssign {
+            dest,
+            condition_left,
+            condition,
+            condition_right,
+            then_value,
+            else_value,
+        } => {
+            let guard = Guard::Compare {
+                left: condition_left.clone(),
+                condition: condition.clone(),
+                right: condition_right.clone(),
+            };
+            format!(
+                "{} = {} ? {} : {}",
+                dest,
+                guard_text(&guard),
+                then_value,
+                else_value
+            )
+        }
+        Statement::ComparisonAssign {
+            variable,
+            condition_left,
+            condition,
+            condition_right,
+        } => {
+            let guard = Guard::Compare {
+                left: condition_left.clone(),
+                condition: condition.clone(),
+                right: condition_right.clone(),
+            };
+            format!("{} = ({})", variable, guard_text(&guard))
+        }
+        Statement::While { guard, .. } => format!("while({})", guard_text(guard)),
+        Statement::Repeat { count, .. } => format!("repeat({})", count),
+        Statement::ConstDef { .. }
+        | Statement::RegisterPin { .. }
+        | Statement::ArrayDecl { .. }
+        | Statement::DataBytes { .. }
+        | Statement::FunctionDef { .. }
+        | Statement::AssertFits { .. } => return None,
+    };
+    Some(text)
+}
+
+/// The c85 source of a condition, e.g. "A > B && C < D"
+pub(crate) fn guard_text(guard: &Guard) -> String {
+    match guard {
+        Guard::Compare {
+            left,
+            condition,
+            right,
+        } => {
+            let symbol = match condition {
+                Condition::Greater => ">",
+                Condition::Less => "<",
+                Condition::Equal => "==",
+                Condition::GreaterEqual => ">=",
+                Condition::LessEqual => "<=",
+                Condition::NotEqual => "!=",
+            };
+            format!("{} {} {}", operand_text(left), symbol, operand_text(right))
+        }
+        Guard::And(first, second) => format!("{} && {}", guard_text(first), guard_text(second)),
+        Guard::Or(first, second) => format!("{} || {}", guard_text(first), guard_text(second)),
+    }
+}
+
+fn operand_text(operand: &Operand) -> &str {
+    match operand {
+        Operand::Reg(name) | Operand::Imm(name) => name,
+    }
+}
+
+/// Emit code that falls through when the guard holds and jumps to `skip`
+/// when it doesn't. && and || short-circuit.
+fn generate_guard(
+    guard: &Guard,
+    skip: &str,
+    symbols: &Symbols,
+    asm_code: &mut Vec<AsmInstruction>,
+    label_counter: &mut i32,
+) -> Result<(), String> {
+    match guard {
+        Guard::Compare {
+            left,
+            condition,
+            right,
+        } => {
+            generate_comparison(
+                left,
+                condition,
+                right,
+                skip,
+                symbols,
+                asm_code,
+                label_counter,
+            )?;
+        }
+        Guard::And(first, second) => {
+            // Either side failing skips the body
+            generate_guard(first, skip, symbols, asm_code, label_counter)?;
+            generate_guard(second, skip, symbols, asm_code, label_counter)?;
+        }
+        Guard::Or(first, second) => {
+            // The first side holding enters the body; failing tries the second
+            let label = *label_counter;
+            *label_counter += 1;
+            generate_guard(
+                first,
+                &generated_label("NEXT", label),
+                symbols,
+                asm_code,
+                label_counter,
+            )?;
+            asm_code.push(AsmInstruction::Jmp(generated_label("THEN", label)));
+            asm_code.push(AsmInstruction::Label(generated_label("NEXT", label)));
+            generate_guard(second, skip, symbols, asm_code, label_counter)?;
+            asm_code.push(AsmInstruction::Label(generated_label("THEN", label)));
+        }
+    }
+    Ok(())
+}
+
+/// Emit the compare and the jump to `skip` taken when the condition is false
+#[allow(clippy::too_many_arguments)]
+fn generate_comparison(
+    left: &Operand,
+    condition: &Condition,
+    right: &Operand,
+    skip: &str,
+    symbols: &Symbols,
+    asm_code: &mut Vec<AsmInstruction>,
+    label_counter: &mut i32,
+) -> Result<(), String> {
+    let mut condition = condition.clone();
+
+    if is_wide(left, symbols) || is_wide(right, symbols) {
+        compare_wide(left, right, symbols, asm_code, label_counter)?;
+    } else {
+        // Resolve left and right to actual registers or spilled memory
+        let mut left_location = locate_operand(left, symbols)?;
+        let mut right_location = locate_operand(right, symbols)?;
+
+        // If only the right operand lives in A, loading the left one into A would
+        // clobber it; compare the other way round instead (B > A is A < B)
+        let accumulator = Location::Register("A".to_string());
+        if right_location == accumulator && left_location != accumulator {
+            std::mem::swap(&mut left_location, &mut right_location);
+            condition = mirrored(&condition);
+        }
+
+        // Move left operand to A if not already A
+        load_accumulator(&left_location, asm_code);
+
+        // Compare A with right operand. CMP sets Z when A == operand and
+        // CY when A < operand (unsigned).
+        alu_instruction(AluOp::Cmp, &right_location, asm_code);
+    }
+
+    // Jump to `skip` when the condition is false
+    let skip = skip.to_string();
+    match condition {
+        Condition::Equal => asm_code.push(AsmInstruction::Jnz(skip)), // Jump if not zero (!=)
+        Condition::Greater => {
+            // Jump if zero or carry (<=)
+            asm_code.push(AsmInstruction::Jz(skip.clone()));
+            asm_code.push(AsmInstruction::Jc(skip));
+        }
+        Condition::Less => asm_code.push(AsmInstruction::Jnc(skip)), // Jump if no carry (>=), which includes equal
+        Condition::GreaterEqual => asm_code.push(AsmInstruction::Jc(skip)), // Jump if carry (<)
+        Condition::LessEqual => {
+            // Enter on zero, else jump if no carry (>)
+            let label = *label_counter;
+            *label_counter += 1;
+            asm_code.push(AsmInstruction::Jz(generated_label("BODY", label)));
+            asm_code.push(AsmInstruction::Jnc(skip));
+            asm_code.push(AsmInstruction::Label(generated_label("BODY", label)));
+        }
+        Condition::NotEqual => asm_code.push(AsmInstruction::Jz(skip)), // Jump if zero (==)
+    }
+    Ok(())
+}
+
+/// Whether a comparison operand needs 16 bits: a register pair, a 16-bit
+/// variable, or a constant or literal above 0xFF
+fn is_wide(operand: &Operand, symbols: &Symbols) -> bool {
+    match operand {
+        Operand::Reg(name) if is_16bit_register(name) => true,
+        Operand::Reg(name) => match symbols.constants.get(name) {
+            Some(value) => hex_value(value).unwrap_or(0) > 0xFF,
+            None => symbols
+                .static_vars
+                .get(name)
+                .is_some_and(|var| var.is_16bit),
+        },
+        Operand::Imm(value) => hex_value(value).unwrap_or(0) > 0xFF,
+    }
+}
+
+/// Emit a 16-bit unsigned compare. The high bytes are compared first and the
+/// low bytes only when those are equal, which leaves Z and CY as a single CMP
+/// of the full values would, so the usual jumps apply. Clobbers A.
+fn compare_wide(
+    left: &Operand,
+    right: &Operand,
+    symbols: &Symbols,
+    asm_code: &mut Vec<AsmInstruction>,
+    label_counter: &mut i32,
+) -> Result<(), String> {
+    let (left_high, left_low) = wide_bytes(left, symbols)?;
+    let (right_high, right_low) = wide_bytes(right, symbols)?;
+
+    let label = *label_counter;
+    *label_counter += 1;
+    load_accumulator(&left_high, asm_code);
+    alu_instruction(AluOp::Cmp, &right_high, asm_code);
+    asm_code.push(AsmInstruction::Jnz(generated_label("WIDE", label)));
+    load_accumulator(&left_low, asm_code);
+    alu_instruction(AluOp::Cmp, &right_low, asm_code);
+    asm_code.push(AsmInstruction::Label(generated_label("WIDE", label)));
+    Ok(())
+}
+
+/// The high and low bytes of an operand in a 16-bit comparison. Values up to
+/// 0xFF are zero-extended; 8-bit registers and variables can't be.
+fn wide_bytes(operand: &Operand, symbols: &Symbols) -> Result<(Location, Location), String> {
+    let name = match operand {
+        Operand::Reg(name) => name,
+        Operand::Imm(value) => return Ok(immediate_bytes(value)),
+    };
+    if let Some(value) = symbols.constants.get(name) {
+        return Ok(immediate_bytes(value));
+    }
+    match name.as_str() {
+        "HL" | "DE" | "BC" => {
+            let (high, low) = name.split_at(1);
+            Ok((
+                Location::Register(high.to_string()),
+                Location::Register(low.to_string()),
+            ))
+        }
+        "SP" => Err(
+            "SP can't be compared; only HL, DE, BC, 16-bit variables and values can".to_string(),
+        ),
+        _ => match symbols.static_vars.get(name) {
+            // Stored little-endian: low byte first
+            Some(var) if var.is_16bit => {
+                let address = data_address(name, var.address, symbols);
+                Ok((
+                    Location::Memory(address.offset(1)),
+                    Location::Memory(address),
+                ))
+            }
+            _ => Err(format!(
+                "Can't compare 8-bit '{}' with a 16-bit value",
+                name
+            )),
+        },
+    }
+}
+
+/// Split a 16-bit value into high and low immediates
+fn immediate_bytes(value: &str) -> (Location, Location) {
+    let value = hex_value(value).unwrap_or(0);
+    (
+        Location::Immediate(format!("0x{:02X}", (value >> 8) & 0xFF)),
+        Location::Immediate(format!("0x{:02X}", value & 0xFF)),
+    )
+}
+
+/// Where an operand's value can be found at runtime
+#[derive(Debug, PartialEq)]
+enum Location {
+    Register(String),
+    // A variable that didn't get a register lives only in memory
+    Memory(Address),
+    // A constant is encoded into the instruction itself
+    Immediate(String),
+}
+
+/// Everything a name used as an operand can refer to
+struct Symbols<'a> {
+    static_vars: HashMap<String, StaticVar>,
+    var_to_register: HashMap<String, String>,
+    arrays: HashMap<String, StaticArray>,
+    constants: HashMap<String, String>,
+    /// The whole program, for looking into the functions a block calls
+    program: &'a [Statement],
+    /// Whether variables and arrays are referred to by label
+    relocatable: bool,
+    /// The source line of each statement, when building a source map
+    lines: HashMap<*const Statement, usize>,
+}
+
+/// How instructions refer to the variable or array `name` allocated at `address`
+fn data_address(name: &str, address: u16, symbols: &Symbols) -> Address {
+    if symbols.relocatable {
+        Address::Label(name.to_string(), 0)
+    } else {
+        Address::Fixed(address)
+    }
+}
+
+/// Resolve a register, variable or constant name to its location
+fn locate(name: &str, symbols: &Symbols) -> Result<Location, String> {
+    if let Some(value) = symbols.constants.get(name) {
+        return Ok(Location::Immediate(value.clone()));
+    }
+    match (
+        symbols.var_to_register.get(name),
+        symbols.static_vars.get(name),
+    ) {
+        (Some(reg), _) => Ok(Location::Register(reg.clone())),
+        (None, Some(var)) => Ok(Location::Memory(data_address(name, var.address, symbols))),
+        (None, None) if is_8bit_register(name) => Ok(Location::Register(name.to_string())),
+        (None, None) => Err(format!("Unknown register or variable '{}'", name)),
+    }
+}
+
+/// Resolve one side of a comparison; literals become immediates (CPI)
+fn locate_operand(operand: &Operand, symbols: &Symbols) -> Result<Location, String> {
+    match operand {
+        Operand::Reg(name) => locate(name, symbols),
+        Operand::Imm(value) => Ok(Location::Immediate(value.clone())),
+    }
+}
+
+/// Resolve a name that is written to, which can't be a constant
+fn locate_target(name: &str, symbols: &Symbols) -> Result<Location, String> {
+    match locate(name, symbols)? {
+        Location::Immediate(_) => Err(format!("Cannot assign to constant '{}'", name)),
+        location => Ok(location),
+    }
+}
+
+/// The address of a 16-bit variable, read with LHLD
+fn wide_variable_address(name: &str, symbols: &Symbols) -> Result<Address, String> {
+    match symbols.static_vars.get(name) {
+        Some(var) if var.is_16bit => Ok(data_address(name, var.address, symbols)),
+        Some(_) => Err(format!(
+            "'{}' is an 8-bit variable; only 16-bit variables can be used as a register pair value",
+            name
+        )),
+        None => Err(format!("Unknown 16-bit variable '{}'", name)),
+    }
+}
+
+/// The base address of a declared array
+fn array_address(name: &str, symbols: &Symbols) -> Result<Address, String> {
+    symbols
+        .arrays
+        .get(name)
+        .map(|array| data_address(name, array.address, symbols))
+        .ok_or_else(|| format!("Unknown array '{}'", name))
+}
+
+/// Point HL at an array element: HL = base + index. A register index is
+/// added with DAD through DE, which is saved around it, so A is left alone,
+/// as in a lookup table: LXI H,table; MOV E,B; MVI D,00H; DAD D. Any other
+/// index is added in A, clobbering it.
+fn element_address(base: &Address, index: &Location, asm_code: &mut Vec<AsmInstruction>) {
+    if let Location::Register(reg) = index {
+        asm_code.push(AsmInstruction::Push("D".to_string()));
+        // Read the index first, since it may live in D, H or L
+        if reg != "E" {
+            asm_code.push(AsmInstruction::Mov {
+                dest: "E".to_string(),
+                src: reg.clone(),
+            });
+        }
+        asm_code.push(AsmInstruction::Mvi {
+            dest: "D".to_string(),
+            value: "00".to_string(),
+        });
+        asm_code.push(AsmInstruction::LxiAddress {
+            pair: "H".to_string(),
+            address: base.clone(),
+        });
+        asm_code.push(AsmInstruction::Dad("D".to_string()));
+        asm_code.push(AsmInstruction::Pop("D".to_string()));
+        return;
+    }
+
+    // Read the index first, since it may live in H or L
+    load_accumulator(index, asm_code);
+    asm_code.push(AsmInstruction::LxiAddress {
+        pair: "H".to_string(),
+        address: base.clone(),
+    });
+    asm_code.push(AsmInstruction::Alu(AluOp::Add, "L".to_string()));
+    asm_code.push(AsmInstruction::Mov {
+        dest: "L".to_string(),
+        src: "A".to_string(),
+    });
+    // MVI leaves the carry from the low byte for ADC
+    asm_code.push(AsmInstruction::Mvi {
+        dest: "A".to_string(),
+        value: "00".to_string(),
+    });
+    asm_code.push(AsmInstruction::Adc("H".to_string()));
+    asm_code.push(AsmInstruction::Mov {
+        dest: "H".to_string(),
+        src: "A".to_string(),
+    });
+}
+
+/// Bring an operand into the accumulator
+fn load_accumulator(location: &Location, asm_code: &mut Vec<AsmInstruction>) {
+    match location {
+        Location::Register(reg) if reg == "A" => {}
+        Location::Register(reg) => asm_code.push(AsmInstruction::Mov {
+            dest: "A".to_string(),
+            src: reg.clone(),
+        }),
+        Location::Memory(address) => asm_code.push(AsmInstruction::Lda(address.clone())),
+        Location::Immediate(value) => {
+            asm_code.push(AsmInstruction::Mvi {
+                dest: "A".to_string(),
+                value: hex_digits(value),
+            });
+        }
+    }
+}
+
+/// Write the accumulator back to an operand's location
+fn store_accumulator(location: &Location, asm_code: &mut Vec<AsmInstruction>) {
+    match location {
+        Location::Register(reg) if reg == "A" => {}
+        Location::Register(reg) => asm_code.push(AsmInstruction::Mov {
+            dest: reg.clone(),
+            src: "A".to_string(),
+        }),
+        Location::Memory(address) => asm_code.push(AsmInstruction::Sta(address.clone())),
+        Location::Immediate(_) => unreachable!("targets are resolved by locate_target"),
+    }
+}
+
+/// Emit an 8-bit ALU instruction such as `ADD` on A and an operand. Spilled
+/// variables are read through M, with HL saved around it since it may hold a
+/// pointer (POP leaves the flags alone), and constants use the immediate form
+/// of the instruction (`ADI`, `CPI`, ...).
+fn alu_instruction(operation: AluOp, location: &Location, asm_code: &mut Vec<AsmInstruction>) {
+    match location {
+        Location::Register(reg) => asm_code.push(AsmInstruction::Alu(operation, reg.clone())),
+        Location::Memory(address) => {
+            asm_code.push(AsmInstruction::Push("H".to_string()));
+            asm_code.push(AsmInstruction::LxiAddress {
+                pair: "H".to_string(),
+                address: address.clone(),
+            });
+            asm_code.push(AsmInstruction::Alu(operation, "M".to_string()));
+            asm_code.push(AsmInstruction::Pop("H".to_string()));
+        }
+        Location::Immediate(value) => {
+            asm_code.push(AsmInstruction::AluImmediate(operation, hex_digits(value)))
+        }
+    }
+}
+
+/// The address of a direct memory access, already validated as 16-bit
+fn direct_address(address: &str) -> u16 {
+    hex_value(address)
+        .and_then(|address| u16::try_from(address).ok())
+        .unwrap_or(0)
+}
+
+/// The hex digits of a value as written in the assembly, e.g. 0x1f is 1F.
+/// Digits are padded to a whole byte or word (0x5 is 05, 0xabc is 0ABC), as
+/// the compiler writes the values it computes itself.
+fn hex_digits(value: &str) -> String {
+    let digits = value
+        .trim_start_matches("0x")
+        .trim_start_matches("0X")
+        .to_uppercase();
+    let width = if digits.len() <= 2 { 2 } else { 4 };
+    format!("{:0>width$}", digits)
+}
+
+/// The condition that holds when the operands are swapped (left > right is right < left)
+fn mirrored(condition: &Condition) -> Condition {
+    match condition {
+        Condition::Greater => Condition::Less,
+        Condition::Less => Condition::Greater,
+        Condition::GreaterEqual => Condition::LessEqual,
+        Condition::LessEqual => Condition::GreaterEqual,
+        Condition::Equal => Condition::Equal,
+        Condition::NotEqual => Condition::NotEqual,
+    }
+}
+
+/// Encodes generated assembly as an Intel HEX file, so programs can be loaded
+/// onto a trainer without a separate assembler.
+pub fn to_intel_hex(asm_code: &str) -> Result<String, String> {
+    let bytes = assemble(asm_code)?;
+
+    let mut hex = String::new();
+    let mut start = 0;
+    while start < bytes.len() {
+        // A record holds up to 16 bytes at consecutive addresses
+        let mut end = start + 1;
+        while end < bytes.len()
+            && end - start < 16
+            && bytes[end].0 == bytes[end - 1].0.wrapping_add(1)
+        {
+            end += 1;
+        }
+        let address = bytes[start].0;
+        let data: Vec<u8> = bytes[start..end].iter().map(|(_, byte)| *byte).collect();
+        hex.push_str(&intel_hex_record(address, 0x00, &data));
+        start = end;
+    }
+    hex.push_str(&intel_hex_record(0x0000, 0x01, &[]));
+
+    Ok(hex)
+}
+
+/// Formats one Intel HEX record: `:LLAAAATT<data>CC`
+fn intel_hex_record(address: u16, record_type: u8, data: &[u8]) -> String {
+    let mut record = format!(":{:02X}{:04X}{:02X}", data.len(), address, record_type);
+    let mut checksum = (data.len() as u8)
+        .wrapping_add((address >> 8) as u8)
+        .wrapping_add(address as u8)
+        .wrapping_add(record_type);
+    for byte in data {
+        record.push_str(&format!("{:02X}", byte));
+        checksum = checksum.wrapping_add(*byte);
+    }
+    record.push_str(&format!("{:02X}\n", checksum.wrapping_neg()));
+    record
+}
+
+/// Two-pass assembler for the subset of 8085 assembly `generate` emits.
+/// Returns every encoded byte along with its address.
+fn assemble(asm_code: &str) -> Result<Vec<(u16, u8)>, String> {
+    // Strip comments (everything from ';') and blank lines
+    let lines: Vec<&str> = asm_code
+        .lines()
+        .map(|line| line.split(';').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    // First pass: find the address of every label
+    let mut labels: HashMap<&str, u16> = HashMap::new();
+    let mut address = 0u16;
+    for line in &lines {
+        if let Some(label) = line.strip_suffix(':') {
+            labels.insert(label, address);
+            continue;
+        }
+        let (mnemonic, operands) = split_instruction(line);
+        match mnemonic {
+            "ORG" => address = parse_asm_number(operands.first().copied().unwrap_or(""))?,
+            "END" => break,
+            "DS" => {
+                address =
+                    address.wrapping_add(parse_asm_number(operands.first().copied().unwrap_or(""))?)
+            }
+            "DB" => address = address.wrapping_add(operands.len() as u16),
+            "DW" => address = address.wrapping_add(2 * operands.len() as u16),
+            _ => address = address.wrapping_add(instruction_size(mnemonic)? as u16),
+        }
+    }
+
+    // Second pass: encode
+    let mut bytes = Vec::new();
+    let mut address = 0u16;
+    for line in &lines {
+        if line.ends_with(':') {
+            continue;
+        }
+        let (mnemonic, operands) = split_instruction(line);
+        match mnemonic {
+            "ORG" => address = parse_asm_number(operands.first().copied().unwrap_or(""))?,
+            "END" => break,
+            // Reserved space holds no bytes of its own
+            "DS" => {
+                address =
+                    address.wrapping_add(parse_asm_number(operands.first().copied().unwrap_or(""))?)
+            }
+            _ => {
+                for byte in encode_instruction(mnemonic, &operands, &labels)
+                    .map_err(|e| format!("{} in '{}'", e, line))?
+                {
+                    bytes.push((address, byte));
+                    address = address.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Splits "MVI A,05H" into ("MVI", ["A", "05H"])
+fn split_instruction(line: &str) -> (&str, Vec<&str>) {
+    match line.split_once(char::is_whitespace) {
+        Some((mnemonic, rest)) => (mnemonic, rest.split(',').map(str::trim).collect()),
+        None => (line, Vec::new()),
+    }
+}
+
+/// Size in bytes of an instruction, including its operands
+fn instruction_size(mnemonic: &str) -> Result<u8, String> {
+    match mnemonic {
+        "MVI" | "ADI" | "ACI" | "SUI" | "SBI" | "ANI" | "XRI" | "ORI" | "CPI" | "IN" | "OUT" => {
+            Ok(2)
+        }
+        "LXI" | "STA" | "LDA" | "SHLD" | "LHLD" | "JMP" | "JZ" | "JNZ" | "JC" | "JNC" | "JP"
+        | "JM" | "JPE" | "JPO" | "CALL" => Ok(3),
+        "MOV" | "ADD" | "ADC" | "SUB" | "SBB" | "ANA" | "XRA" | "ORA" | "CMP" | "INR" | "DCR"
+        | "INX" | "DCX" | "DAD" | "STAX" | "LDAX" | "PUSH" | "POP" | "RLC" | "RRC" | "RAL"
+        | "RAR" | "CMA" | "CMC" | "STC" | "DAA" | "XCHG" | "XTHL" | "SPHL" | "PCHL" | "EI"
+        | "DI" | "RIM" | "SIM" | "RST" | "NOP" | "HLT" | "RET" => Ok(1),
+        _ => Err(format!("Unknown instruction '{}'", mnemonic)),
+    }
+}
+
+/// Encodes one instruction into its opcode and operand bytes
+fn encode_instruction(
+    mnemonic: &str,
+    operands: &[&str],
+    labels: &HashMap<&str, u16>,
+) -> Result<Vec<u8>, String> {
+    let operand = |index: usize| -> Result<&str, String> {
+        operands
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("Missing operand for {}", mnemonic))
+    };
+    // 16-bit operand: a number, or a label with an optional offset (buffer+3)
+    let word = |text: &str| -> Result<[u8; 2], String> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (
+                name,
+                offset
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid offset in '{}'", text))?,
+            ),
+            None => (text, 0),
+        };
+        let value = match labels.get(name) {
+            Some(address) => address.wrapping_add(offset),
+            None => parse_asm_number(text)?,
+        };
+        Ok(value.to_le_bytes())
+    };
+    let byte = |text: &str| -> Result<u8, String> {
+        u8::try_from(parse_asm_number(text)?)
+            .map_err(|_| format!("Value {} does not fit in 8 bits", text))
+    };
+
+    let bytes = match mnemonic {
+        "MOV" => vec![0x40 | register_code(operand(0)?)? << 3 | register_code(operand(1)?)?],
+        "MVI" => vec![0x06 | register_code(operand(0)?)? << 3, byte(operand(1)?)?],
+        "DB" => operands
+            .iter()
+            .map(|value| byte(value))
+            .collect::<Result<_, _>>()?,
+        "DW" => operands
+            .iter()
+            .map(|value| word(value))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat(),
+        "INR" => vec![0x04 | register_code(operand(0)?)? << 3],
+        "DCR" => vec![0x05 | register_code(operand(0)?)? << 3],
+        "ADD" => vec![0x80 | register_code(operand(0)?)?],
+        "ADC" => vec![0x88 | register_code(operand(0)?)?],
+        "SUB" => vec![0x90 | register_code(operand(0)?)?],
+        "SBB" => vec![0x98 | register_code(operand(0)?)?],
+        "ANA" => vec![0xA0 | register_code(operand(0)?)?],
+        "XRA" => vec![0xA8 | register_code(operand(0)?)?],
+        "ORA" => vec![0xB0 | register_code(operand(0)?)?],
+        "CMP" => vec![0xB8 | register_code(operand(0)?)?],
+        "ADI" => vec![0xC6, byte(operand(0)?)?],
+        "ACI" => vec![0xCE, byte(operand(0)?)?],
+        "SUI" => vec![0xD6, byte(operand(0)?)?],
+        "SBI" => vec![0xDE, byte(operand(0)?)?],
+        "ANI" => vec![0xE6, byte(operand(0)?)?],
+        "XRI" => vec![0xEE, byte(operand(0)?)?],
+        "ORI" => vec![0xF6, byte(operand(0)?)?],
+        "CPI" => vec![0xFE, byte(operand(0)?)?],
+        "IN" => vec![0xDB, byte(operand(0)?)?],
+        "OUT" => vec![0xD3, byte(operand(0)?)?],
+        "LXI" => {
+            let [low, high] = word(operand(1)?)?;
+            vec![
+                0x01 | register_pair_code(operand(0)?, false)? << 4,
+                low,
+                high,
+            ]
+        }
+        "INX" => vec![0x03 | register_pair_code(operand(0)?, false)? << 4],
+        "DCX" => vec![0x0B | register_pair_code(operand(0)?, false)? << 4],
+        "DAD" => vec![0x09 | register_pair_code(operand(0)?, false)? << 4],
+        "PUSH" => vec![0xC5 | register_pair_code(operand(0)?, true)? << 4],
+        "POP" => vec![0xC1 | register_pair_code(operand(0)?, true)? << 4],
+        "STAX" | "LDAX" => {
+            let pair = register_pair_code(operand(0)?, false)?;
+            if pair > 1 {
+                return Err(format!("{} only supports B or D", mnemonic));
+            }
+            let base = if mnemonic == "STAX" { 0x02 } else { 0x0A };
+            vec![base | pair << 4]
+        }
+        "RST" => {
+            let vector = byte(operand(0)?)?;
+            if vector > 7 {
+                return Err(format!("RST vector {} is out of range (0-7)", vector));
+            }
+            vec![0xC7 | vector << 3]
+        }
+        "STA" | "LDA" | "SHLD" | "LHLD" | "JMP" | "JZ" | "JNZ" | "JC" | "JNC" | "JP" | "JM"
+        | "JPE" | "JPO" | "CALL" => {
+            let opcode = match mnemonic {
+                "STA" => 0x32,
+                "LDA" => 0x3A,
+                "SHLD" => 0x22,
+                "LHLD" => 0x2A,
+                "JMP" => 0xC3,
+                "JNZ" => 0xC2,
+                "JZ" => 0xCA,
+                "JNC" => 0xD2,
+                "JC" => 0xDA,
+                "JPO" => 0xE2,
+                "JPE" => 0xEA,
+                "JP" => 0xF2,
+                "JM" => 0xFA,
+                _ => 0xCD, // CALL
+            };
+            let [low, high] = word(operand(0)?)?;
+            vec![opcode, low, high]
+        }
+        "RLC" => vec![0x07],
+        "RRC" => vec![0x0F],
+        "RAL" => vec![0x17],
+        "RAR" => vec![0x1F],
+        "CMA" => vec![0x2F],
+        "CMC" => vec![0x3F],
+        "STC" => vec![0x37],
+        "DAA" => vec![0x27],
+        "XCHG" => vec![0xEB],
+        "XTHL" => vec![0xE3],
+        "SPHL" => vec![0xF9],
+        "PCHL" => vec![0xE9],
+        "EI" => vec![0xFB],
+        "DI" => vec![0xF3],
+        "RIM" => vec![0x20],
+        "SIM" => vec![0x30],
+        "NOP" => vec![0x00],
+        "HLT" => vec![0x76],
+        "RET" => vec![0xC9],
+        _ => return Err(format!("Unknown instruction '{}'", mnemonic)),
+    };
+    Ok(bytes)
+}
+
+/// 3-bit register field used by MOV, MVI and the ALU instructions
+fn register_code(register: &str) -> Result<u8, String> {
+    match register {
+        "B" => Ok(0),
+        "C" => Ok(1),
+        "D" => Ok(2),
+        "E" => Ok(3),
+        "H" => Ok(4),
+        "L" => Ok(5),
+        "M" => Ok(6),
+        "A" => Ok(7),
+        _ => Err(format!("Invalid register '{}'", register)),
+    }
+}
+
+/// 2-bit register pair field. PUSH/POP use PSW where other instructions use SP.
+fn register_pair_code(pair: &str, stack: bool) -> Result<u8, String> {
+    match pair {
+        "B" | "BC" => Ok(0),
+        "D" | "DE" => Ok(1),
+        "H" | "HL" => Ok(2),
+        "SP" if !stack => Ok(3),
+        "PSW" if stack => Ok(3),
+        _ => Err(format!("Invalid register pair '{}'", pair)),
+    }
+}
+
+/// Parses an assembler number: "0AH"/"8000H" (hex) or plain decimal
+fn parse_asm_number(text: &str) -> Result<u16, String> {
+    let parsed = match text.strip_suffix('H') {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("Invalid number '{}'", text))
+}
@@ -1,143 +1,573 @@
 // src/lexer.rs
 
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     // Keywords
-    Main,        // "main"
-    Reg,         // "reg"
-    Malloc,      // "malloc"
-    If,          // "if"
+    Main,     // "main"
+    Reg,      // "reg"
+    Malloc,   // "malloc"
+    If,       // "if"
+    Else,     // "else"
+    While,    // "while"
+    Fn,       // "fn"
+    In,       // "in"
+    Out,      // "out"
+    Const,    // "const"
+    Print,    // "print"
+    Label,    // "label"
+    Goto,     // "goto"
+    Repeat,   // "repeat"
+    Break,    // "break"
+    Continue, // "continue"
+    Return,   // "return"
 
     // Symbols
-    LBrace,      // "{"
-    RBrace,      // "}"
-    LParen,      // "("
-    RParen,      // ")"
-    Equal,       // "="
-    Semicolon,   // ";"
-    
+    LBrace,    // "{"
+    RBrace,    // "}"
+    LParen,    // "("
+    RParen,    // ")"
+    LBracket,  // "["
+    RBracket,  // "]"
+    Equal,     // "="
+    Semicolon, // ";"
+    Comma,     // ","
+    Colon,     // ":"
+    Question,  // "?"
+    At,        // "@"
+
     // Operators
-    Plus,        // "+"
-    Minus,       // "-"
-    And,         // "&"
-    Or,          // "|"
-    Xor,         // "^"
-    PlusPlus,    // "++"
-    MinusMinus,  // "--"
-    
+    Plus,       // "+"
+    Minus,      // "-"
+    And,        // "&"
+    Or,         // "|"
+    Xor,        // "^"
+    Star,       // "*" (pointer dereference)
+    Tilde,      // "~" (complement accumulator)
+    PlusPlus,   // "++"
+    MinusMinus, // "--"
+    Shl,        // "<<"  (rotate left)
+    Shr,        // ">>"  (rotate right)
+    ShlCarry,   // "<<<" (rotate left through carry)
+    ShrCarry,   // ">>>" (rotate right through carry)
+    PlusEqual,  // "+="
+    MinusEqual, // "-="
+    AndEqual,   // "&="
+    OrEqual,    // "|="
+    XorEqual,   // "^="
+
     // Comparisons
-    Greater,     // ">"
-    Less,        // "<"
-    EqualEqual,  // "=="
+    Greater,      // ">"
+    Less,         // "<"
+    EqualEqual,   // "=="
+    GreaterEqual, // ">="
+    LessEqual,    // "<="
+    NotEqual,     // "!="
+
+    // Logical
+    AndAnd, // "&&"
+    OrOr,   // "||"
 
     // Literals
     Identifier(String), // e.g., "A", "HL"
     HexLiteral(String), // e.g., "0x08", "0x6000"
+    DecLiteral(String), // e.g., "10", "255"
+    BinLiteral(String), // e.g., "0b10110010"
+    OctLiteral(String), // e.g., "0o377"
+
+    // Only produced by lex_with_comments
+    Comment(String), // the text of "// ..." or "/* ... */", trimmed
+}
+
+/// A 1-based source position.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// An error from the lexer or parser. The message mentions the position
+/// for people to read; `span` gives it to tools, and is `None` for errors
+/// about the program as a whole or its end.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl SourceError {
+    /// An error pointing at `span`
+    pub fn at(span: Span, message: String) -> Self {
+        SourceError {
+            message,
+            span: Some(span),
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// A peekable char iterator that keeps track of the current line and column.
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(source: &'a str) -> Self {
+        Cursor {
+            chars: source.chars().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    /// Position of the next char to be consumed
+    fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+}
+
+/// A simple, manual lexer. It turns source code into a Vec<Token>, along with
+/// a parallel Vec<Span> holding the start position of each token.
+pub fn lex(source: &str) -> Result<(Vec<Token>, Vec<Span>), SourceError> {
+    lex_all(source, false)
+}
+
+/// Like `lex`, but keeps comments as `Token::Comment` instead of skipping
+/// them, for tools such as documentation generators. `parser::parse` ignores
+/// them; `parser::parse_documented` attaches them to statements.
+pub fn lex_with_comments(source: &str) -> Result<(Vec<Token>, Vec<Span>), SourceError> {
+    lex_all(source, true)
 }
 
-/// A simple, manual lexer. It turns source code into a Vec<Token>.
-pub fn lex(source: &str) -> Result<Vec<Token>, String> {
+fn lex_all(source: &str, keep_comments: bool) -> Result<(Vec<Token>, Vec<Span>), SourceError> {
     let mut tokens = Vec::new();
-    let mut chars = source.chars().peekable();
-    
-    while let Some(c) = chars.next() {
-        match c {
-            ' ' | '\t' | '\r' | '\n' => continue, // Skip whitespace
-            '{' => tokens.push(Token::LBrace),
-            '}' => tokens.push(Token::RBrace),
-            '(' => tokens.push(Token::LParen),
-            ')' => tokens.push(Token::RParen),
-            ';' => tokens.push(Token::Semicolon),
-            '&' => tokens.push(Token::And),
-            '|' => tokens.push(Token::Or),
-            '^' => tokens.push(Token::Xor),
-            '>' => tokens.push(Token::Greater),
-            '<' => tokens.push(Token::Less),
-            '=' => {
-                if chars.peek() == Some(&'=') {
-                    chars.next();
-                    tokens.push(Token::EqualEqual);
-                } else {
-                    tokens.push(Token::Equal);
-                }
-            }
-            '+' => {
-                if chars.peek() == Some(&'+') {
-                    chars.next();
-                    tokens.push(Token::PlusPlus);
-                } else {
-                    tokens.push(Token::Plus);
-                }
-            }
-            '-' => {
-                if chars.peek() == Some(&'-') {
-                    chars.next();
-                    tokens.push(Token::MinusMinus);
-                } else {
-                    tokens.push(Token::Minus);
-                }
-            }
-            '/' => {
-                // Check for comments
-                if chars.peek() == Some(&'/') {
+    let mut spans = Vec::new();
+    let mut lexer = Lexer::new(source);
+    lexer.keep_comments = keep_comments;
+    while let Some(token) = lexer.next() {
+        tokens.push(token?);
+        spans.push(lexer.span());
+    }
+    Ok((tokens, spans))
+}
+
+/// A lexer that yields tokens one at a time, for tools that want to stop
+/// early. It yields nothing more after an error.
+pub struct Lexer<'a> {
+    chars: Cursor<'a>,
+    span: Span,
+    failed: bool,
+    keep_comments: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Lexer {
+            chars: Cursor::new(source),
+            span: Span { line: 1, column: 1 },
+            failed: false,
+            keep_comments: false,
+        }
+    }
+
+    /// Yield comments as `Token::Comment` rather than skipping them
+    pub fn keep_comments(mut self) -> Self {
+        self.keep_comments = true;
+        self
+    }
+
+    /// Start position of the token most recently yielded
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    fn next_token(&mut self) -> Option<Result<Token, SourceError>> {
+        let chars = &mut self.chars;
+        loop {
+            let start = chars.span();
+            let c = chars.next()?;
+            self.span = start;
+            let token = match c {
+                ' ' | '\t' | '\r' | '\n' => continue, // Skip whitespace
+                '{' => Token::LBrace,
+                '}' => Token::RBrace,
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                '[' => Token::LBracket,
+                ']' => Token::RBracket,
+                ';' => Token::Semicolon,
+                ',' => Token::Comma,
+                ':' => Token::Colon,
+                '?' => Token::Question,
+                '@' => Token::At,
+                '&' => {
+                    if chars.peek() == Some(&'&') {
+                        chars.next();
+                        Token::AndAnd
+                    } else if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::AndEqual
+                    } else {
+                        Token::And
+                    }
+                }
+                '|' => {
+                    if chars.peek() == Some(&'|') {
+                        chars.next();
+                        Token::OrOr
+                    } else if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::OrEqual
+                    } else {
+                        Token::Or
+                    }
+                }
+                '^' => {
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::XorEqual
+                    } else {
+                        Token::Xor
+                    }
+                }
+                '*' => Token::Star,
+                '~' => Token::Tilde,
+                '>' => {
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::GreaterEqual
+                    } else if chars.peek() == Some(&'>') {
+                        chars.next();
+                        if chars.peek() == Some(&'>') {
+                            chars.next();
+                            Token::ShrCarry
+                        } else {
+                            Token::Shr
+                        }
+                    } else {
+                        Token::Greater
+                    }
+                }
+                '<' => {
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::LessEqual
+                    } else if chars.peek() == Some(&'<') {
+                        chars.next();
+                        if chars.peek() == Some(&'<') {
+                            chars.next();
+                            Token::ShlCarry
+                        } else {
+                            Token::Shl
+                        }
+                    } else {
+                        Token::Less
+                    }
+                }
+                '!' => {
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::NotEqual
+                    } else {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!("Unexpected character '!' at {}. Did you mean '!='?", start),
+                        )));
+                    }
+                }
+                '=' => {
+                    if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::EqualEqual
+                    } else {
+                        Token::Equal
+                    }
+                }
+                '+' => {
+                    if chars.peek() == Some(&'+') {
+                        chars.next();
+                        Token::PlusPlus
+                    } else if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::PlusEqual
+                    } else {
+                        Token::Plus
+                    }
+                }
+                '-' => {
+                    if chars.peek() == Some(&'-') {
+                        chars.next();
+                        Token::MinusMinus
+                    } else if chars.peek() == Some(&'=') {
+                        chars.next();
+                        Token::MinusEqual
+                    } else {
+                        Token::Minus
+                    }
+                }
+                '/' if chars.peek() == Some(&'/') => {
                     // Single-line comment: skip until newline
+                    chars.next(); // Consume the second '/'
+                    let mut text = String::new();
                     while let Some(&next_c) = chars.peek() {
                         chars.next();
                         if next_c == '\n' {
                             break;
                         }
+                        text.push(next_c);
                     }
-                    continue;
-                } else {
-                    return Err(format!("Unexpected character: {}", c));
-                }
-            }
-            'a'..='z' | 'A'..='Z' => {
-                let mut identifier = String::new();
-                identifier.push(c);
-                while let Some(&next_c) = chars.peek() {
-                    if next_c.is_alphanumeric() {
-                        identifier.push(chars.next().unwrap());
+                    if self.keep_comments {
+                        Token::Comment(text.trim().to_string())
                     } else {
-                        break;
+                        continue;
                     }
                 }
-                match identifier.as_str() {
-                    "main" => tokens.push(Token::Main),
-                    "reg" => tokens.push(Token::Reg),
-                    "malloc" => tokens.push(Token::Malloc),
-                    "if" => tokens.push(Token::If),
-                    _ => {
-                        // Could be a register (A, HL) or a variable name later
-                        tokens.push(Token::Identifier(identifier))
+                '/' if chars.peek() == Some(&'*') => {
+                    // Block comment: skip until the closing */
+                    chars.next(); // Consume '*'
+                    let mut text = String::new();
+                    loop {
+                        let here = chars.span();
+                        match chars.next() {
+                            Some('*') if chars.peek() == Some(&'/') => {
+                                chars.next(); // Consume '/'
+                                break;
+                            }
+                            Some('/') if chars.peek() == Some(&'*') => {
+                                return Some(Err(SourceError::at(
+                                    here,
+                                    format!("Nested block comment at {} is not supported.", here),
+                                )));
+                            }
+                            Some(c) => text.push(c),
+                            None => {
+                                return Some(Err(SourceError::at(
+                                    start,
+                                    format!("Unterminated block comment starting at {}.", start),
+                                )));
+                            }
+                        }
+                    }
+                    if self.keep_comments {
+                        Token::Comment(text.trim().to_string())
+                    } else {
+                        continue;
                     }
                 }
-            }
-            '0' => {
-                // Check for 0x prefix
-                if chars.peek() == Some(&'x') || chars.peek() == Some(&'X') {
+                'a'..='z' | 'A'..='Z' => {
+                    let mut identifier = String::new();
+                    identifier.push(c);
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_alphanumeric() || next_c == '_' {
+                            identifier.push(chars.next().unwrap());
+                        } else {
+                            break;
+                        }
+                    }
+                    match identifier.as_str() {
+                        "main" => Token::Main,
+                        "reg" => Token::Reg,
+                        "malloc" => Token::Malloc,
+                        "if" => Token::If,
+                        "else" => Token::Else,
+                        "while" => Token::While,
+                        "fn" => Token::Fn,
+                        "in" => Token::In,
+                        "out" => Token::Out,
+                        "const" => Token::Const,
+                        "print" => Token::Print,
+                        "label" => Token::Label,
+                        "goto" => Token::Goto,
+                        "break" => Token::Break,
+                        "continue" => Token::Continue,
+                        "return" => Token::Return,
+                        "repeat" => Token::Repeat,
+                        _ => {
+                            // Could be a register (A, HL) or a variable name later
+                            Token::Identifier(identifier)
+                        }
+                    }
+                }
+                '0' if chars.peek() == Some(&'x') || chars.peek() == Some(&'X') => {
                     chars.next(); // Consume 'x' or 'X'
                     let mut hex_literal = String::from("0x");
                     while let Some(&next_c) = chars.peek() {
                         if next_c.is_ascii_hexdigit() {
                             hex_literal.push(chars.next().unwrap());
+                        } else if next_c == '_' {
+                            chars.next(); // Digit separator, e.g. 0xFF_00
                         } else {
                             break;
                         }
                     }
                     if hex_literal.len() <= 2 {
-                        return Err(format!("Invalid hex literal: '{}'. Expected digits after 0x.", hex_literal));
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Invalid hex literal '{}' at {}. Expected digits after 0x.",
+                                hex_literal, start
+                            ),
+                        )));
+                    }
+                    Token::HexLiteral(hex_literal)
+                }
+                '0' if chars.peek() == Some(&'b') || chars.peek() == Some(&'B') => {
+                    chars.next(); // Consume 'b' or 'B'
+                    let mut bin_literal = String::from("0b");
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_ascii_digit() {
+                            bin_literal.push(chars.next().unwrap());
+                        } else if next_c == '_' {
+                            chars.next(); // Digit separator, e.g. 0b1011_0010
+                        } else {
+                            break;
+                        }
+                    }
+                    if bin_literal.len() <= 2 {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Invalid binary literal '{}' at {}. Expected digits after 0b.",
+                                bin_literal, start
+                            ),
+                        )));
+                    }
+                    if let Some(digit) = bin_literal[2..].chars().find(|d| *d != '0' && *d != '1') {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Invalid digit '{}' in binary literal '{}' at {}.",
+                                digit, bin_literal, start
+                            ),
+                        )));
+                    }
+                    if u64::from_str_radix(&bin_literal[2..], 2).is_err() {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Binary literal '{}' at {} is too large.",
+                                bin_literal, start
+                            ),
+                        )));
+                    }
+                    Token::BinLiteral(bin_literal)
+                }
+                '0' if chars.peek() == Some(&'o') || chars.peek() == Some(&'O') => {
+                    chars.next(); // Consume 'o' or 'O'
+                    let mut oct_literal = String::from("0o");
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_ascii_digit() {
+                            oct_literal.push(chars.next().unwrap());
+                        } else if next_c == '_' {
+                            chars.next(); // Digit separator, e.g. 0o17_77
+                        } else {
+                            break;
+                        }
                     }
-                    tokens.push(Token::HexLiteral(hex_literal));
-                } else {
-                    return Err(format!("Invalid number literal. Use 0x prefix for hex values."));
+                    if oct_literal.len() <= 2 {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Invalid octal literal '{}' at {}. Expected digits after 0o.",
+                                oct_literal, start
+                            ),
+                        )));
+                    }
+                    if let Some(digit) = oct_literal[2..].chars().find(|d| *d > '7') {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Invalid digit '{}' in octal literal '{}' at {}.",
+                                digit, oct_literal, start
+                            ),
+                        )));
+                    }
+                    if u64::from_str_radix(&oct_literal[2..], 8).is_err() {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!("Octal literal '{}' at {} is too large.", oct_literal, start),
+                        )));
+                    }
+                    Token::OctLiteral(oct_literal)
                 }
-            }
-            '1'..='9' => {
-                return Err(format!("Invalid number literal starting with '{}'. Use 0x prefix for hex values.", c));
-            }
-            _ => return Err(format!("Unexpected character: {}", c)),
+                '0'..='9' => {
+                    let mut dec_literal = String::new();
+                    dec_literal.push(c);
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_ascii_digit() {
+                            dec_literal.push(chars.next().unwrap());
+                        } else if next_c == '_' {
+                            chars.next(); // Digit separator, e.g. 1_000
+                        } else {
+                            break;
+                        }
+                    }
+                    if dec_literal.parse::<u64>().is_err() {
+                        return Some(Err(SourceError::at(
+                            start,
+                            format!(
+                                "Decimal literal '{}' at {} is too large.",
+                                dec_literal, start
+                            ),
+                        )));
+                    }
+                    Token::DecLiteral(dec_literal)
+                }
+                _ => {
+                    return Some(Err(SourceError::at(
+                        start,
+                        format!("Unexpected character '{}' at {}", c, start),
+                    )));
+                }
+            };
+            return Some(Ok(token));
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
+        let token = self.next_token();
+        self.failed = matches!(token, Some(Err(_)));
+        token
     }
-    Ok(tokens)
-}
\ No newline at end of file
+}
@@ -0,0 +1,307 @@
+// src/lib.rs
+
+use std::fmt;
+
+// Declare all our modules
+pub mod asm;
+pub mod codegen;
+pub mod lexer;
+pub mod optimize;
+pub mod parser;
+pub mod peephole;
+pub mod semantic;
+
+use codegen::GenerateOptions;
+use lexer::{SourceError, Span};
+
+/// The compiler phase an error came from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Phase {
+    Lex,
+    Parse,
+    Semantic,
+    Codegen,
+    Assemble,
+}
+
+/// An error from one of the compiler phases, with the source position it
+/// points at when there is one.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompileError {
+    pub phase: Phase,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl CompileError {
+    /// Wraps the error message of a phase that doesn't track positions.
+    fn new(phase: Phase, message: String) -> Self {
+        CompileError {
+            phase,
+            message,
+            span: None,
+        }
+    }
+
+    /// Wraps a lexer or parser error, keeping the position it points at.
+    fn located(phase: Phase, error: SourceError) -> Self {
+        CompileError {
+            phase,
+            message: error.message,
+            span: error.span,
+        }
+    }
+
+    /// Formats the error rustc-style: the message, then the source line it
+    /// points at with a `^` under the column. Without a position, or if the
+    /// line isn't in `source`, only the message is shown.
+    pub fn render(&self, source: &str) -> String {
+        let Some(span) = self.span else {
+            return self.to_string();
+        };
+        let Some(line) = source.lines().nth(span.line.saturating_sub(1)) else {
+            return self.to_string();
+        };
+
+        // Tabs before the column stay tabs so the caret lines up
+        let indent: String = line
+            .chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let gutter = " ".repeat(span.line.to_string().len());
+        format!(
+            "{}\n{}--> {}\n{} |\n{} | {}\n{} | {}^",
+            self, gutter, span, gutter, span.line, line, gutter, indent
+        )
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let phase = match self.phase {
+            Phase::Lex => "Lexer",
+            Phase::Parse => "Parsing",
+            Phase::Semantic => "Semantic",
+            Phase::Codegen => "Codegen",
+            Phase::Assemble => "Assembler",
+        };
+        write!(f, "{} Error: {}", phase, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Compiles c85 source code into 8085 assembly using the default options.
+pub fn compile(source: &str) -> Result<String, CompileError> {
+    compile_with_options(source, &GenerateOptions::default())
+}
+
+/// Compiles c85 source code into 8085 assembly.
+pub fn compile_with_options(
+    source: &str,
+    options: &GenerateOptions,
+) -> Result<String, CompileError> {
+    // 1-3. Lex, parse and check the source code.
+    let ast = analyze(source)?;
+    check_strict(&ast, options)?;
+
+    // 4. Fold operations on compile-time-known values, checking assert_fits.
+    let ast = optimize::fold_constants(ast).map_err(|e| CompileError::new(Phase::Semantic, e))?;
+
+    // 5. Generate the assembly code from the AST.
+    codegen::generate(&ast, options).map_err(|e| CompileError::new(Phase::Codegen, e))
+}
+
+/// Lexes, parses and checks c85 source code, returning the checked AST.
+fn analyze(source: &str) -> Result<Vec<parser::Statement>, CompileError> {
+    analyze_with_lines(source).map(|(ast, _)| ast)
+}
+
+/// Like `analyze`, also returning the source line of every statement as
+/// `parser::parse_with_lines` lists them.
+fn analyze_with_lines(source: &str) -> Result<(Vec<parser::Statement>, Vec<usize>), CompileError> {
+    // 1. Lex the source code into tokens.
+    let (tokens, spans) = lexer::lex(source).map_err(|e| CompileError::located(Phase::Lex, e))?;
+
+    // 2. Parse the tokens into an AST.
+    let (ast, lines) = parser::parse_with_lines(&tokens, &spans)
+        .map_err(|e| CompileError::located(Phase::Parse, e))?;
+
+    // 3. Check the AST for semantic errors.
+    semantic::check(&ast).map_err(|e| CompileError::new(Phase::Semantic, e))?;
+
+    Ok((ast, lines))
+}
+
+/// Lexes and parses c85 source code, carrying on past parse errors to
+/// report all of them instead of only the first. A lexer error is reported
+/// on its own, since there are no tokens to parse.
+pub fn parse_errors(source: &str) -> Vec<CompileError> {
+    let (tokens, spans) = match lexer::lex(source) {
+        Ok(lexed) => lexed,
+        Err(e) => return vec![CompileError::located(Phase::Lex, e)],
+    };
+    let (_, errors) = parser::parse_all(&tokens, &spans);
+    errors
+        .into_iter()
+        .map(|e| CompileError::located(Phase::Parse, e))
+        .collect()
+}
+
+/// In strict mode, width conflicts are errors rather than warnings.
+fn check_strict(ast: &[parser::Statement], options: &GenerateOptions) -> Result<(), CompileError> {
+    match semantic::width_conflicts(ast).into_iter().next() {
+        Some(conflict) if options.strict => Err(CompileError::new(Phase::Semantic, conflict)),
+        _ => Ok(()),
+    }
+}
+
+/// Checks c85 source code for errors without generating any code, using the
+/// default options.
+pub fn check(source: &str) -> Result<(), CompileError> {
+    check_with_options(source, &GenerateOptions::default())
+}
+
+/// Checks c85 source code for errors without generating any code.
+pub fn check_with_options(source: &str, options: &GenerateOptions) -> Result<(), CompileError> {
+    let ast = analyze(source)?;
+    check_strict(&ast, options)?;
+    // Folding is where assert_fits is checked against known values
+    optimize::fold_constants(ast).map_err(|e| CompileError::new(Phase::Semantic, e))?;
+    Ok(())
+}
+
+/// Compiles c85 source code as far as the symbol table: each static variable
+/// with its data address and backing register, using the default options.
+pub fn symbol_table(source: &str) -> Result<String, CompileError> {
+    symbol_table_with_options(source, &GenerateOptions::default())
+}
+
+/// Compiles c85 source code as far as the symbol table, laying out the data
+/// region from `options.data_start`.
+pub fn symbol_table_with_options(
+    source: &str,
+    options: &GenerateOptions,
+) -> Result<String, CompileError> {
+    let ast = analyze(source)?;
+    Ok(codegen::symbol_table(&ast, options.data_start))
+}
+
+/// Compiles c85 source code and maps each line of the generated assembly to
+/// the source line of the statement it was generated for, one
+/// "asm_line c85_line" pair per line. Lines of no statement, such as the
+/// ORG, the final HLT and the data definitions, are left out.
+pub fn source_map_with_options(
+    source: &str,
+    options: &GenerateOptions,
+) -> Result<String, CompileError> {
+    let (ast, lines) = analyze_with_lines(source)?;
+    check_strict(&ast, options)?;
+    let ast = optimize::fold_constants(ast).map_err(|e| CompileError::new(Phase::Semantic, e))?;
+    codegen::source_map(&ast, &lines, options).map_err(|e| CompileError::new(Phase::Codegen, e))
+}
+
+/// Runs the compiler phase by phase for `--verbose`, describing what each one
+/// produces: the tokens, the parsed AST, the static-variable allocation and
+/// the assembly. The trace ends at the first phase that fails; reporting the
+/// error is left to the caller.
+pub fn trace_with_options(source: &str, options: &GenerateOptions) -> String {
+    let mut trace = String::new();
+    let Ok((tokens, spans)) = lexer::lex(source) else {
+        return trace;
+    };
+    trace.push_str("== Tokens ==\n");
+    for (token, span) in tokens.iter().zip(&spans) {
+        trace.push_str(&format!("{}:{} {:?}\n", span.line, span.column, token));
+    }
+
+    let Ok(ast) = parser::parse(&tokens, &spans) else {
+        return trace;
+    };
+    trace.push_str(&format!("== AST ==\n{:#?}\n", ast));
+
+    if semantic::check(&ast).is_err() || check_strict(&ast, options).is_err() {
+        return trace;
+    }
+    let Ok(ast) = optimize::fold_constants(ast) else {
+        return trace;
+    };
+    trace.push_str("== Allocation ==\n");
+    trace.push_str(&codegen::symbol_table(&ast, options.data_start));
+
+    let Ok(asm_code) = codegen::generate(&ast, options) else {
+        return trace;
+    };
+    trace.push_str("== Assembly ==\n");
+    trace.push_str(&asm_code);
+    trace
+}
+
+/// Lexes, parses and checks c85 source code, returning the AST as JSON for
+/// external tools. Constants are already substituted; nothing is folded.
+pub fn ast_json(source: &str) -> Result<String, CompileError> {
+    let ast = analyze(source)?;
+    Ok(serde_json::to_string_pretty(&ast).expect("the AST only holds strings, numbers and enums"))
+}
+
+/// Lexes, parses and checks c85 source code, returning the top-level
+/// statements with the comments written before each one, for documentation
+/// tools. Constants are already substituted.
+pub fn documented(source: &str) -> Result<Vec<parser::Documented>, CompileError> {
+    analyze(source)?;
+    let (tokens, spans) =
+        lexer::lex_with_comments(source).map_err(|e| CompileError::located(Phase::Lex, e))?;
+    parser::parse_documented(&tokens, &spans).map_err(|e| CompileError::located(Phase::Parse, e))
+}
+
+/// Lexes, parses and checks c85 source code, listing for main and each
+/// function the functions it calls and the variables it touches.
+pub fn dependency_graph(source: &str) -> Result<String, CompileError> {
+    let ast = analyze(source)?;
+    let list = |names: &[String]| {
+        if names.is_empty() {
+            "-".to_string()
+        } else {
+            names.join(", ")
+        }
+    };
+    let mut graph = String::new();
+    for dependencies in semantic::dependencies(&ast) {
+        graph.push_str(&format!("{}\n", dependencies.function));
+        graph.push_str(&format!("  calls: {}\n", list(&dependencies.calls)));
+        graph.push_str(&format!("  variables: {}\n", list(&dependencies.variables)));
+    }
+    Ok(graph)
+}
+
+/// Checks c85 source code for suspicious but legal code, such as variables
+/// that are assigned but never read, overlapping malloc() regions or
+/// variables used at two widths.
+/// Returns one message per warning. Uses the default options.
+pub fn warnings(source: &str) -> Result<Vec<String>, CompileError> {
+    warnings_with_options(source, &GenerateOptions::default())
+}
+
+/// Checks c85 source code for suspicious but legal code, with malloc()
+/// regions checked against the data region at `options.data_start`.
+pub fn warnings_with_options(
+    source: &str,
+    options: &GenerateOptions,
+) -> Result<Vec<String>, CompileError> {
+    let ast = analyze(source)?;
+    let mut warnings: Vec<String> = semantic::unused_variables(&ast)
+        .into_iter()
+        .map(|name| format!("Variable '{}' is assigned but never used. Removing it frees its data address and register.", name))
+        .collect();
+    warnings.extend(codegen::malloc_overlaps(&ast, options.data_start));
+    warnings.extend(semantic::width_conflicts(&ast));
+    warnings.extend(semantic::constant_loops(&ast));
+    warnings.extend(codegen::stack_overflows(&ast, options));
+    Ok(warnings)
+}
+
+/// Encodes generated assembly as an Intel HEX file.
+pub fn intel_hex(asm_code: &str) -> Result<String, CompileError> {
+    codegen::to_intel_hex(asm_code).map_err(|e| CompileError::new(Phase::Assemble, e))
+}
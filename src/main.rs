@@ -2,47 +2,392 @@
 
 use std::env;
 use std::fs;
+use std::io::{self, Read};
 use std::path::Path;
 
-// Declare all our modules
-mod lexer;
-mod parser;
-mod codegen;
+use c85c::codegen;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: c85c <input_file.c85>");
-        std::process::exit(1);
-    }
-    let input_path = &args[1];
+const USAGE: &str = "Usage: c85c [--check] [--strict] [--deny-warnings] [--fail-fast] [--dump-ast] [--emit-deps] [--verbose] [--no-halt] [--no-comments] [--org <address>] [--data <address>] [--stack <address>] [--no-stack] [--relocatable] [--print-routine <address>] [--target 8080|8085] [--sym] [--source-map] [--hex] [--stdout] <input_file.c85 | directory | ->";
 
-    let source_code = fs::read_to_string(input_path).unwrap_or_else(|err| {
-        eprintln!("Error reading file '{}': {}", input_path, err);
-        std::process::exit(1)
-    });
+/// Parses a CLI address such as "0x2000" or "8192"
+fn parse_address(value: &str) -> Result<u16, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    };
+    parsed.map_err(|_| {
+        format!(
+            "Invalid address '{}'. Expected a value up to 0xFFFF.",
+            value
+        )
+    })
+}
 
-    // 1. Lex the source code into tokens.
-    let tokens = lexer::lex(&source_code).unwrap_or_else(|err| {
-        eprintln!("Lexer Error: {}", err);
+/// Writes an output file, exiting with an error message if that fails
+fn write_output(path: &Path, contents: &str) {
+    fs::write(path, contents).unwrap_or_else(|err| {
+        eprintln!(
+            "Error writing to file '{}': {}",
+            path.to_str().unwrap(),
+            err
+        );
         std::process::exit(1)
     });
+}
 
-    // 2. Parse the tokens into an AST.
-    let ast = parser::parse(&tokens).unwrap_or_else(|err| {
-        eprintln!("Parsing Error: {}", err);
+/// Prints each warning on stderr. With --deny-warnings, any warning then
+/// fails the build; returns false in that case.
+fn report_warnings(
+    source_code: &str,
+    options: &codegen::GenerateOptions,
+    deny_warnings: bool,
+) -> bool {
+    let warnings = c85c::warnings_with_options(source_code, options).unwrap_or_default();
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+    if deny_warnings && !warnings.is_empty() {
+        eprintln!(
+            "error: {} warning(s) treated as errors because of --deny-warnings",
+            warnings.len()
+        );
+        return false;
+    }
+    true
+}
+
+fn main() {
+    let mut options = codegen::GenerateOptions::default();
+    let mut input_path = None;
+    let mut write_symbols = false;
+    let mut write_source_map = false;
+    let mut write_hex = false;
+    let mut to_stdout = false;
+    let mut check_only = false;
+    let mut deny_warnings = false;
+    let mut fail_fast = false;
+    let mut dump_ast = false;
+    let mut emit_deps = false;
+    let mut verbose = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-halt" => options.emit_halt = false,
+            "--no-stack" => options.stack_top = None,
+            "--relocatable" => options.relocatable = true,
+            "--no-comments" => options.comments = false,
+            "--sym" => write_symbols = true,
+            "--source-map" => write_source_map = true,
+            "--hex" => write_hex = true,
+            "--stdout" => to_stdout = true,
+            "--check" => check_only = true,
+            "--strict" => options.strict = true,
+            "--deny-warnings" => deny_warnings = true,
+            "--fail-fast" => fail_fast = true,
+            "--dump-ast" => dump_ast = true,
+            "--emit-deps" => emit_deps = true,
+            "--verbose" => verbose = true,
+            "--org" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("Missing address after --org");
+                    std::process::exit(1)
+                });
+                options.code_origin = parse_address(&value).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1)
+                });
+            }
+            "--data" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("Missing address after --data");
+                    std::process::exit(1)
+                });
+                options.data_start = parse_address(&value).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1)
+                });
+            }
+            "--stack" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("Missing address after --stack");
+                    std::process::exit(1)
+                });
+                options.stack_top = Some(parse_address(&value).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1)
+                }));
+            }
+            "--print-routine" => {
+                let value = args.next().unwrap_or_else(|| {
+                    eprintln!("Missing address after --print-routine");
+                    std::process::exit(1)
+                });
+                options.print_routine = parse_address(&value).unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1)
+                });
+            }
+            "--target" => {
+                options.target = match args.next().as_deref() {
+                    Some("8080") => codegen::Target::Intel8080,
+                    Some("8085") => codegen::Target::Intel8085,
+                    _ => {
+                        eprintln!("--target expects 8080 or 8085");
+                        std::process::exit(1)
+                    }
+                };
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option: {}", arg);
+                std::process::exit(1);
+            }
+            _ if input_path.is_none() => input_path = Some(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+    let input_path = &input_path.unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
         std::process::exit(1)
     });
+    let mode = Mode {
+        check_only,
+        write_symbols,
+        write_source_map,
+        write_hex,
+        deny_warnings,
+        verbose,
+    };
+
+    // A directory compiles every .c85 file in it, each to its own outputs
+    if Path::new(input_path).is_dir() {
+        if to_stdout || dump_ast || emit_deps {
+            eprintln!(
+                "--stdout, --dump-ast and --emit-deps need a single input file, not a directory."
+            );
+            std::process::exit(1);
+        }
+        if !build_directory(Path::new(input_path), &options, &mode, fail_fast) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // "-" reads the source from stdin, which also sends the assembly to stdout
+    let from_stdin = input_path == "-";
+    if from_stdin && (write_symbols || write_source_map || write_hex) {
+        eprintln!("--sym, --source-map and --hex need an input file to name their output after.");
+        std::process::exit(1);
+    }
+    let to_stdout = to_stdout || from_stdin;
+
+    let source_code = if from_stdin {
+        let mut source = String::new();
+        io::stdin()
+            .read_to_string(&mut source)
+            .unwrap_or_else(|err| {
+                eprintln!("Error reading from stdin: {}", err);
+                std::process::exit(1)
+            });
+        source
+    } else {
+        fs::read_to_string(input_path).unwrap_or_else(|err| {
+            eprintln!("Error reading file '{}': {}", input_path, err);
+            std::process::exit(1)
+        })
+    };
+
+    // With --dump-ast, print the checked AST as JSON instead of compiling
+    if dump_ast && !check_only {
+        let json = c85c::ast_json(&source_code).unwrap_or_else(|err| {
+            eprintln!("{}", err.render(&source_code));
+            std::process::exit(1)
+        });
+        println!("{}", json);
+        return;
+    }
+
+    // With --emit-deps, print what each function calls and touches instead of compiling
+    if emit_deps && !check_only {
+        let graph = c85c::dependency_graph(&source_code).unwrap_or_else(|err| {
+            eprintln!("{}", err.render(&source_code));
+            std::process::exit(1)
+        });
+        print!("{}", graph);
+        return;
+    }
 
-    // 3. Generate the assembly code from the AST.
-    let asm_code = codegen::generate(&ast);
+    if !build_file(
+        Path::new(input_path),
+        &source_code,
+        &options,
+        &mode,
+        to_stdout,
+    ) {
+        std::process::exit(1);
+    }
+}
+
+/// What to do with each input besides compiling it
+struct Mode {
+    check_only: bool,
+    write_symbols: bool,
+    write_source_map: bool,
+    write_hex: bool,
+    deny_warnings: bool,
+    verbose: bool,
+}
 
-    // 4. Write the output to an .asm file.
-    let output_path = Path::new(input_path).with_extension("asm");
-    fs::write(&output_path, asm_code).unwrap_or_else(|err| {
-        eprintln!("Error writing to file '{}': {}", output_path.to_str().unwrap(), err);
+/// Builds every .c85 file directly inside `dir`, in name order, and prints a
+/// summary. A failing file doesn't stop the others unless `fail_fast` is set.
+/// Returns whether every file succeeded.
+fn build_directory(
+    dir: &Path,
+    options: &codegen::GenerateOptions,
+    mode: &Mode,
+    fail_fast: bool,
+) -> bool {
+    let entries = fs::read_dir(dir).unwrap_or_else(|err| {
+        eprintln!("Error reading directory '{}': {}", dir.display(), err);
         std::process::exit(1)
     });
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file() && path.extension().is_some_and(|extension| extension == "c85")
+        })
+        .collect();
+    paths.sort();
+
+    let mut failed = 0;
+    let mut built = 0;
+    for path in &paths {
+        let succeeded = match fs::read_to_string(path) {
+            Ok(source_code) => build_file(path, &source_code, options, mode, false),
+            Err(err) => {
+                eprintln!("Error reading file '{}': {}", path.display(), err);
+                false
+            }
+        };
+        built += 1;
+        if !succeeded {
+            eprintln!("❌ {} failed", path.display());
+            failed += 1;
+            if fail_fast {
+                break;
+            }
+        }
+    }
+
+    let skipped = paths.len() - built;
+    println!(
+        "{} file(s): {} succeeded, {} failed{}",
+        paths.len(),
+        built - failed,
+        failed,
+        if skipped > 0 {
+            format!(", {} skipped after the first failure", skipped)
+        } else {
+            String::new()
+        }
+    );
+    failed == 0
+}
+
+/// Checks or compiles one source, printing errors and warnings, and writes
+/// its outputs next to `input_path` unless `to_stdout` is set. Returns
+/// whether it succeeded.
+fn build_file(
+    input_path: &Path,
+    source_code: &str,
+    options: &codegen::GenerateOptions,
+    mode: &Mode,
+    to_stdout: bool,
+) -> bool {
+    // With --verbose, trace every phase on stderr so stdout stays clean
+    if mode.verbose {
+        eprint!("{}", c85c::trace_with_options(source_code, options));
+    }
+
+    // With --check, stop after reporting errors and warnings; nothing is written
+    if mode.check_only {
+        if let Err(err) = c85c::check_with_options(source_code, options) {
+            // Parsing recovers from errors, so all of them can be listed at once
+            let errors = match err.phase {
+                c85c::Phase::Parse => c85c::parse_errors(source_code),
+                _ => vec![err],
+            };
+            for err in errors {
+                eprintln!("{}", err.render(source_code));
+            }
+            return false;
+        }
+        return report_warnings(source_code, options, mode.deny_warnings);
+    }
 
-    println!("✅ Compilation successful! Output written to {}", output_path.to_str().unwrap());
-}
\ No newline at end of file
+    // 1-3. Lex, parse and generate the assembly code.
+    let asm_code = match c85c::compile_with_options(source_code, options) {
+        Ok(asm_code) => asm_code,
+        Err(err) => {
+            eprintln!("{}", err.render(source_code));
+            return false;
+        }
+    };
+
+    // Warnings don't stop compilation unless they are denied, in which case
+    // nothing is written
+    if !report_warnings(source_code, options, mode.deny_warnings) {
+        return false;
+    }
+
+    // 4. Optionally write the symbol table to a .sym file.
+    if mode.write_symbols {
+        match c85c::symbol_table_with_options(source_code, options) {
+            Ok(symbols) => write_output(&input_path.with_extension("sym"), &symbols),
+            Err(err) => {
+                eprintln!("{}", err.render(source_code));
+                return false;
+            }
+        }
+    }
+
+    // 5. Optionally map the assembly's lines to source lines in a .map file.
+    if mode.write_source_map {
+        match c85c::source_map_with_options(source_code, options) {
+            Ok(map) => write_output(&input_path.with_extension("map"), &map),
+            Err(err) => {
+                eprintln!("{}", err.render(source_code));
+                return false;
+            }
+        }
+    }
+
+    // 6. Optionally encode the machine code as an Intel HEX file.
+    if mode.write_hex {
+        match c85c::intel_hex(&asm_code) {
+            Ok(hex) => write_output(&input_path.with_extension("hex"), &hex),
+            Err(err) => {
+                eprintln!("{}", err);
+                return false;
+            }
+        }
+    }
+
+    // 7. Write the assembly to stdout or to an .asm file.
+    if to_stdout {
+        print!("{}", asm_code);
+    } else {
+        let output_path = input_path.with_extension("asm");
+        write_output(&output_path, &asm_code);
+        println!(
+            "✅ Compilation successful! Output written to {}",
+            output_path.to_str().unwrap()
+        );
+    }
+    true
+}
@@ -0,0 +1,263 @@
+// src/optimize.rs
+
+use crate::parser::{
+    BinaryOperator, Operand, Statement, hex_value, is_8bit_register, is_16bit_register,
+};
+use std::collections::HashMap;
+
+/// Constant folding: tracks registers and variables holding compile-time-known
+/// values and replaces 8-bit binary operations on two known values with a
+/// single immediate load of the result.
+///
+/// The tracked values are also what `assert_fits` is checked against; an
+/// assertion that a known value breaks is an error.
+pub fn fold_constants(statements: Vec<Statement>) -> Result<Vec<Statement>, String> {
+    let mut known = HashMap::new();
+    // Main ends in HLT, and function bodies are folded on their own
+    fold_block(statements, &mut known, false)
+}
+
+/// Folds a block. `flags_read_after` says whether the code that runs after
+/// the block may read the flags it leaves.
+fn fold_block(
+    statements: Vec<Statement>,
+    known: &mut HashMap<String, u16>,
+    flags_read_after: bool,
+) -> Result<Vec<Statement>, String> {
+    let flags_read: Vec<bool> = (1..=statements.len())
+        .map(|next| flags_read(&statements[next..], flags_read_after))
+        .collect();
+    let mut folded = Vec::with_capacity(statements.len());
+    for (statement, flags_read) in statements.into_iter().zip(flags_read) {
+        // A folded MVI sets no flags, so an operation whose flags are read
+        // later has to run
+        if flags_read {
+            known.clear();
+        }
+        folded.push(fold_statement(statement, known)?);
+    }
+    Ok(folded)
+}
+
+fn fold_statement(
+    statement: Statement,
+    known: &mut HashMap<String, u16>,
+) -> Result<Statement, String> {
+    let statement = match statement {
+        Statement::MoveImmediate {
+            ref register,
+            ref value,
+        } => {
+            let value = hex_value(value).and_then(|v| u16::try_from(v).ok());
+            set(known, register, value);
+            statement
+        }
+        Statement::StaticAssignment {
+            ref variable,
+            ref value,
+            ..
+        } => {
+            let value = hex_value(value).and_then(|v| u16::try_from(v).ok());
+            set(known, variable, value);
+            statement
+        }
+        Statement::RegisterMove { ref dest, ref src } => {
+            // A copied 8-bit value stays known; pairs aren't tracked
+            let value = if is_8bit_register(dest) {
+                known.get(src).copied()
+            } else {
+                None
+            };
+            set(known, dest, value);
+            statement
+        }
+        Statement::BinaryOp {
+            register,
+            operator,
+            second,
+        } => {
+            // Only 8-bit operations are folded; a 16-bit value in an 8-bit
+            // operation means its low byte, which codegen handles
+            let byte = |name: &str| known.get(name).and_then(|&value| u8::try_from(value).ok());
+            let immediate = match &second {
+                Operand::Reg(name) => byte(name),
+                Operand::Imm(value) => hex_value(value).and_then(|value| u8::try_from(value).ok()),
+            };
+            let folded = match (byte(&register), immediate) {
+                (Some(left), Some(right)) if !is_16bit_register(&register) => {
+                    Some(match operator {
+                        BinaryOperator::Add => left.wrapping_add(right),
+                        BinaryOperator::Sub => left.wrapping_sub(right),
+                        BinaryOperator::And => left & right,
+                        BinaryOperator::Or => left | right,
+                        BinaryOperator::Xor => left ^ right,
+                    })
+                }
+                _ => None,
+            };
+            set(known, &register, folded.map(u16::from));
+            if folded.is_none() {
+                // The operation itself runs through the accumulator
+                known.remove("A");
+            }
+
+            match folded {
+                Some(result) if is_8bit_register(&register) => Statement::MoveImmediate {
+                    register,
+                    value: format!("0x{:02X}", result),
+                },
+                Some(result) => Statement::StaticAssignment {
+                    variable: register,
+                    value: format!("0x{:02X}", result),
+                    is_16bit: false,
+                },
+                None => Statement::BinaryOp {
+                    register,
+                    operator,
+                    second,
+                },
+            }
+        }
+        Statement::If {
+            guard,
+            body,
+            else_body,
+        } => {
+            // The comparison clobbers A, and either branch may have run
+            // afterwards, so start afresh
+            known.clear();
+            let body = fold_block(body, &mut HashMap::new(), true)?;
+            let else_body = else_body
+                .map(|else_body| fold_block(else_body, &mut HashMap::new(), true))
+                .transpose()?;
+            Statement::If {
+                guard,
+                body,
+                else_body,
+            }
+        }
+        Statement::While { guard, body } => {
+            // The body may run many times, so nothing is known inside or after it
+            known.clear();
+            let body = fold_block(body, &mut HashMap::new(), true)?;
+            Statement::While { guard, body }
+        }
+        Statement::Repeat { count, body } => {
+            // Same as a while loop, and B is the counter
+            known.clear();
+            let body = fold_block(body, &mut HashMap::new(), true)?;
+            Statement::Repeat { count, body }
+        }
+        Statement::FunctionDef { name, body } => {
+            // Functions can be called from anywhere
+            let body = fold_block(body, &mut HashMap::new(), true)?;
+            Statement::FunctionDef { name, body }
+        }
+        Statement::ConstDef { .. }
+        | Statement::RegisterPin { .. }
+        | Statement::ArrayDecl { .. }
+        | Statement::DataBytes { .. } => {
+            // Declarations emit no code, so nothing is written
+            statement
+        }
+        Statement::AssertFits {
+            ref name,
+            ref bound,
+        } => {
+            // Emits nothing, so everything known stays known
+            let limit = hex_value(bound).unwrap_or(0);
+            if let Some(&value) = known.get(name)
+                && u64::from(value) > limit
+            {
+                return Err(format!(
+                    "assert_fits({}, {}) failed: '{}' holds 0x{:02X} at this point.",
+                    name, bound, name, value
+                ));
+            }
+            statement
+        }
+        other => {
+            // Anything else may write registers or memory we don't model
+            known.clear();
+            other
+        }
+    };
+    Ok(statement)
+}
+
+/// Whether the flags left before `rest` may be read by it. Statements that
+/// leave the flags alone, such as `MVI`, `MOV` and `STA`, are looked past up
+/// to the first that reads or sets them; `flags_read_after` is for the end
+/// of the block.
+fn flags_read(rest: &[Statement], flags_read_after: bool) -> bool {
+    for statement in rest {
+        if reads_flags(statement) {
+            return true;
+        }
+        if sets_flags(statement) {
+            return false;
+        }
+    }
+    flags_read_after
+}
+
+/// Whether a statement's code reads the flags left by the code before it:
+/// DAA adjusts by the carries, CMC flips the carry, RAL and RAR rotate it
+/// into A, and PUSH PSW saves the flags with A. Calls and jumps may reach
+/// code that reads them, and a repeat body runs before its `DCR B`.
+fn reads_flags(statement: &Statement) -> bool {
+    match statement {
+        Statement::DecimalAdjust | Statement::ComplementCarry => true,
+        Statement::Rotate { through_carry, .. } => *through_carry,
+        Statement::StackOp {
+            register_pair,
+            is_push,
+        } => *is_push && register_pair == "PSW",
+        Statement::Call { .. }
+        | Statement::Print { .. }
+        | Statement::Restart { .. }
+        | Statement::Goto { .. }
+        | Statement::Break
+        | Statement::Continue
+        | Statement::JumpHl
+        | Statement::Repeat { .. } => true,
+        _ => false,
+    }
+}
+
+/// Whether a statement sets all the flags before anything reads them: an
+/// 8-bit ALU operation, the compare of an if, while or conditional
+/// assignment, or `POP PSW`. A `return` halts, so nothing reads them.
+fn sets_flags(statement: &Statement) -> bool {
+    match statement {
+        // DAD only sets the carry
+        Statement::BinaryOp { register, .. } => !is_16bit_register(register),
+        Statement::If { .. }
+        | Statement::While { .. }
+        | Statement::ConditionalAssign { .. }
+        | Statement::Return { .. } => true,
+        Statement::StackOp {
+            register_pair,
+            is_push,
+        } => !*is_push && register_pair == "PSW",
+        _ => false,
+    }
+}
+
+/// Record the value written to `target`. A write to a register may change the
+/// variable backed by it, and a write to a variable goes through A and its
+/// backing register, so both forget everything else on the other side.
+fn set(known: &mut HashMap<String, u16>, target: &str, value: Option<u16>) {
+    if is_8bit_register(target) {
+        known.retain(|name, _| is_8bit_register(name) && name != target);
+    } else if is_16bit_register(target) {
+        known.clear();
+    } else {
+        known.retain(|name, _| {
+            !is_8bit_register(name) && !is_16bit_register(name) && name != target
+        });
+    }
+    if let Some(value) = value {
+        known.insert(target.to_string(), value);
+    }
+}
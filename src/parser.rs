@@ -1,19 +1,33 @@
 // src/parser.rs
 
-use crate::lexer::Token;
+use crate::lexer::{SourceError, Span, Token};
+use serde::Serialize;
+use std::collections::HashMap;
 
 /// A more precise Abstract Syntax Tree (AST) node.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
 pub enum Statement {
     // For MVI A, 0x08
     MoveImmediate {
         register: String,
         value: String,
     },
-    // For LXI HL, 0x6000
+    // For LXI HL, 0x6000 (malloc(0x6000) or malloc(0x6000, 0x20)), or
+    // LXI B, 0x1234 (reg BC = (0x12, 0x34);)
     LoadImmediateExtended {
         register_pair: String,
         address: String,
+        size: Option<String>, // bytes reserved from the address, when given
+    },
+    // For reg HL = wide; (LHLD, reading a 16-bit variable)
+    LoadPair {
+        register_pair: String,
+        variable: String,
+    },
+    // For C = A; (MOV C,A), DE = HL; (MOV D,H and MOV E,L) or SP = HL; (SPHL)
+    RegisterMove {
+        dest: String,
+        src: String,
     },
     // For counter = 0x06; (static allocation)
     StaticAssignment {
@@ -21,47 +35,249 @@ pub enum Statement {
         value: String,
         is_16bit: bool,
     },
-    // For A + B; (A = A + B with second operand always B)
+    // For const LIMIT = 0x10; (no storage, used as an immediate)
+    ConstDef {
+        name: String,
+        value: String,
+    },
+    // For @reg(B) counter = 0x00; (backs the variable with that register;
+    // emits nothing, the assignment after it is its own statement)
+    RegisterPin {
+        variable: String,
+        register: String,
+    },
+    // For buffer[0x10]; (reserves 16 bytes of static memory)
+    ArrayDecl {
+        name: String,
+        size: String,
+    },
+    // For table: db 0x01, 0x02; (bytes placed in the data region, indexed
+    // like an array) or table: dw 0x1234; (16-bit words, little-endian)
+    DataBytes {
+        label: String,
+        values: Vec<String>,
+        wide: bool,
+    },
+    // For A = buffer[B]; (HL = buffer + B, MOV A,M)
+    ArrayLoad {
+        register: String,
+        array: String,
+        index: String, // register, variable or constant name
+    },
+    // For buffer[B] = A; (HL = buffer + B, MOV M,A)
+    ArrayStore {
+        array: String,
+        index: String,    // register, variable or constant name
+        register: String, // register, variable or constant name
+    },
+    // For A += C; (A = A + C), A &= 0x0F; (ANI 0FH) or HL += DE; (DAD D).
+    // A + C; is the older form.
     BinaryOp {
         register: String,
         operator: BinaryOperator,
+        second: Operand,
+    },
+    // For *HL = A; (MOV M,A) or *DE = A; (STAX D)
+    StoreIndirect {
+        register_pair: String,
+        register: String,
+    },
+    // For A = *HL; (MOV A,M) or A = *DE; (LDAX D)
+    LoadIndirect {
+        register: String,
+        register_pair: String,
+    },
+    // For reg A = [0x8000]; (LDA 8000H) or reg HL = [0x8000]; (LHLD 8000H)
+    LoadDirect {
+        register: String,
+        address: String,
+    },
+    // For [0x8000] = A; (STA 8000H) or [0x8000] = HL; (SHLD 8000H)
+    StoreDirect {
+        address: String,
+        register: String,
+    },
+    // For fn name { ... } (subroutine ending in RET)
+    FunctionDef {
+        name: String,
+        body: Vec<Statement>,
+    },
+    // For label start; (start:)
+    LabelDef {
+        name: String,
+    },
+    // For goto start; (JMP start)
+    Goto {
+        name: String,
+    },
+    // For break; (JMP past the end of the enclosing loop)
+    Break,
+    // For continue; (JMP to the next iteration of the enclosing loop)
+    Continue,
+    // For return 0x00; in main (MVI A, then HLT, so A holds the exit status)
+    Return {
+        value: String,
     },
-    // For HL++; or HL--;
+    // For name(); (CALL name)
+    Call {
+        name: String,
+    },
+    // For enable_interrupts(); (EI) or disable_interrupts(); (DI)
+    SetInterrupts {
+        enabled: bool,
+    },
+    // For read_interrupt_mask(); (RIM, into A) or set_interrupt_mask();
+    // (SIM, from A), which only the 8085 has
+    InterruptMask {
+        set: bool,
+    },
+    // For decimal_adjust(); (DAA), after adding packed BCD digits in A
+    DecimalAdjust,
+    // For ~A; (CMA)
+    Complement {
+        register: String,
+    },
+    // For complement_carry(); (CMC)
+    ComplementCarry,
+    // For jump_hl(); (PCHL, jump to the address in HL)
+    JumpHl,
+    // For nop(); (NOP)
+    Nop,
+    // For align(0x10); (NOPs up to the next multiple of boundary)
+    Align {
+        boundary: u16,
+    },
+    // For assert_fits(counter, 0xFF); (checked at compile time, emits nothing)
+    AssertFits {
+        name: String,
+        bound: String,
+    },
+    // For swap(HL, DE); or swap(DE, HL); (XCHG)
+    Swap {
+        first: String,
+        second: String,
+    },
+    // For rst(7); (RST 7)
+    Restart {
+        vector: u8,
+    },
+    // For push(HL); (PUSH H) or pop(PSW); (POP PSW)
+    StackOp {
+        register_pair: String,
+        is_push: bool,
+    },
+    // For A << 1; (RLC), A >> 1; (RRC), A <<< 1; (RAL) or A >>> 1; (RAR)
+    Rotate {
+        register: String,
+        direction: RotateDirection,
+        through_carry: bool,
+    },
+    // For A = in(0x01); (IN 01H)
+    PortIn {
+        register: String,
+        port: String,
+    },
+    // For out(0x02) = A; (OUT 02H)
+    PortOut {
+        port: String,
+        register: String,
+    },
+    // For print(A); (A = value, CALL the display routine)
+    Print {
+        register: String,
+    },
+    // For HL++; or C--; (INX/DCX for a pair, INR/DCR for an 8-bit register)
     PointerIncDec {
         register_pair: String,
         is_increment: bool,
     },
-    // For if(counter > result) { ... } or if(A > B) { ... }
+    // For if(counter > result) { ... } or if(A > B && C < D) { ... } else { ... }
     If {
-        left: String,       // register or variable name
+        guard: Guard,
+        body: Vec<Statement>,
+        else_body: Option<Vec<Statement>>,
+    },
+    // For A = counter > result ? 0x01 : 0x00;
+    ConditionalAssign {
+        dest: String,
+        condition_left: Operand,
+        condition: Condition,
+        condition_right: Operand,
+        then_value: String,
+        else_value: String,
+    },
+    // For flag = (counter > result); (0x01 if true, else 0x00)
+    ComparisonAssign {
+        variable: String,
+        condition_left: Operand,
         condition: Condition,
-        right: String,      // register or variable name
+        condition_right: Operand,
+    },
+    // For while(counter < result) { ... }
+    While {
+        guard: Guard,
+        body: Vec<Statement>,
+    },
+    // For repeat(0x0A) { ... } (B counts down with DCR B / JNZ)
+    Repeat {
+        count: String,
         body: Vec<Statement>,
     },
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub enum BinaryOperator {
-    Add,    // +
-    Sub,    // -
-    And,    // &
-    Or,     // |
-    Xor,    // ^
+    Add, // +
+    Sub, // -
+    And, // &
+    Or,  // |
+    Xor, // ^
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub enum RotateDirection {
+    Left,  // << or <<<
+    Right, // >> or >>>
+}
+
+/// The condition tree of an if or while: comparisons joined by && and ||
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub enum Guard {
+    // For A > B
+    Compare {
+        left: Operand,
+        condition: Condition,
+        right: Operand,
+    },
+    // For A > B && C < D
+    And(Box<Guard>, Box<Guard>),
+    // For A > B || C < D
+    Or(Box<Guard>, Box<Guard>),
+}
+
+/// One side of a comparison
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub enum Operand {
+    Reg(String), // register, variable or constant name
+    Imm(String), // 8-bit literal, e.g. 0x05
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub enum Condition {
-    Greater,     // >
-    Less,        // <
-    Equal,       // ==
+    Greater,      // >
+    Less,         // <
+    Equal,        // ==
+    GreaterEqual, // >=
+    LessEqual,    // <=
+    NotEqual,     // !=
 }
 
 /// Validates hex literal bounds
 fn validate_hex(value: &str, expected_16bit: bool) -> Result<(), String> {
     let hex_str = value.trim_start_matches("0x").trim_start_matches("0X");
-    let num = u64::from_str_radix(hex_str, 16)
-        .map_err(|_| format!("Invalid hex literal: {}", value))?;
-    
+    let num =
+        u64::from_str_radix(hex_str, 16).map_err(|_| format!("Invalid hex literal: {}", value))?;
+
     if expected_16bit {
         if num > 0xFFFF {
             return Err(format!("16-bit value {} exceeds maximum (0xFFFF)", value));
@@ -74,227 +290,3220 @@ fn validate_hex(value: &str, expected_16bit: bool) -> Result<(), String> {
     Ok(())
 }
 
+/// Reads a numeric literal token as a hex string (e.g. "0x0A"), whatever radix it was written in
+fn numeric_literal(token: &Token) -> Option<String> {
+    match token {
+        Token::HexLiteral(value) => Some(value.clone()),
+        Token::DecLiteral(value) => value
+            .parse::<u64>()
+            .ok()
+            .map(|num| format!("0x{:02X}", num)),
+        Token::BinLiteral(value) => u64::from_str_radix(&value[2..], 2)
+            .ok()
+            .map(|num| format!("0x{:02X}", num)),
+        Token::OctLiteral(value) => u64::from_str_radix(&value[2..], 8)
+            .ok()
+            .map(|num| format!("0x{:02X}", num)),
+        _ => None,
+    }
+}
+
+/// Converts the literal after a '-' to its two's complement hex string in
+/// the given width, e.g. -1 becomes 0xFF (8-bit) or 0xFFFF (16-bit)
+fn negative_literal(token: Option<&Token>, is_16bit: bool) -> Result<String, String> {
+    let magnitude = token
+        .and_then(numeric_literal)
+        .and_then(|value| hex_value(&value))
+        .ok_or_else(|| "Expected a number after '-'".to_string())?;
+    let (bits, limit) = if is_16bit { (16, 0x8000) } else { (8, 0x80) };
+    if magnitude > limit {
+        return Err(format!(
+            "{}-bit value -{} is below the minimum (-{})",
+            bits, magnitude, limit
+        ));
+    }
+    Ok(format!(
+        "0x{:02X}",
+        ((1u64 << bits) - magnitude) & ((1u64 << bits) - 1)
+    ))
+}
+
+/// A negative literal whose width isn't fixed by a register: 8-bit when it
+/// fits, 16-bit otherwise
+fn negative_literal_any_width(token: Option<&Token>) -> Result<String, String> {
+    negative_literal(token, false).or_else(|_| negative_literal(token, true))
+}
+
 /// Checks if a register is 16-bit
-fn is_16bit_register(reg: &str) -> bool {
+pub(crate) fn is_16bit_register(reg: &str) -> bool {
     matches!(reg, "HL" | "BC" | "DE" | "SP")
 }
 
-/// Infers if value needs 16-bit storage
-fn is_16bit_value(value: &str) -> bool {
-    let hex_str = value.trim_start_matches("0x").trim_start_matches("0X");
-    u64::from_str_radix(hex_str, 16).unwrap_or(0) > 0xFF
+/// Checks if a register is one of the 8-bit registers
+pub(crate) fn is_8bit_register(reg: &str) -> bool {
+    matches!(reg, "A" | "B" | "C" | "D" | "E" | "H" | "L")
 }
 
-/// Parses a slice of Tokens into a list of Statements (our AST).
-pub fn parse(tokens: &[Token]) -> Result<Vec<Statement>, String> {
-    let mut statements = Vec::new();
-    let mut i = 0;
+/// Checks if a name is one of the 8085's registers or register pairs
+pub(crate) fn is_valid_register(reg: &str) -> bool {
+    is_8bit_register(reg) || is_16bit_register(reg) || reg == "PSW"
+}
+
+/// Checks if a name is an 8-bit register or a register pair
+fn is_register(name: &str) -> bool {
+    is_8bit_register(name) || is_16bit_register(name)
+}
 
-    // Expect main { ... }
-    if tokens.get(i) != Some(&Token::Main) || tokens.get(i+1) != Some(&Token::LBrace) {
-        return Err("Expected 'main{' at the beginning of the file.".to_string());
+/// Validates a register copy: both sides must be registers of the same
+/// width, and SP can only be loaded from HL (SPHL)
+fn validate_register_move(dest: &str, src: &str) -> Result<(), String> {
+    for name in [dest, src] {
+        if !is_register(name) {
+            return Err(format!(
+                "'{} = {}' copies between registers, but '{}' is not a register",
+                dest, src, name
+            ));
+        }
+    }
+    if is_8bit_register(dest) != is_8bit_register(src) {
+        return Err(format!(
+            "Can't copy between registers of different widths, got {} = {}",
+            dest, src
+        ));
     }
-    i += 2; // Consume "main" and "{"
+    if (dest == "SP" || src == "SP") && (dest, src) != ("SP", "HL") {
+        return Err(format!(
+            "SP can only be loaded from HL (SPHL), got {} = {}",
+            dest, src
+        ));
+    }
+    Ok(())
+}
 
-    parse_block(tokens, &mut i, &mut statements)?;
+/// Validates a register/pair combination for memory access through a pointer.
+/// HL can move any 8-bit register via M; BC and DE only transfer A (STAX/LDAX).
+fn validate_indirect(register: &str, register_pair: &str) -> Result<(), String> {
+    if !is_8bit_register(register) {
+        return Err(format!(
+            "Pointer access requires an 8-bit register, got {}",
+            register
+        ));
+    }
+    match register_pair {
+        "HL" => Ok(()),
+        "BC" | "DE" if register == "A" => Ok(()),
+        "BC" | "DE" => Err(format!(
+            "Pointer access through {} only supports register A, got {}",
+            register_pair, register
+        )),
+        _ => Err(format!(
+            "Pointer access requires HL, BC or DE, got {}",
+            register_pair
+        )),
+    }
+}
 
-    Ok(statements)
+/// Numeric value of a hex literal string such as "0x0A"
+pub(crate) fn hex_value(value: &str) -> Option<u64> {
+    let hex_str = value.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(hex_str, 16).ok()
 }
 
-/// Parse a block of statements (handles nested blocks for if statements)
-fn parse_block(tokens: &[Token], i: &mut usize, statements: &mut Vec<Statement>) -> Result<(), String> {
-    while *i < tokens.len() && tokens[*i] != Token::RBrace {
-        // Check what kind of statement this is
-        match tokens.get(*i) {
-            Some(Token::Reg) => {
-                // Register assignment: reg A = 0x08; or reg HL = malloc(0x6000);
-                let register = match tokens.get(*i + 1) {
-                    Some(Token::Identifier(name)) => name.clone(),
-                    _ => return Err("Expected a register name after 'reg'.".to_string()),
-                };
+/// Infers if value needs 16-bit storage
+fn is_16bit_value(value: &str) -> bool {
+    hex_value(value).unwrap_or(0) > 0xFF
+}
+
+/// Checks if a value is a literal rather than the name of a constant
+fn is_literal(value: &str) -> bool {
+    value.starts_with("0x") || value.starts_with("0X")
+}
 
-                if tokens.get(*i + 2) != Some(&Token::Equal) {
-                    return Err("Expected '=' after register name.".to_string());
+/// Collects every constant in the program by name. Constants are visible
+/// everywhere, wherever they are declared.
+pub(crate) fn collect_constants(
+    statements: &[Statement],
+    constants: &mut HashMap<String, String>,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::ConstDef { name, .. } if constants.contains_key(name) => {
+                return Err(format!("Constant '{}' is defined more than once.", name));
+            }
+            Statement::ConstDef { name, value } => {
+                constants.insert(name.clone(), value.clone());
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_constants(body, constants)?;
+                if let Some(else_body) = else_body {
+                    collect_constants(else_body, constants)?;
                 }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                collect_constants(body, constants)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
 
-                match tokens.get(*i + 3) {
-                    // Direct value assignment: reg A = 0x08;
-                    Some(Token::HexLiteral(value)) => {
-                        let is_16bit = is_16bit_register(&register);
-                        validate_hex(value, is_16bit)?;
-                        
-                        statements.push(Statement::MoveImmediate {
-                            register,
-                            value: value.clone(),
-                        });
-                        *i += 4; // Consumed: reg, A, =, 0x08
+/// Replaces constant names written as values (reg A = LIMIT; x = LIMIT;)
+/// or addresses (reg A = [PORT];) with the constant's literal, checking it fits where it's used. Any other
+/// name loaded into a register pair is a 16-bit variable (reg HL = wide;).
+fn substitute_constants(
+    statements: &mut [Statement],
+    constants: &HashMap<String, String>,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::MoveImmediate { register, value }
+                if !is_literal(value)
+                    && !constants.contains_key(value.as_str())
+                    && matches!(register.as_str(), "HL" | "DE" | "BC") =>
+            {
+                *statement = Statement::LoadPair {
+                    register_pair: register.clone(),
+                    variable: value.clone(),
+                };
+            }
+            Statement::MoveImmediate { register, value } if !is_literal(value) => {
+                let constant = lookup_constant(value, constants)?;
+                validate_hex(constant, is_16bit_register(register))
+                    .map_err(|e| format!("{} (constant '{}')", e, value))?;
+                *value = constant.clone();
+            }
+            Statement::Return { value } if !is_literal(value) => {
+                let constant = lookup_constant(value, constants)?;
+                validate_hex(constant, false)
+                    .map_err(|e| format!("Exit status {} (constant '{}')", e, value))?;
+                *value = constant.clone();
+            }
+            Statement::LoadDirect { address, .. } | Statement::StoreDirect { address, .. }
+                if !is_literal(address) =>
+            {
+                *address = lookup_constant(address, constants)?.clone();
+            }
+            Statement::StaticAssignment {
+                value, is_16bit, ..
+            } if !is_literal(value) => {
+                let constant = lookup_constant(value, constants)?;
+                *is_16bit = is_16bit_value(constant);
+                *value = constant.clone();
+            }
+            Statement::ConditionalAssign {
+                dest,
+                then_value,
+                else_value,
+                ..
+            } => {
+                for value in [then_value, else_value] {
+                    if !is_literal(value) {
+                        let constant = lookup_constant(value, constants)?;
+                        validate_hex(constant, is_16bit_register(dest))
+                            .map_err(|e| format!("{} (constant '{}')", e, value))?;
+                        *value = constant.clone();
                     }
-                    // Malloc call: reg HL = malloc(0x6000);
-                    Some(Token::Malloc) => {
-                        if !is_16bit_register(&register) {
-                            return Err(format!("malloc() requires a 16-bit register pair, got {}", register));
-                        }
-                        
-                        let address = match tokens.get(*i + 5) {
-                            Some(Token::HexLiteral(addr)) => addr.clone(),
-                            _ => return Err("Expected a hex address inside malloc().".to_string()),
-                        };
+                }
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                substitute_constants(body, constants)?;
+                if let Some(else_body) = else_body {
+                    substitute_constants(else_body, constants)?;
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                substitute_constants(body, constants)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
 
-                        validate_hex(&address, true)?;
+fn lookup_constant<'a>(
+    name: &str,
+    constants: &'a HashMap<String, String>,
+) -> Result<&'a String, String> {
+    constants.get(name).ok_or_else(|| {
+        format!(
+            "Unknown constant '{}'. Expected a numeric literal or a name declared with 'const'.{}",
+            name,
+            bare_hex_hint(name)
+        )
+    })
+}
 
-                        if tokens.get(*i + 4) != Some(&Token::LParen) || tokens.get(*i + 6) != Some(&Token::RParen) {
-                            return Err("Malformed malloc() call. Expected malloc(ADDRESS).".to_string());
-                        }
+/// A suggestion for a name made only of hex digits, such as FF, which was
+/// probably meant as a hex literal; empty for any other name
+pub(crate) fn bare_hex_hint(name: &str) -> String {
+    if name.chars().all(|c| c.is_ascii_hexdigit()) {
+        format!(" Did you mean 0x{}? Hex literals need the 0x prefix.", name)
+    } else {
+        String::new()
+    }
+}
 
-                        statements.push(Statement::LoadImmediateExtended {
-                            register_pair: register,
-                            address,
-                        });
-                        *i += 7; // Consumed: reg, HL, =, malloc, (, 0x6000, )
-                    }
-                    _ => return Err("Invalid expression after '='.".to_string()),
-                }
+/// bare_hex_hint for the token where a number was expected
+fn token_hex_hint(token: Option<&Token>) -> String {
+    match token {
+        Some(Token::Identifier(name)) => bare_hex_hint(name),
+        _ => String::new(),
+    }
+}
 
-                // Expect semicolon
-                if tokens.get(*i) != Some(&Token::Semicolon) {
-                    return Err("Expected ';' at the end of the statement.".to_string());
-                }
-                *i += 1; // Consume ";"
-            }
-            Some(Token::Identifier(name)) => {
-                let identifier = name.clone();
-                
-                // Check what follows: =, +, -, &, |, ^, ++, --
-                match tokens.get(*i + 1) {
-                    Some(Token::Equal) => {
-                        // Static allocation: counter = 0x06;
-                        let value = match tokens.get(*i + 2) {
-                            Some(Token::HexLiteral(v)) => v.clone(),
-                            _ => return Err(format!("Expected hex value after '=' for variable '{}'.", identifier)),
-                        };
+/// Describes the token at `i` for error messages, e.g. "line 8, column 3, found Plus"
+fn describe(tokens: &[Token], spans: &[Span], i: usize) -> String {
+    match (tokens.get(i), spans.get(i)) {
+        (Some(token), Some(span)) if keyword_text(token).is_some() => {
+            format!("{}, found keyword '{}'", span, keyword_text(token).unwrap())
+        }
+        (Some(token), Some(span)) => format!("{}, found {:?}", span, token),
+        _ => "end of input".to_string(),
+    }
+}
 
-                        let is_16bit = is_16bit_value(&value);
-                        validate_hex(&value, is_16bit)?;
+/// The source text of a keyword token, for errors about reserved names
+fn keyword_text(token: &Token) -> Option<&'static str> {
+    match token {
+        Token::Main => Some("main"),
+        Token::Reg => Some("reg"),
+        Token::Malloc => Some("malloc"),
+        Token::If => Some("if"),
+        Token::Else => Some("else"),
+        Token::While => Some("while"),
+        Token::Fn => Some("fn"),
+        Token::In => Some("in"),
+        Token::Out => Some("out"),
+        Token::Const => Some("const"),
+        Token::Print => Some("print"),
+        Token::Label => Some("label"),
+        Token::Goto => Some("goto"),
+        Token::Repeat => Some("repeat"),
+        Token::Break => Some("break"),
+        Token::Continue => Some("continue"),
+        Token::Return => Some("return"),
+        _ => None,
+    }
+}
 
-                        statements.push(Statement::StaticAssignment {
-                            variable: identifier,
-                            value,
-                            is_16bit,
-                        });
-                        *i += 3; // Consumed: identifier, =, value
-                    }
-                    Some(Token::Plus) | Some(Token::Minus) | Some(Token::And) | Some(Token::Or) | Some(Token::Xor) => {
-                        // Binary operation: A + B;
-                        let operator = match tokens.get(*i + 1) {
-                            Some(Token::Plus) => BinaryOperator::Add,
-                            Some(Token::Minus) => BinaryOperator::Sub,
-                            Some(Token::And) => BinaryOperator::And,
-                            Some(Token::Or) => BinaryOperator::Or,
-                            Some(Token::Xor) => BinaryOperator::Xor,
-                            _ => unreachable!(),
-                        };
+/// The error for a keyword at `i` written where a name of the given kind is expected
+fn reserved_name(token: &Token, kind: &str, spans: &[Span], i: usize) -> SourceError {
+    error_at(
+        spans,
+        i,
+        format!(
+            "'{}' is a reserved keyword and cannot be used as a {} at {}.",
+            keyword_text(token).unwrap_or_default(),
+            kind,
+            position(spans, i)
+        ),
+    )
+}
 
-                        // Second operand must be B
-                        if tokens.get(*i + 2) != Some(&Token::Identifier("B".to_string())) {
-                            return Err("Second operand must be register B.".to_string());
-                        }
+/// The operators that may start a condition
+const COMPARISONS: [&str; 6] = [">", "<", "==", ">=", "<=", "!="];
 
-                        statements.push(Statement::BinaryOp {
-                            register: identifier,
-                            operator,
-                        });
-                        *i += 3; // Consumed: A, +, B
-                    }
-                    Some(Token::PlusPlus) => {
-                        // Pointer increment: HL++;
-                        if !is_16bit_register(&identifier) {
-                            return Err(format!("Increment/decrement requires a 16-bit register pair, got {}", identifier));
-                        }
+/// The source text of an operator token, for errors about operator sequences
+fn operator_text(token: &Token) -> Option<&'static str> {
+    match token {
+        Token::Plus => Some("+"),
+        Token::Minus => Some("-"),
+        Token::And => Some("&"),
+        Token::Or => Some("|"),
+        Token::Xor => Some("^"),
+        Token::Star => Some("*"),
+        Token::Tilde => Some("~"),
+        Token::PlusPlus => Some("++"),
+        Token::MinusMinus => Some("--"),
+        Token::Shl => Some("<<"),
+        Token::Shr => Some(">>"),
+        Token::ShlCarry => Some("<<<"),
+        Token::ShrCarry => Some(">>>"),
+        Token::PlusEqual => Some("+="),
+        Token::MinusEqual => Some("-="),
+        Token::AndEqual => Some("&="),
+        Token::OrEqual => Some("|="),
+        Token::XorEqual => Some("^="),
+        Token::Greater => Some(">"),
+        Token::Less => Some("<"),
+        Token::EqualEqual => Some("=="),
+        Token::GreaterEqual => Some(">="),
+        Token::LessEqual => Some("<="),
+        Token::NotEqual => Some("!="),
+        Token::AndAnd => Some("&&"),
+        Token::OrOr => Some("||"),
+        _ => None,
+    }
+}
 
-                        statements.push(Statement::PointerIncDec {
-                            register_pair: identifier,
-                            is_increment: true,
-                        });
-                        *i += 2; // Consumed: HL, ++
-                    }
-                    Some(Token::MinusMinus) => {
-                        // Pointer decrement: HL--;
-                        if !is_16bit_register(&identifier) {
-                            return Err(format!("Increment/decrement requires a 16-bit register pair, got {}", identifier));
-                        }
+/// The position of the token at `i`, for errors that aren't about the token's kind
+fn position(spans: &[Span], i: usize) -> String {
+    match spans.get(i) {
+        Some(span) => span.to_string(),
+        None => "end of input".to_string(),
+    }
+}
 
-                        statements.push(Statement::PointerIncDec {
-                            register_pair: identifier,
-                            is_increment: false,
-                        });
-                        *i += 2; // Consumed: HL, --
-                    }
-                    _ => return Err(format!("Unexpected token after identifier '{}'.", identifier)),
+/// An error about the token at `i`; past the last token it points nowhere,
+/// as `position` says "end of input"
+fn error_at(spans: &[Span], i: usize, message: String) -> SourceError {
+    SourceError {
+        message,
+        span: spans.get(i).copied(),
+    }
+}
+
+/// A top-level statement with the comments written just before it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Documented {
+    pub comments: Vec<String>,
+    pub statement: Statement,
+}
+
+/// Parses a slice of Tokens (with their parallel Spans) into a list of Statements (our AST).
+/// Comment tokens are ignored.
+pub fn parse(tokens: &[Token], spans: &[Span]) -> Result<Vec<Statement>, SourceError> {
+    parse_with_lines(tokens, spans).map(|(statements, _)| statements)
+}
+
+/// Parses like `parse`, also returning the source line of every statement,
+/// nested ones included, in the order a depth-first walk of the AST visits
+/// them: each statement before the statements of its body.
+pub fn parse_with_lines(
+    tokens: &[Token],
+    spans: &[Span],
+) -> Result<(Vec<Statement>, Vec<usize>), SourceError> {
+    let (tokens, spans, _) = strip_comments(tokens, spans);
+    let (mut lines, mut errors) = (Vec::new(), Vec::new());
+    let statements = parse_program(&tokens, &spans, &mut Vec::new(), &mut lines, &mut errors);
+    first_error(errors)?;
+    Ok((statements, lines))
+}
+
+/// Parses like `parse` but doesn't stop at the first error: a statement that
+/// fails to parse is skipped up to its ';' or closing '}' and parsing carries
+/// on. Returns what could be parsed along with every error, in source order.
+pub fn parse_all(tokens: &[Token], spans: &[Span]) -> (Vec<Statement>, Vec<SourceError>) {
+    let (tokens, spans, _) = strip_comments(tokens, spans);
+    let mut errors = Vec::new();
+    let statements = parse_program(
+        &tokens,
+        &spans,
+        &mut Vec::new(),
+        &mut Vec::new(),
+        &mut errors,
+    );
+    (statements, errors)
+}
+
+/// Fails with the first of `errors`, if any
+fn first_error(errors: Vec<SourceError>) -> Result<(), SourceError> {
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Parses tokens from `lex_with_comments`, attaching the comments before each
+/// statement of main, and before each function defined after it, to that
+/// statement. Comments inside nested blocks are dropped.
+pub fn parse_documented(tokens: &[Token], spans: &[Span]) -> Result<Vec<Documented>, SourceError> {
+    let (tokens, spans, mut leading) = strip_comments(tokens, spans);
+    let (mut starts, mut errors) = (Vec::new(), Vec::new());
+    let statements = parse_program(&tokens, &spans, &mut starts, &mut Vec::new(), &mut errors);
+    first_error(errors)?;
+    Ok(statements
+        .into_iter()
+        .enumerate()
+        .map(|(index, statement)| {
+            let comments = match starts.iter().rfind(|&&(first, _)| first == index) {
+                Some(&(_, token)) => leading.remove(&token).unwrap_or_default(),
+                None => Vec::new(),
+            };
+            Documented {
+                comments,
+                statement,
+            }
+        })
+        .collect())
+}
+
+/// Drops comment tokens, returning the comments that came before each
+/// remaining token keyed by its new index.
+fn strip_comments(
+    tokens: &[Token],
+    spans: &[Span],
+) -> (Vec<Token>, Vec<Span>, HashMap<usize, Vec<String>>) {
+    let mut kept = Vec::new();
+    let mut kept_spans = Vec::new();
+    let mut leading: HashMap<usize, Vec<String>> = HashMap::new();
+    for (token, span) in tokens.iter().zip(spans) {
+        match token {
+            Token::Comment(text) => leading.entry(kept.len()).or_default().push(text.clone()),
+            _ => {
+                kept.push(token.clone());
+                kept_spans.push(*span);
+            }
+        }
+    }
+    (kept, kept_spans, leading)
+}
+
+/// Parses the whole program: one main block and any number of function
+/// definitions, in any order. Records in `starts` the statement count and
+/// token index at the start of each top-level statement, in `lines` the line
+/// of every statement as `parse_with_lines` returns it, and in `errors` every
+/// error found, in source order. After an error the statement is skipped and
+/// parsing carries on, so the statements returned may be incomplete.
+///
+/// The statements of main come first and the functions after them, whatever
+/// order they were written in, since main's code runs first.
+fn parse_program(
+    tokens: &[Token],
+    spans: &[Span],
+    starts: &mut Vec<(usize, usize)>,
+    lines: &mut Vec<usize>,
+    errors: &mut Vec<SourceError>,
+) -> Vec<Statement> {
+    let mut statements = Vec::new();
+    let mut main_found = false;
+    // Each function with the lines of its statements and its first token
+    let mut functions = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match tokens[i] {
+            Token::Main => {
+                if main_found {
+                    errors.push(error_at(
+                        spans,
+                        i,
+                        format!(
+                            "'main' is defined more than once, again at {}.",
+                            position(spans, i)
+                        ),
+                    ));
+                }
+                if tokens.get(i + 1) != Some(&Token::LBrace) {
+                    errors.push(error_at(
+                        spans,
+                        i + 1,
+                        format!(
+                            "Expected '{{' after 'main' at {}.",
+                            describe(tokens, spans, i + 1)
+                        ),
+                    ));
+                    return statements;
                 }
+                i += 2; // Consume "main" and "{"
 
-                // Expect semicolon
-                if tokens.get(*i) != Some(&Token::Semicolon) {
-                    return Err("Expected ';' at the end of the statement.".to_string());
+                // A second main is still parsed for its errors, then dropped
+                let (mut body, mut body_starts, mut body_lines) =
+                    (Vec::new(), Vec::new(), Vec::new());
+                parse_block(
+                    tokens,
+                    spans,
+                    &mut i,
+                    &mut body,
+                    &mut body_starts,
+                    &mut body_lines,
+                    errors,
+                );
+
+                // parse_block also stops at the end of input, so the brace may be missing
+                if tokens.get(i) != Some(&Token::RBrace) {
+                    errors.push(error_at(
+                        spans,
+                        i,
+                        format!(
+                            "Expected '}}' to close main block at {}.",
+                            describe(tokens, spans, i)
+                        ),
+                    ));
+                    return statements;
                 }
-                *i += 1; // Consume ";"
-            }
-            Some(Token::If) => {
-                // If statement: if(A > B) { ... } or if(counter > result) { ... }
-                *i += 1; // Consume "if"
+                i += 1; // Consume "}"
 
-                if tokens.get(*i) != Some(&Token::LParen) {
-                    return Err("Expected '(' after 'if'.".to_string());
+                if !main_found {
+                    main_found = true;
+                    statements = body;
+                    *starts = body_starts;
+                    *lines = body_lines;
                 }
-                *i += 1; // Consume "("
+            }
+            Token::Fn => {
+                let (start, mut function_lines) = (i, vec![spans[i].line]);
+                match parse_function(tokens, spans, &mut i, &mut function_lines, errors) {
+                    Ok(function) => functions.push((function, function_lines, start)),
+                    Err(error) => {
+                        errors.push(error);
+                        i = skip_statement(tokens, start);
+                    }
+                }
+            }
+            _ => {
+                errors.push(error_at(spans, i, format!("Expected 'main {{ ... }}' or a function definition at the top level, at {}.", describe(tokens, spans, i))));
+                return statements;
+            }
+        }
+    }
 
-                let left = match tokens.get(*i) {
-                    Some(Token::Identifier(name)) => name.clone(),
-                    _ => return Err("Expected register or variable name in condition.".to_string()),
-                };
-                *i += 1;
+    if !main_found {
+        let message = "No 'main { ... }' block was found. Every program needs one; it is where execution starts.".to_string();
+        errors.push(SourceError {
+            message,
+            span: None,
+        });
+        return statements;
+    }
 
-                let condition = match tokens.get(*i) {
-                    Some(Token::Greater) => Condition::Greater,
-                    Some(Token::Less) => Condition::Less,
-                    Some(Token::EqualEqual) => Condition::Equal,
-                    _ => return Err("Expected condition: '>', '<', or '=='.".to_string()),
-                };
-                *i += 1;
+    // Functions go after main's statements, as if written after main
+    for (function, function_lines, start) in functions {
+        starts.push((statements.len(), start));
+        lines.extend(function_lines);
+        statements.push(function);
+    }
 
-                let right = match tokens.get(*i) {
-                    Some(Token::Identifier(name)) => name.clone(),
-                    _ => return Err("Expected register or variable name in condition.".to_string()),
-                };
-                *i += 1;
+    // Constants in a partly parsed program could be reported as missing
+    if errors.is_empty() {
+        let mut constants = HashMap::new();
+        if let Err(message) = collect_constants(&statements, &mut constants)
+            .and_then(|_| substitute_constants(&mut statements, &constants))
+        {
+            errors.push(SourceError {
+                message,
+                span: None,
+            });
+        }
+    }
+
+    statements
+}
+
+/// Names of the built-in statements written like calls
+const INTRINSICS: [&str; 14] = [
+    "enable_interrupts",
+    "disable_interrupts",
+    "read_interrupt_mask",
+    "set_interrupt_mask",
+    "decimal_adjust",
+    "complement_carry",
+    "jump_hl",
+    "nop",
+    "align",
+    "rst",
+    "push",
+    "pop",
+    "swap",
+    "assert_fits",
+];
+
+/// Parse a function definition: fn name { ... }
+fn parse_function(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+    lines: &mut Vec<usize>,
+    errors: &mut Vec<SourceError>,
+) -> Result<Statement, SourceError> {
+    *i += 1; // Consume "fn"
+
+    let name = match tokens.get(*i) {
+        Some(Token::Identifier(name)) if INTRINSICS.contains(&name.as_str()) => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "'{}' is a built-in and can't be used as a function name at {}.",
+                    name,
+                    position(spans, *i)
+                ),
+            ));
+        }
+        Some(Token::Identifier(name)) => name.clone(),
+        Some(token) if keyword_text(token).is_some() => {
+            return Err(reserved_name(token, "function name", spans, *i));
+        }
+        _ => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected a function name after 'fn' at {}.",
+                    describe(tokens, spans, *i)
+                ),
+            ));
+        }
+    };
+    *i += 1;
+
+    let body = parse_braced_block(tokens, spans, i, "fn", lines, errors)?;
+
+    Ok(Statement::FunctionDef { name, body })
+}
+
+/// Parse a block of statements (handles nested blocks for if statements),
+/// noting in `starts` where each one begins and in `lines` its line. A
+/// statement that fails to parse is recorded in `errors` and skipped.
+fn parse_block(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+    statements: &mut Vec<Statement>,
+    starts: &mut Vec<(usize, usize)>,
+    lines: &mut Vec<usize>,
+    errors: &mut Vec<SourceError>,
+) {
+    while *i < tokens.len() && tokens[*i] != Token::RBrace {
+        let (first, slot, start, line) = (statements.len(), lines.len(), *i, spans[*i].line);
+        starts.push((first, start));
+        lines.push(line);
+        match parse_statement(tokens, spans, i, statements, lines, errors) {
+            // A chain (a = b = 0x05;) is several statements on the same line
+            Ok(()) => {
+                lines.splice(
+                    slot..=slot,
+                    std::iter::repeat_n(line, statements.len() - first),
+                );
+            }
+            Err(error) => {
+                errors.push(error);
+                statements.truncate(first);
+                lines.truncate(slot);
+                starts.pop();
+                *i = skip_statement(tokens, start);
+            }
+        }
+    }
+}
 
-                if tokens.get(*i) != Some(&Token::RParen) {
-                    return Err("Expected ')' after condition.".to_string());
+/// Skips the statement starting at `start`, returning the index just past its
+/// ';' or its closing '}' (and any else block), or the index of the '}' that
+/// ends the enclosing block.
+fn skip_statement(tokens: &[Token], start: usize) -> usize {
+    let mut depth = 0;
+    let mut i = start;
+    while let Some(token) = tokens.get(i) {
+        match token {
+            Token::Semicolon if depth == 0 => return i + 1,
+            Token::LBrace => depth += 1,
+            Token::RBrace if depth == 0 => return i,
+            Token::RBrace => {
+                depth -= 1;
+                if depth == 0 && tokens.get(i + 1) != Some(&Token::Else) {
+                    return i + 1;
                 }
-                *i += 1; // Consume ")"
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    i
+}
 
-                if tokens.get(*i) != Some(&Token::LBrace) {
-                    return Err("Expected '{' after condition.".to_string());
+/// Parse one statement of a block, pushing what it parses to `statements`
+fn parse_statement(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+    statements: &mut Vec<Statement>,
+    lines: &mut Vec<usize>,
+    errors: &mut Vec<SourceError>,
+) -> Result<(), SourceError> {
+    // Check what kind of statement this is
+    match tokens.get(*i) {
+        Some(token)
+            if keyword_text(token).is_some() && tokens.get(*i + 1) == Some(&Token::Equal) =>
+        {
+            // A keyword assigned like a variable: main = 0x01;
+            return Err(reserved_name(token, "variable", spans, *i));
+        }
+        Some(Token::At) => {
+            // Register pinning: @reg(B) counter = 0x00;
+            if tokens.get(*i + 1) != Some(&Token::Reg) || tokens.get(*i + 2) != Some(&Token::LParen)
+            {
+                return Err(error_at(
+                    spans,
+                    *i + 1,
+                    format!(
+                        "Expected 'reg(' after '@' at {}. The only annotation is @reg(<register>).",
+                        describe(tokens, spans, *i + 1)
+                    ),
+                ));
+            }
+            let register = match tokens.get(*i + 3) {
+                Some(Token::Identifier(name))
+                    if matches!(name.as_str(), "A" | "B" | "C" | "D" | "E") =>
+                {
+                    name.clone()
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 3,
+                        format!(
+                            "Expected A, B, C, D or E inside @reg() at {}. Only those registers back variables.",
+                            describe(tokens, spans, *i + 3)
+                        ),
+                    ));
+                }
+            };
+            if tokens.get(*i + 4) != Some(&Token::RParen) {
+                return Err(error_at(
+                    spans,
+                    *i + 4,
+                    format!(
+                        "Expected ')' after the register in @reg() at {}.",
+                        describe(tokens, spans, *i + 4)
+                    ),
+                ));
+            }
+            let variable = match (tokens.get(*i + 5), tokens.get(*i + 6)) {
+                (Some(Token::Identifier(name)), Some(Token::Equal)) if !is_register(name) => {
+                    name.clone()
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 5,
+                        format!(
+                            "@reg({}) must come right before a variable assignment, e.g. @reg({}) counter = 0x00; at {}.",
+                            register,
+                            register,
+                            describe(tokens, spans, *i + 5)
+                        ),
+                    ));
                 }
-                *i += 1; // Consume "{"
+            };
 
-                let mut body = Vec::new();
-                parse_block(tokens, i, &mut body)?;
+            statements.push(Statement::RegisterPin { variable, register });
+            *i += 5; // Consumed: @, reg, (, B, )
 
-                if tokens.get(*i) != Some(&Token::RBrace) {
-                    return Err("Expected '}' to close if block.".to_string());
+            // The annotated assignment is parsed as a statement of its own
+            return parse_statement(tokens, spans, i, statements, lines, errors);
+        }
+        Some(Token::Reg) => {
+            // Register assignment: reg A = 0x08; or reg HL = malloc(0x6000);
+            let register = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name)) if name == "PSW" => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "PSW can't be assigned; save and restore it with push(PSW) and pop(PSW) at {}.",
+                            position(spans, *i + 1)
+                        ),
+                    ));
+                }
+                Some(Token::Identifier(name)) if is_valid_register(name) => name.clone(),
+                Some(Token::Identifier(name)) => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "'{}' is not an 8085 register at {}. Expected A, B, C, D, E, H, L or a pair HL, BC, DE or SP.",
+                            name,
+                            position(spans, *i + 1)
+                        ),
+                    ));
                 }
-                *i += 1; // Consume "}"
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected a register name after 'reg' at {}.",
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
 
-                statements.push(Statement::If {
-                    left,
-                    condition,
-                    right,
-                    body,
-                });
+            if tokens.get(*i + 2) != Some(&Token::Equal) {
+                return Err(error_at(
+                    spans,
+                    *i + 2,
+                    format!(
+                        "Expected '=' after register name at {}.",
+                        describe(tokens, spans, *i + 2)
+                    ),
+                ));
             }
-            _ => return Err(format!("Expected statement, found {:?}", tokens.get(*i))),
-        }
-    }
 
-    Ok(())
-}
\ No newline at end of file
+            match tokens.get(*i + 3) {
+                // Direct value assignment: reg A = 0x08;
+                Some(token) if numeric_literal(token).is_some() => {
+                    let value = numeric_literal(token).unwrap();
+                    let is_16bit = is_16bit_register(&register);
+                    validate_hex(&value, is_16bit).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 3,
+                            format!("{} at {}", e, position(spans, *i + 3)),
+                        )
+                    })?;
+
+                    statements.push(Statement::MoveImmediate { register, value });
+                    *i += 4; // Consumed: reg, A, =, 0x08
+                }
+                // Negative value: reg A = -1; (two's complement, 0xFF)
+                Some(Token::Minus) => {
+                    let value = negative_literal(tokens.get(*i + 4), is_16bit_register(&register))
+                        .map_err(|e| {
+                            error_at(
+                                spans,
+                                *i + 3,
+                                format!("{} at {}", e, position(spans, *i + 3)),
+                            )
+                        })?;
+
+                    statements.push(Statement::MoveImmediate { register, value });
+                    *i += 5; // Consumed: reg, A, =, -, 1
+                }
+                // Constant value: reg A = LIMIT; (resolved once all constants are known)
+                Some(Token::Identifier(name)) => {
+                    statements.push(Statement::MoveImmediate {
+                        register,
+                        value: name.clone(),
+                    });
+                    *i += 4; // Consumed: reg, A, =, LIMIT
+                }
+                // Malloc call: reg HL = malloc(0x6000);
+                Some(Token::Malloc) => {
+                    if !is_16bit_register(&register) {
+                        return Err(error_at(
+                            spans,
+                            *i + 1,
+                            format!(
+                                "malloc() requires a 16-bit register pair, got 8-bit register {} at {}. Use one of HL, BC, DE or SP, e.g. reg HL = malloc(...);",
+                                register,
+                                position(spans, *i + 1)
+                            ),
+                        ));
+                    }
+
+                    let address = match tokens.get(*i + 5).and_then(numeric_literal) {
+                        Some(addr) => addr,
+                        None => {
+                            return Err(error_at(
+                                spans,
+                                *i + 5,
+                                format!(
+                                    "Expected a hex address inside malloc() at {}.{}",
+                                    describe(tokens, spans, *i + 5),
+                                    token_hex_hint(tokens.get(*i + 5))
+                                ),
+                            ));
+                        }
+                    };
+
+                    validate_hex(&address, true).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 5,
+                            format!("{} at {}", e, position(spans, *i + 5)),
+                        )
+                    })?;
+
+                    if tokens.get(*i + 4) != Some(&Token::LParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "Malformed malloc() call at {}. Expected malloc(ADDRESS) or malloc(ADDRESS, SIZE).",
+                                position(spans, *i + 3)
+                            ),
+                        ));
+                    }
+
+                    // Optional size: malloc(0x6000, 0x20);
+                    let size = if tokens.get(*i + 6) == Some(&Token::Comma) {
+                        let size = match tokens.get(*i + 7).and_then(numeric_literal) {
+                            Some(size) => size,
+                            None => {
+                                return Err(error_at(
+                                    spans,
+                                    *i + 7,
+                                    format!(
+                                        "Expected a size after ',' in malloc() at {}.",
+                                        describe(tokens, spans, *i + 7)
+                                    ),
+                                ));
+                            }
+                        };
+                        let end = hex_value(&address).unwrap_or(0) + hex_value(&size).unwrap_or(0);
+                        if hex_value(&size) == Some(0) || end > 0x10000 {
+                            return Err(error_at(
+                                spans,
+                                *i + 7,
+                                format!(
+                                    "malloc() size {} must be at least 1 and end within the 64K address space at {}.",
+                                    size,
+                                    position(spans, *i + 7)
+                                ),
+                            ));
+                        }
+                        *i += 2; // Consume: ",", size
+                        Some(size)
+                    } else {
+                        None
+                    };
+
+                    if tokens.get(*i + 6) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "Malformed malloc() call at {}. Expected malloc(ADDRESS) or malloc(ADDRESS, SIZE).",
+                                position(spans, *i + 3)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::LoadImmediateExtended {
+                        register_pair: register,
+                        address,
+                        size,
+                    });
+                    *i += 7; // Consumed: reg, HL, =, malloc, (, 0x6000, )
+                }
+                // Pair from two bytes, high first: reg BC = (0x12, 0x34);
+                Some(Token::LParen) => {
+                    if !is_16bit_register(&register) {
+                        return Err(error_at(
+                            spans,
+                            *i + 1,
+                            format!(
+                                "A (high, low) byte pair needs a 16-bit register pair, got {} at {}",
+                                register,
+                                position(spans, *i + 1)
+                            ),
+                        ));
+                    }
+                    let byte = |index: usize| -> Result<u64, SourceError> {
+                        let value = match tokens.get(index).and_then(numeric_literal) {
+                            Some(value) => value,
+                            None => {
+                                return Err(error_at(
+                                    spans,
+                                    index,
+                                    format!(
+                                        "Expected a byte value in (high, low) at {}.{}",
+                                        describe(tokens, spans, index),
+                                        token_hex_hint(tokens.get(index))
+                                    ),
+                                ));
+                            }
+                        };
+                        validate_hex(&value, false).map_err(|e| {
+                            error_at(spans, index, format!("{} at {}", e, position(spans, index)))
+                        })?;
+                        Ok(hex_value(&value).unwrap_or(0))
+                    };
+                    let high = byte(*i + 4)?;
+                    if tokens.get(*i + 5) != Some(&Token::Comma) {
+                        return Err(error_at(
+                            spans,
+                            *i + 5,
+                            format!(
+                                "Expected ',' between the high and low bytes at {}.",
+                                describe(tokens, spans, *i + 5)
+                            ),
+                        ));
+                    }
+                    let low = byte(*i + 6)?;
+                    if tokens.get(*i + 7) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 7,
+                            format!(
+                                "Expected ')' after the low byte at {}.",
+                                describe(tokens, spans, *i + 7)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::LoadImmediateExtended {
+                        register_pair: register,
+                        address: format!("0x{:04X}", high << 8 | low),
+                        size: None,
+                    });
+                    *i += 8; // Consumed: reg, BC, =, (, 0x12, ",", 0x34, )
+                }
+                // Direct memory read: reg A = [0x8000];
+                Some(Token::LBracket) => {
+                    validate_direct(&register).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 1,
+                            format!("{} at {}", e, position(spans, *i + 1)),
+                        )
+                    })?;
+                    *i += 3; // Consumed: reg, A, =
+                    let address = parse_direct_address(tokens, spans, i)?;
+
+                    statements.push(Statement::LoadDirect { register, address });
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 3,
+                        format!(
+                            "Invalid expression after '=' at {}.",
+                            describe(tokens, spans, *i + 3)
+                        ),
+                    ));
+                }
+            }
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Identifier(name)) => {
+            let identifier = name.clone();
+
+            // Check what follows: =, +, -, &, |, ^, ++, --, (
+            match tokens.get(*i + 1) {
+                Some(Token::LParen) if identifier == "rst" => {
+                    // Software restart: rst(7);
+                    let vector = match tokens
+                        .get(*i + 2)
+                        .and_then(numeric_literal)
+                        .and_then(|v| hex_value(&v))
+                    {
+                        Some(vector) if vector <= 7 => vector as u8,
+                        Some(vector) => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Restart vector must be between 0 and 7, got {} at {}.",
+                                    vector,
+                                    position(spans, *i + 2)
+                                ),
+                            ));
+                        }
+                        None => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a restart vector (0-7) inside rst() at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    if tokens.get(*i + 3) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "Expected ')' after restart vector at {}.",
+                                describe(tokens, spans, *i + 3)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::Restart { vector });
+                    *i += 4; // Consumed: rst, (, 7, )
+                }
+                Some(Token::LParen) if identifier == "push" || identifier == "pop" => {
+                    // Stack: push(HL); or pop(DE);
+                    let register_pair = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(pair))
+                            if matches!(pair.as_str(), "BC" | "DE" | "HL" | "PSW") =>
+                        {
+                            pair.clone()
+                        }
+                        // A and the flags are pushed together as the processor status word
+                        Some(Token::Identifier(pair)) if pair == "AF" => "PSW".to_string(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "{}() requires BC, DE, HL or PSW at {}.",
+                                    identifier,
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    if tokens.get(*i + 3) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "Expected ')' after register pair at {}.",
+                                describe(tokens, spans, *i + 3)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::StackOp {
+                        register_pair,
+                        is_push: identifier == "push",
+                    });
+                    *i += 4; // Consumed: push, (, HL, )
+                }
+                Some(Token::LParen) if identifier == "assert_fits" => {
+                    // Compile-time bound: assert_fits(counter, 0xFF);
+                    let name = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(name)) => name.clone(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a register, variable or constant in assert_fits() at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    if tokens.get(*i + 3) != Some(&Token::Comma) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "assert_fits() takes a name and a bound; expected ',' at {}.",
+                                describe(tokens, spans, *i + 3)
+                            ),
+                        ));
+                    }
+                    let bound = match tokens.get(*i + 4).and_then(numeric_literal) {
+                        Some(bound) => bound,
+                        None => {
+                            return Err(error_at(
+                                spans,
+                                *i + 4,
+                                format!(
+                                    "Expected a numeric bound in assert_fits() at {}.{}",
+                                    describe(tokens, spans, *i + 4),
+                                    token_hex_hint(tokens.get(*i + 4))
+                                ),
+                            ));
+                        }
+                    };
+                    validate_hex(&bound, true).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 4,
+                            format!("Bound {} at {}", e, position(spans, *i + 4)),
+                        )
+                    })?;
+                    if tokens.get(*i + 5) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 5,
+                            format!(
+                                "Expected ')' after the bound at {}.",
+                                describe(tokens, spans, *i + 5)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::AssertFits { name, bound });
+                    *i += 6; // Consumed: assert_fits, (, counter, ",", 0xFF, )
+                }
+                Some(Token::LParen) if identifier == "swap" => {
+                    // Pair exchange: swap(HL, DE);
+                    let first = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(pair)) => pair.clone(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a register pair in swap() at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    if tokens.get(*i + 3) != Some(&Token::Comma) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "swap() takes two register pairs; expected ',' at {}.",
+                                describe(tokens, spans, *i + 3)
+                            ),
+                        ));
+                    }
+                    let second = match tokens.get(*i + 4) {
+                        Some(Token::Identifier(pair)) => pair.clone(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 4,
+                                format!(
+                                    "Expected a register pair in swap() at {}.",
+                                    describe(tokens, spans, *i + 4)
+                                ),
+                            ));
+                        }
+                    };
+                    if tokens.get(*i + 5) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 5,
+                            format!(
+                                "Expected ')' after register pair at {}.",
+                                describe(tokens, spans, *i + 5)
+                            ),
+                        ));
+                    }
+                    // XCHG is the 8085's only exchange instruction
+                    if !matches!(
+                        (first.as_str(), second.as_str()),
+                        ("HL", "DE") | ("DE", "HL")
+                    ) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "swap() can only exchange HL and DE (XCHG); the 8085 has no instruction to swap {} and {} at {}.",
+                                first,
+                                second,
+                                position(spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::Swap { first, second });
+                    *i += 6; // Consumed: swap, (, HL, ",", DE, )
+                }
+                Some(Token::LParen)
+                    if identifier == "enable_interrupts" || identifier == "disable_interrupts" =>
+                {
+                    // Interrupt control: enable_interrupts(); or disable_interrupts();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "'{}' takes no arguments; expected ')' at {}.",
+                                identifier,
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::SetInterrupts {
+                        enabled: identifier == "enable_interrupts",
+                    });
+                    *i += 3; // Consumed: name, (, )
+                }
+                Some(Token::LParen)
+                    if identifier == "read_interrupt_mask"
+                        || identifier == "set_interrupt_mask" =>
+                {
+                    // 8085 interrupt masks through A: read_interrupt_mask(); or set_interrupt_mask();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "'{}' takes no arguments; expected ')' at {}.",
+                                identifier,
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::InterruptMask {
+                        set: identifier == "set_interrupt_mask",
+                    });
+                    *i += 3; // Consumed: name, (, )
+                }
+                Some(Token::LParen) if identifier == "decimal_adjust" => {
+                    // BCD correction of A: decimal_adjust();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "'decimal_adjust' takes no arguments; expected ')' at {}.",
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::DecimalAdjust);
+                    *i += 3; // Consumed: decimal_adjust, (, )
+                }
+                Some(Token::LParen) if identifier == "complement_carry" => {
+                    // Flip the carry flag: complement_carry();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "'complement_carry' takes no arguments; expected ')' at {}.",
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::ComplementCarry);
+                    *i += 3; // Consumed: complement_carry, (, )
+                }
+                Some(Token::LParen) if identifier == "jump_hl" => {
+                    // Computed jump to the address in HL: jump_hl();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "'jump_hl' takes no arguments; expected ')' at {}.",
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::JumpHl);
+                    *i += 3; // Consumed: jump_hl, (, )
+                }
+                Some(Token::LParen) if identifier == "nop" => {
+                    // No operation, e.g. for timing: nop();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "'nop' takes no arguments; expected ')' at {}.",
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::Nop);
+                    *i += 3; // Consumed: nop, (, )
+                }
+                Some(Token::LParen) if identifier == "align" => {
+                    // Pad the code with NOPs to a boundary: align(0x10);
+                    let boundary = match tokens
+                        .get(*i + 2)
+                        .and_then(numeric_literal)
+                        .and_then(|v| hex_value(&v))
+                    {
+                        Some(boundary) if boundary.is_power_of_two() && boundary <= 0x8000 => {
+                            boundary as u16
+                        }
+                        Some(boundary) => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Alignment must be a power of two up to 0x8000, got 0x{:X} at {}.",
+                                    boundary,
+                                    position(spans, *i + 2)
+                                ),
+                            ));
+                        }
+                        None => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a boundary inside align() at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    if tokens.get(*i + 3) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 3,
+                            format!(
+                                "Expected ')' after alignment boundary at {}.",
+                                describe(tokens, spans, *i + 3)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::Align { boundary });
+                    *i += 4; // Consumed: align, (, 0x10, )
+                }
+                Some(Token::LParen) => {
+                    // Function call: name();
+                    if tokens.get(*i + 2) != Some(&Token::RParen) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "Expected ')' in call to '{}' at {}.",
+                                identifier,
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::Call { name: identifier });
+                    *i += 3; // Consumed: name, (, )
+                }
+                Some(Token::Equal) if tokens.get(*i + 2) == Some(&Token::Star) => {
+                    // Pointer load: A = *HL;
+                    let register_pair = match tokens.get(*i + 3) {
+                        Some(Token::Identifier(pair)) => pair.clone(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 3,
+                                format!(
+                                    "Expected a register pair after '*' at {}.",
+                                    describe(tokens, spans, *i + 3)
+                                ),
+                            ));
+                        }
+                    };
+                    validate_indirect(&identifier, &register_pair).map_err(|e| {
+                        error_at(spans, *i, format!("{} at {}", e, position(spans, *i)))
+                    })?;
+
+                    statements.push(Statement::LoadIndirect {
+                        register: identifier,
+                        register_pair,
+                    });
+                    *i += 4; // Consumed: A, =, *, HL
+                }
+                Some(Token::Equal) if tokens.get(*i + 3) == Some(&Token::LBracket) => {
+                    // Array load: A = buffer[B];
+                    let array = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(name)) => name.clone(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected an array name after '=' at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    *i += 3; // Consume: A, =, buffer
+                    let index = parse_index(tokens, spans, i)?;
+
+                    if is_16bit_register(&identifier) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "Array elements are 8-bit, got {} at {}",
+                                identifier,
+                                position(spans, *i)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::ArrayLoad {
+                        register: identifier,
+                        array,
+                        index,
+                    });
+                }
+                Some(Token::Colon) => {
+                    // Data table: table: db 0x01, 0x02, 0x03;
+                    let wide = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(directive)) if directive == "db" => false,
+                        Some(Token::Identifier(directive)) if directive == "dw" => true,
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected 'db' or 'dw' after '{}:' at {}.",
+                                    identifier,
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    *i += 3; // Consumed: table, :, db
+
+                    let mut values = Vec::new();
+                    loop {
+                        let value = match tokens.get(*i).and_then(numeric_literal) {
+                            Some(value) => value,
+                            None => {
+                                return Err(error_at(
+                                    spans,
+                                    *i,
+                                    format!(
+                                        "Expected a numeric value in '{}' at {}.{}",
+                                        identifier,
+                                        describe(tokens, spans, *i),
+                                        token_hex_hint(tokens.get(*i))
+                                    ),
+                                ));
+                            }
+                        };
+                        validate_hex(&value, wide).map_err(|e| {
+                            error_at(spans, *i, format!("{} at {}", e, position(spans, *i)))
+                        })?;
+                        values.push(value);
+                        *i += 1;
+                        if tokens.get(*i) != Some(&Token::Comma) {
+                            break;
+                        }
+                        *i += 1; // Consume ","
+                    }
+
+                    statements.push(Statement::DataBytes {
+                        label: identifier,
+                        values,
+                        wide,
+                    });
+                }
+                Some(Token::LBracket)
+                    if tokens.get(*i + 3) == Some(&Token::RBracket)
+                        && tokens.get(*i + 4) == Some(&Token::Semicolon) =>
+                {
+                    // Array declaration: buffer[0x10];
+                    let size = match tokens.get(*i + 2).and_then(numeric_literal) {
+                        Some(size) => size,
+                        None => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a numeric size for array '{}' at {}.{}",
+                                    identifier,
+                                    describe(tokens, spans, *i + 2),
+                                    token_hex_hint(tokens.get(*i + 2))
+                                ),
+                            ));
+                        }
+                    };
+                    validate_hex(&size, true).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 2,
+                            format!("Array size {} at {}", e, position(spans, *i + 2)),
+                        )
+                    })?;
+                    if hex_value(&size) == Some(0) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "Array '{}' must have at least one element at {}.",
+                                identifier,
+                                position(spans, *i + 2)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::ArrayDecl {
+                        name: identifier,
+                        size,
+                    });
+                    *i += 4; // Consumed: buffer, [, 0x10, ]
+                }
+                Some(Token::LBracket) => {
+                    // Array store: buffer[B] = A;
+                    *i += 1; // Consume "buffer"
+                    let index = parse_index(tokens, spans, i)?;
+
+                    if tokens.get(*i) != Some(&Token::Equal) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "Expected '=' after '{}[{}]' at {}.",
+                                identifier,
+                                index,
+                                describe(tokens, spans, *i)
+                            ),
+                        ));
+                    }
+
+                    let register = match tokens.get(*i + 1) {
+                        Some(Token::Identifier(name)) if !is_16bit_register(name) => name.clone(),
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Expected an 8-bit register, variable or constant after '=' at {}.",
+                                    describe(tokens, spans, *i + 1)
+                                ),
+                            ));
+                        }
+                    };
+
+                    statements.push(Statement::ArrayStore {
+                        array: identifier,
+                        index,
+                        register,
+                    });
+                    *i += 2; // Consumed: =, A
+                }
+                Some(Token::Equal) if tokens.get(*i + 2) == Some(&Token::In) => {
+                    // Port input: A = in(0x01);
+                    *i += 3; // Consume: A, =, in
+                    let port = parse_port(tokens, spans, i, "in")?;
+
+                    if is_16bit_register(&identifier) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "Port input requires an 8-bit register, got {} at {}",
+                                identifier,
+                                position(spans, *i)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::PortIn {
+                        register: identifier,
+                        port,
+                    });
+                }
+                Some(Token::Equal) if tokens.get(*i + 2) == Some(&Token::LParen) => {
+                    // Comparison result: flag = (counter > result);
+                    if is_register(&identifier) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "A comparison result is stored to a variable, got register {} at {}. Use {} = x > y ? 0x01 : 0x00; to set a register.",
+                                identifier,
+                                position(spans, *i),
+                                identifier
+                            ),
+                        ));
+                    }
+                    *i += 2; // Consume: flag, =
+                    let start = *i;
+                    let Guard::Compare {
+                        left,
+                        condition,
+                        right,
+                    } = parse_condition(tokens, spans, i, "=")?
+                    else {
+                        return Err(error_at(
+                            spans,
+                            start,
+                            format!(
+                                "Only a single comparison can be stored in '{}', not comparisons joined with && or ||, at {}.",
+                                identifier,
+                                position(spans, start)
+                            ),
+                        ));
+                    };
+
+                    statements.push(Statement::ComparisonAssign {
+                        variable: identifier,
+                        condition_left: left,
+                        condition,
+                        condition_right: right,
+                    });
+                }
+                Some(Token::Equal)
+                    if tokens
+                        .get(*i + 3)
+                        .and_then(operator_text)
+                        .is_some_and(|operator| COMPARISONS.contains(&operator)) =>
+                {
+                    // Conditional assignment: A = counter > result ? 0x01 : 0x00;
+                    if !is_register(&identifier) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "A conditional assignment sets a register, got '{}' at {}. Use an if/else to set a variable.",
+                                identifier,
+                                position(spans, *i)
+                            ),
+                        ));
+                    }
+                    *i += 2; // Consume: A, =
+                    let Guard::Compare {
+                        left,
+                        condition,
+                        right,
+                    } = parse_comparison(tokens, spans, i)?
+                    else {
+                        unreachable!()
+                    };
+
+                    if tokens.get(*i) != Some(&Token::Question) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "Expected '?' after the condition at {}. Conditions can't be joined with && or || here.",
+                                describe(tokens, spans, *i)
+                            ),
+                        ));
+                    }
+                    let then_value = parse_conditional_value(tokens, spans, *i + 1, &identifier)?;
+                    if tokens.get(*i + 2) != Some(&Token::Colon) {
+                        return Err(error_at(
+                            spans,
+                            *i + 2,
+                            format!(
+                                "Expected ':' between the two values at {}.",
+                                describe(tokens, spans, *i + 2)
+                            ),
+                        ));
+                    }
+                    let else_value = parse_conditional_value(tokens, spans, *i + 3, &identifier)?;
+
+                    statements.push(Statement::ConditionalAssign {
+                        dest: identifier,
+                        condition_left: left,
+                        condition,
+                        condition_right: right,
+                        then_value,
+                        else_value,
+                    });
+                    *i += 4; // Consumed: ?, 0x01, :, 0x00
+                }
+                Some(Token::Equal) if matches!(tokens.get(*i + 2), Some(Token::Identifier(src)) if is_register(&identifier) || is_register(src)) =>
+                {
+                    // Register copy: C = A;
+                    let Some(Token::Identifier(src)) = tokens.get(*i + 2) else {
+                        unreachable!()
+                    };
+                    validate_register_move(&identifier, src).map_err(|e| {
+                        error_at(spans, *i, format!("{} at {}", e, position(spans, *i)))
+                    })?;
+
+                    statements.push(Statement::RegisterMove {
+                        dest: identifier,
+                        src: src.clone(),
+                    });
+                    *i += 3; // Consumed: C, =, A
+                }
+                Some(Token::Equal) => {
+                    // Static allocation: counter = 0x06; or counter = LIMIT;
+                    // A chain (a = b = 0x05;) gives every name the final value
+                    if identifier == "PSW" {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "PSW can't be assigned; save and restore it with push(PSW) and pop(PSW) at {}.",
+                                position(spans, *i)
+                            ),
+                        ));
+                    }
+                    if is_register(&identifier) {
+                        // C = D; copies a register, so C = 0x05; must not make a variable C
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "Variables can't be named after a register, got {} at {}. Set it with 'reg {} = ...;' instead.",
+                                identifier,
+                                position(spans, *i),
+                                identifier
+                            ),
+                        ));
+                    }
+                    let mut targets = vec![identifier];
+                    while let (Some(Token::Identifier(name)), Some(Token::Equal)) =
+                        (tokens.get(*i + 2), tokens.get(*i + 3))
+                    {
+                        if is_register(name) {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Only variables can be assigned in a chain, got register {} at {}. Set it with 'reg {} = ...;' instead.",
+                                    name,
+                                    position(spans, *i + 2),
+                                    name
+                                ),
+                            ));
+                        }
+                        targets.push(name.clone());
+                        *i += 2; // Consumed: identifier, =
+                    }
+                    let identifier = targets.last().cloned().unwrap_or_default();
+
+                    let value = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(name)) => name.clone(),
+                        Some(token) if numeric_literal(token).is_some() => {
+                            numeric_literal(token).unwrap()
+                        }
+                        Some(Token::Malloc) => {
+                            let pair = if is_16bit_register(&identifier) {
+                                identifier.as_str()
+                            } else {
+                                "HL"
+                            };
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "malloc requires `reg <pair>` at {}. It loads an address into a register pair: write reg {} = malloc(...);",
+                                    position(spans, *i + 2),
+                                    pair
+                                ),
+                            ));
+                        }
+                        Some(Token::Minus) => {
+                            let value =
+                                negative_literal_any_width(tokens.get(*i + 3)).map_err(|e| {
+                                    error_at(
+                                        spans,
+                                        *i + 2,
+                                        format!("{} at {}", e, position(spans, *i + 2)),
+                                    )
+                                })?;
+                            *i += 1; // Consume "-"
+                            value
+                        }
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected hex value after '=' for variable '{}' at {}.",
+                                    identifier,
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+
+                    let is_16bit = is_16bit_value(&value);
+                    if is_literal(&value) {
+                        validate_hex(&value, is_16bit).map_err(|e| {
+                            error_at(
+                                spans,
+                                *i + 2,
+                                format!("{} at {}", e, position(spans, *i + 2)),
+                            )
+                        })?;
+                    }
+
+                    for variable in targets {
+                        statements.push(Statement::StaticAssignment {
+                            variable,
+                            value: value.clone(),
+                            is_16bit,
+                        });
+                    }
+                    *i += 3; // Consumed: identifier, =, value
+                }
+                Some(Token::PlusEqual)
+                | Some(Token::MinusEqual)
+                | Some(Token::AndEqual)
+                | Some(Token::OrEqual)
+                | Some(Token::XorEqual)
+                | Some(Token::Plus)
+                | Some(Token::Minus)
+                | Some(Token::And)
+                | Some(Token::Or)
+                | Some(Token::Xor) => {
+                    // Binary operation: A += B; or the older A + B;
+                    let operator = match tokens.get(*i + 1) {
+                        Some(Token::PlusEqual) | Some(Token::Plus) => BinaryOperator::Add,
+                        Some(Token::MinusEqual) | Some(Token::Minus) => BinaryOperator::Sub,
+                        Some(Token::AndEqual) | Some(Token::And) => BinaryOperator::And,
+                        Some(Token::OrEqual) | Some(Token::Or) => BinaryOperator::Or,
+                        Some(Token::XorEqual) | Some(Token::Xor) => BinaryOperator::Xor,
+                        _ => unreachable!(),
+                    };
+
+                    let second = match tokens.get(*i + 2) {
+                        Some(Token::Identifier(name)) => Operand::Reg(name.clone()),
+                        Some(token) if numeric_literal(token).is_some() => {
+                            // Only HL += 0x1234; takes a 16-bit value, through DAD
+                            let value = numeric_literal(token).unwrap();
+                            validate_hex(&value, identifier == "HL").map_err(|e| {
+                                error_at(
+                                    spans,
+                                    *i + 2,
+                                    format!("Immediate {} at {}", e, position(spans, *i + 2)),
+                                )
+                            })?;
+                            Operand::Imm(value)
+                        }
+                        // A+-B; or A&|B; lexes as two operators in a row
+                        Some(token) if operator_text(token).is_some() => {
+                            let first = operator_text(&tokens[*i + 1]).unwrap();
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Unexpected operator '{}' after '{}' at {}. A binary operation takes exactly one operator, e.g. {} {} B.",
+                                    operator_text(token).unwrap(),
+                                    first,
+                                    position(spans, *i + 2),
+                                    identifier,
+                                    first
+                                ),
+                            ));
+                        }
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a register, variable, constant or value as the second operand at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    };
+                    let (Operand::Reg(second_name) | Operand::Imm(second_name)) = &second;
+
+                    // 16-bit addition is DAD, which adds a pair into HL; the ALU is 8-bit only
+                    match (
+                        is_16bit_register(&identifier),
+                        is_16bit_register(second_name),
+                    ) {
+                        (false, false) => {}
+                        (true, true) if operator != BinaryOperator::Add => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Register pairs only support addition (DAD), e.g. HL += DE, at {}",
+                                    position(spans, *i + 1)
+                                ),
+                            ));
+                        }
+                        (true, true) if identifier != "HL" => {
+                            return Err(error_at(
+                                spans,
+                                *i,
+                                format!(
+                                    "16-bit addition always adds into HL (DAD), got {} += {} at {}",
+                                    identifier,
+                                    second_name,
+                                    position(spans, *i)
+                                ),
+                            ));
+                        }
+                        (true, true) => {}
+                        // A 16-bit variable or constant added into HL: HL += wide;
+                        (true, false)
+                            if identifier == "HL"
+                                && operator == BinaryOperator::Add
+                                && !is_8bit_register(second_name) => {}
+                        _ => {
+                            return Err(error_at(
+                                spans,
+                                *i,
+                                format!(
+                                    "Binary operations can't mix 8-bit and 16-bit operands, got {} and {} at {}",
+                                    identifier,
+                                    second_name,
+                                    position(spans, *i)
+                                ),
+                            ));
+                        }
+                    }
+
+                    statements.push(Statement::BinaryOp {
+                        register: identifier,
+                        operator,
+                        second,
+                    });
+                    *i += 3; // Consumed: A, +, C
+                }
+                Some(Token::Shl)
+                | Some(Token::Shr)
+                | Some(Token::ShlCarry)
+                | Some(Token::ShrCarry) => {
+                    // Rotate: A << 1;
+                    let (direction, through_carry) = match tokens.get(*i + 1) {
+                        Some(Token::Shl) => (RotateDirection::Left, false),
+                        Some(Token::Shr) => (RotateDirection::Right, false),
+                        Some(Token::ShlCarry) => (RotateDirection::Left, true),
+                        Some(Token::ShrCarry) => (RotateDirection::Right, true),
+                        _ => unreachable!(),
+                    };
+
+                    match tokens.get(*i + 2).and_then(numeric_literal) {
+                        Some(amount) if hex_value(&amount) == Some(1) => {}
+                        Some(amount) => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "The 8085 rotates by one bit at a time; use a shift amount of 1, got {} at {}.",
+                                    amount,
+                                    position(spans, *i + 2)
+                                ),
+                            ));
+                        }
+                        None => {
+                            return Err(error_at(
+                                spans,
+                                *i + 2,
+                                format!(
+                                    "Expected a shift amount of 1 at {}.",
+                                    describe(tokens, spans, *i + 2)
+                                ),
+                            ));
+                        }
+                    }
+
+                    // Rotates operate on the 8-bit accumulator
+                    if is_16bit_register(&identifier) {
+                        return Err(error_at(
+                            spans,
+                            *i,
+                            format!(
+                                "Rotate requires an 8-bit operand, got {} at {}",
+                                identifier,
+                                position(spans, *i)
+                            ),
+                        ));
+                    }
+
+                    statements.push(Statement::Rotate {
+                        register: identifier,
+                        direction,
+                        through_carry,
+                    });
+                    *i += 3; // Consumed: A, <<, 1
+                }
+                Some(Token::PlusPlus) => {
+                    // Increment: HL++; or A++;
+                    // A++B; lexes as A, ++, B rather than a binary operation
+                    match tokens.get(*i + 2) {
+                        Some(Token::Semicolon) | None => {}
+                        Some(Token::Identifier(second)) => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Malformed '{} ++ {}' at {}. '++' takes no operand (HL++;); did you mean '{} += {}'?",
+                                    identifier,
+                                    second,
+                                    position(spans, *i + 1),
+                                    identifier,
+                                    second
+                                ),
+                            ));
+                        }
+                        Some(token) if numeric_literal(token).is_some() => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Malformed '{} ++ ...' at {}. '++' takes no operand (HL++;).",
+                                    identifier,
+                                    position(spans, *i + 1)
+                                ),
+                            ));
+                        }
+                        Some(token) if operator_text(token).is_some() => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Ambiguous operator sequence '++{}' at {}. Separate the operators, e.g. write '{} += B' for a binary operation.",
+                                    operator_text(token).unwrap(),
+                                    position(spans, *i + 1),
+                                    identifier
+                                ),
+                            ));
+                        }
+                        Some(_) => {}
+                    }
+                    if is_register(&identifier) {
+                        statements.push(Statement::PointerIncDec {
+                            register_pair: identifier,
+                            is_increment: true,
+                        });
+                    } else {
+                        // counter++; is shorthand for counter += 0x01;
+                        statements.push(Statement::BinaryOp {
+                            register: identifier,
+                            operator: BinaryOperator::Add,
+                            second: Operand::Imm("0x01".to_string()),
+                        });
+                    }
+                    *i += 2; // Consumed: HL, ++
+                }
+                Some(Token::MinusMinus) => {
+                    // Decrement: HL--; or C--;
+                    // A--B; lexes as A, --, B rather than a binary operation
+                    match tokens.get(*i + 2) {
+                        Some(Token::Semicolon) | None => {}
+                        Some(Token::Identifier(second)) => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Malformed '{} -- {}' at {}. '--' takes no operand (HL--;); did you mean '{} -= {}'?",
+                                    identifier,
+                                    second,
+                                    position(spans, *i + 1),
+                                    identifier,
+                                    second
+                                ),
+                            ));
+                        }
+                        Some(token) if numeric_literal(token).is_some() => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Malformed '{} -- ...' at {}. '--' takes no operand (HL--;).",
+                                    identifier,
+                                    position(spans, *i + 1)
+                                ),
+                            ));
+                        }
+                        Some(token) if operator_text(token).is_some() => {
+                            return Err(error_at(
+                                spans,
+                                *i + 1,
+                                format!(
+                                    "Ambiguous operator sequence '--{}' at {}. Separate the operators, e.g. write '{} -= B' for a binary operation.",
+                                    operator_text(token).unwrap(),
+                                    position(spans, *i + 1),
+                                    identifier
+                                ),
+                            ));
+                        }
+                        Some(_) => {}
+                    }
+                    if is_register(&identifier) {
+                        statements.push(Statement::PointerIncDec {
+                            register_pair: identifier,
+                            is_increment: false,
+                        });
+                    } else {
+                        // counter--; is shorthand for counter -= 0x01;
+                        statements.push(Statement::BinaryOp {
+                            register: identifier,
+                            operator: BinaryOperator::Sub,
+                            second: Operand::Imm("0x01".to_string()),
+                        });
+                    }
+                    *i += 2; // Consumed: HL, --
+                }
+                Some(
+                    token @ (Token::Greater
+                    | Token::Less
+                    | Token::EqualEqual
+                    | Token::GreaterEqual
+                    | Token::LessEqual
+                    | Token::NotEqual
+                    | Token::AndAnd
+                    | Token::OrOr),
+                ) => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "'{}' is only allowed in an if or while condition, at {}.",
+                            operator_text(token).unwrap(),
+                            position(spans, *i + 1)
+                        ),
+                    ));
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Unexpected token after identifier '{}' at {}.",
+                            identifier,
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            }
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::LBracket) => {
+            // Direct memory write: [0x8000] = A;
+            let address = parse_direct_address(tokens, spans, i)?;
+
+            if tokens.get(*i) != Some(&Token::Equal) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected '=' after '[{}]' at {}.",
+                        address,
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+
+            let register = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name)) => name.clone(),
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected a register name after '=' at {}.",
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
+            validate_direct(&register).map_err(|e| {
+                error_at(
+                    spans,
+                    *i + 1,
+                    format!("{} at {}", e, position(spans, *i + 1)),
+                )
+            })?;
+
+            statements.push(Statement::StoreDirect { address, register });
+            *i += 2; // Consumed: =, A
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Tilde) => {
+            // One's complement of the accumulator: ~A;
+            let register = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name)) => name.clone(),
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected a register after '~' at {}.",
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
+            // CMA has no operand; it always complements A
+            if register != "A" {
+                return Err(error_at(
+                    spans,
+                    *i + 1,
+                    format!(
+                        "'~' only complements the accumulator (CMA), got {} at {}. Copy the value into A first.",
+                        register,
+                        position(spans, *i + 1)
+                    ),
+                ));
+            }
+
+            statements.push(Statement::Complement { register });
+            *i += 2; // Consumed: ~, A
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Star) => {
+            // Pointer store: *HL = A;
+            let register_pair = match tokens.get(*i + 1) {
+                Some(Token::Identifier(pair)) => pair.clone(),
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected a register pair after '*' at {}.",
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
+
+            if tokens.get(*i + 2) != Some(&Token::Equal) {
+                return Err(error_at(
+                    spans,
+                    *i + 2,
+                    format!(
+                        "Expected '=' after '*{}' at {}.",
+                        register_pair,
+                        describe(tokens, spans, *i + 2)
+                    ),
+                ));
+            }
+
+            let register = match tokens.get(*i + 3) {
+                Some(Token::Identifier(name)) => name.clone(),
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 3,
+                        format!(
+                            "Expected a register name after '=' at {}.",
+                            describe(tokens, spans, *i + 3)
+                        ),
+                    ));
+                }
+            };
+            validate_indirect(&register, &register_pair)
+                .map_err(|e| error_at(spans, *i, format!("{} at {}", e, position(spans, *i))))?;
+
+            statements.push(Statement::StoreIndirect {
+                register_pair,
+                register,
+            });
+            *i += 4; // Consumed: *, HL, =, A
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Out) => {
+            // Port output: out(0x02) = A;
+            *i += 1; // Consume "out"
+            let port = parse_port(tokens, spans, i, "out")?;
+
+            if tokens.get(*i) != Some(&Token::Equal) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected '=' after out(...) at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+
+            let register = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name)) if !is_16bit_register(name) => name.clone(),
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected an 8-bit register or variable after '=' at {}.",
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
+
+            statements.push(Statement::PortOut { port, register });
+            *i += 2; // Consumed: =, A
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Const) => {
+            // Constant: const LIMIT = 0x10;
+            let name = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name))
+                    if is_8bit_register(name) || is_16bit_register(name) =>
+                {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "'{}' is a register and can't be used as a constant name at {}.",
+                            name,
+                            position(spans, *i + 1)
+                        ),
+                    ));
+                }
+                Some(Token::Identifier(name)) => name.clone(),
+                Some(token) if keyword_text(token).is_some() => {
+                    return Err(reserved_name(token, "constant name", spans, *i + 1));
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected a constant name after 'const' at {}.",
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
+
+            if tokens.get(*i + 2) != Some(&Token::Equal) {
+                return Err(error_at(
+                    spans,
+                    *i + 2,
+                    format!(
+                        "Expected '=' after constant name at {}.",
+                        describe(tokens, spans, *i + 2)
+                    ),
+                ));
+            }
+
+            let value = match tokens.get(*i + 3) {
+                Some(token) if numeric_literal(token).is_some() => numeric_literal(token).unwrap(),
+                Some(Token::Minus) => {
+                    let value = negative_literal_any_width(tokens.get(*i + 4)).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 3,
+                            format!("{} at {}", e, position(spans, *i + 3)),
+                        )
+                    })?;
+                    *i += 1; // Consume "-"
+                    value
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 3,
+                        format!(
+                            "Expected a numeric value for constant '{}' at {}.{}",
+                            name,
+                            describe(tokens, spans, *i + 3),
+                            token_hex_hint(tokens.get(*i + 3))
+                        ),
+                    ));
+                }
+            };
+            validate_hex(&value, true).map_err(|e| {
+                error_at(
+                    spans,
+                    *i + 3,
+                    format!("{} at {}", e, position(spans, *i + 3)),
+                )
+            })?;
+
+            statements.push(Statement::ConstDef { name, value });
+            *i += 4; // Consumed: const, LIMIT, =, 0x10
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Print) => {
+            // Display a value: print(A);
+            if tokens.get(*i + 1) != Some(&Token::LParen) {
+                return Err(error_at(
+                    spans,
+                    *i + 1,
+                    format!(
+                        "Expected '(' after 'print' at {}.",
+                        describe(tokens, spans, *i + 1)
+                    ),
+                ));
+            }
+
+            let register = match tokens.get(*i + 2) {
+                Some(Token::Identifier(name)) if !is_16bit_register(name) => name.clone(),
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 2,
+                        format!(
+                            "Expected an 8-bit register, variable or constant inside print() at {}.",
+                            describe(tokens, spans, *i + 2)
+                        ),
+                    ));
+                }
+            };
+
+            if tokens.get(*i + 3) != Some(&Token::RParen) {
+                return Err(error_at(
+                    spans,
+                    *i + 3,
+                    format!(
+                        "Expected ')' after print({} at {}.",
+                        register,
+                        describe(tokens, spans, *i + 3)
+                    ),
+                ));
+            }
+
+            statements.push(Statement::Print { register });
+            *i += 4; // Consumed: print, (, A, )
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Break) | Some(Token::Continue) => {
+            // Loop exits: break; or continue;
+            statements.push(if tokens.get(*i) == Some(&Token::Break) {
+                Statement::Break
+            } else {
+                Statement::Continue
+            });
+            *i += 1;
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Return) => {
+            // Exit status: return 0x00;
+            let value = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name)) if is_register(name) => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "'return' takes a literal or constant exit status, which it loads into A, got register {} at {}.",
+                            name,
+                            position(spans, *i + 1)
+                        ),
+                    ));
+                }
+                Some(Token::Identifier(name)) => name.clone(),
+                Some(token) if numeric_literal(token).is_some() => {
+                    let value = numeric_literal(token).unwrap();
+                    validate_hex(&value, false).map_err(|e| {
+                        error_at(
+                            spans,
+                            *i + 1,
+                            format!("Exit status {} at {}", e, position(spans, *i + 1)),
+                        )
+                    })?;
+                    value
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected an 8-bit value or constant after 'return' at {}.{}",
+                            describe(tokens, spans, *i + 1),
+                            token_hex_hint(tokens.get(*i + 1))
+                        ),
+                    ));
+                }
+            };
+
+            statements.push(Statement::Return { value });
+            *i += 2; // Consumed: return, 0x00
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Label) | Some(Token::Goto) => {
+            // Label definition: label start; or jump: goto start;
+            let is_label = tokens.get(*i) == Some(&Token::Label);
+            let keyword = if is_label { "label" } else { "goto" };
+            let name = match tokens.get(*i + 1) {
+                Some(Token::Identifier(name)) => name.clone(),
+                Some(token) if keyword_text(token).is_some() => {
+                    return Err(reserved_name(token, "label name", spans, *i + 1));
+                }
+                _ => {
+                    return Err(error_at(
+                        spans,
+                        *i + 1,
+                        format!(
+                            "Expected a label name after '{}' at {}.",
+                            keyword,
+                            describe(tokens, spans, *i + 1)
+                        ),
+                    ));
+                }
+            };
+
+            statements.push(if is_label {
+                Statement::LabelDef { name }
+            } else {
+                Statement::Goto { name }
+            });
+            *i += 2; // Consumed: label, start
+
+            // Expect semicolon
+            if tokens.get(*i) != Some(&Token::Semicolon) {
+                return Err(error_at(
+                    spans,
+                    *i,
+                    format!(
+                        "Expected ';' at the end of the statement at {}.",
+                        describe(tokens, spans, *i)
+                    ),
+                ));
+            }
+            *i += 1; // Consume ";"
+        }
+        Some(Token::Fn) => {
+            let function = parse_function(tokens, spans, i, lines, errors)?;
+            statements.push(function);
+        }
+        Some(Token::If) => {
+            // If statement: if(A > B) { ... } or if(counter > result) { ... }
+            *i += 1; // Consume "if"
+
+            let guard = parse_condition(tokens, spans, i, "if")?;
+            let body = parse_braced_block(tokens, spans, i, "if", lines, errors)?;
+
+            // Optional else block: else { ... }
+            let else_body = if tokens.get(*i) == Some(&Token::Else) {
+                *i += 1; // Consume "else"
+                Some(parse_braced_block(tokens, spans, i, "else", lines, errors)?)
+            } else {
+                None
+            };
+
+            statements.push(Statement::If {
+                guard,
+                body,
+                else_body,
+            });
+        }
+        Some(Token::While) => {
+            // While loop: while(counter < result) { ... }
+            *i += 1; // Consume "while"
+
+            let guard = parse_condition(tokens, spans, i, "while")?;
+            let body = parse_braced_block(tokens, spans, i, "while", lines, errors)?;
+
+            statements.push(Statement::While { guard, body });
+        }
+        Some(Token::Repeat) => {
+            // Counted loop: repeat(0x0A) { ... }
+            *i += 1; // Consume "repeat"
+
+            let count = parse_count(tokens, spans, i)?;
+            let body = parse_braced_block(tokens, spans, i, "repeat", lines, errors)?;
+
+            statements.push(Statement::Repeat { count, body });
+        }
+        _ => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!("Expected statement at {}.", describe(tokens, spans, *i)),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a parenthesized condition: (left > right), where comparisons may be
+/// joined by && and ||. && binds tighter than ||.
+fn parse_condition(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+    keyword: &str,
+) -> Result<Guard, SourceError> {
+    if tokens.get(*i) != Some(&Token::LParen) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected '(' after '{}' at {}.",
+                keyword,
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "("
+
+    let guard = parse_or(tokens, spans, i)?;
+
+    if tokens.get(*i) != Some(&Token::RParen) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected ')' after condition at {}.",
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume ")"
+
+    Ok(guard)
+}
+
+/// Parse comparisons joined by ||
+fn parse_or(tokens: &[Token], spans: &[Span], i: &mut usize) -> Result<Guard, SourceError> {
+    let mut guard = parse_and(tokens, spans, i)?;
+    while tokens.get(*i) == Some(&Token::OrOr) {
+        *i += 1; // Consume "||"
+        let right = parse_and(tokens, spans, i)?;
+        guard = Guard::Or(Box::new(guard), Box::new(right));
+    }
+    Ok(guard)
+}
+
+/// Parse comparisons joined by &&
+fn parse_and(tokens: &[Token], spans: &[Span], i: &mut usize) -> Result<Guard, SourceError> {
+    let mut guard = parse_comparison(tokens, spans, i)?;
+    while tokens.get(*i) == Some(&Token::AndAnd) {
+        *i += 1; // Consume "&&"
+        let right = parse_comparison(tokens, spans, i)?;
+        guard = Guard::And(Box::new(guard), Box::new(right));
+    }
+    Ok(guard)
+}
+
+/// Parse a single comparison: left > right
+fn parse_comparison(tokens: &[Token], spans: &[Span], i: &mut usize) -> Result<Guard, SourceError> {
+    let left = parse_operand(tokens, spans, i)?;
+
+    let condition = match tokens.get(*i) {
+        Some(Token::Greater) => Condition::Greater,
+        Some(Token::Less) => Condition::Less,
+        Some(Token::EqualEqual) => Condition::Equal,
+        Some(Token::GreaterEqual) => Condition::GreaterEqual,
+        Some(Token::LessEqual) => Condition::LessEqual,
+        Some(Token::NotEqual) => Condition::NotEqual,
+        _ => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected condition: '>', '<', '==', '>=', '<=' or '!=' at {}.",
+                    describe(tokens, spans, *i)
+                ),
+            ));
+        }
+    };
+    *i += 1;
+
+    let right = parse_operand(tokens, spans, i)?;
+
+    Ok(Guard::Compare {
+        left,
+        condition,
+        right,
+    })
+}
+
+/// Parse a value of a conditional assignment: a literal that fits `dest`, or
+/// a constant, which is checked once substituted
+fn parse_conditional_value(
+    tokens: &[Token],
+    spans: &[Span],
+    i: usize,
+    dest: &str,
+) -> Result<String, SourceError> {
+    match tokens.get(i) {
+        Some(Token::Identifier(name)) if is_register(name) => Err(error_at(
+            spans,
+            i,
+            format!(
+                "The values of a conditional assignment are loaded with MVI or LXI, so they must be literals or constants, got register {} at {}.",
+                name,
+                position(spans, i)
+            ),
+        )),
+        Some(Token::Identifier(name)) => Ok(name.clone()),
+        Some(token) if numeric_literal(token).is_some() => {
+            let value = numeric_literal(token).unwrap();
+            validate_hex(&value, is_16bit_register(dest)).map_err(|e| {
+                error_at(
+                    spans,
+                    i,
+                    format!("{} for {} at {}", e, dest, position(spans, i)),
+                )
+            })?;
+            Ok(value)
+        }
+        _ => Err(error_at(
+            spans,
+            i,
+            format!(
+                "Expected a value or constant in the conditional assignment at {}.{}",
+                describe(tokens, spans, i),
+                token_hex_hint(tokens.get(i))
+            ),
+        )),
+    }
+}
+
+/// Parse one side of a comparison: a name or a literal (above 0xFF for a
+/// 16-bit comparison)
+fn parse_operand(tokens: &[Token], spans: &[Span], i: &mut usize) -> Result<Operand, SourceError> {
+    let operand = match tokens.get(*i) {
+        Some(Token::Identifier(name)) => Operand::Reg(name.clone()),
+        Some(token) if numeric_literal(token).is_some() => {
+            let value = numeric_literal(token).unwrap();
+            validate_hex(&value, true).map_err(|e| {
+                error_at(
+                    spans,
+                    *i,
+                    format!("Comparison {} at {}", e, position(spans, *i)),
+                )
+            })?;
+            Operand::Imm(value)
+        }
+        _ => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected a register, variable, constant or value in condition at {}.",
+                    describe(tokens, spans, *i)
+                ),
+            ));
+        }
+    };
+    *i += 1;
+    Ok(operand)
+}
+
+/// Parse a bracketed array index: [B]
+fn parse_index(tokens: &[Token], spans: &[Span], i: &mut usize) -> Result<String, SourceError> {
+    if tokens.get(*i) != Some(&Token::LBracket) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!("Expected '[' at {}.", describe(tokens, spans, *i)),
+        ));
+    }
+    *i += 1; // Consume "["
+
+    let index = match tokens.get(*i) {
+        Some(Token::Identifier(name)) if !is_16bit_register(name) => name.clone(),
+        _ => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected an 8-bit register, variable or constant as the array index at {}.",
+                    describe(tokens, spans, *i)
+                ),
+            ));
+        }
+    };
+    *i += 1;
+
+    if tokens.get(*i) != Some(&Token::RBracket) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected ']' after array index at {}.",
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "]"
+
+    Ok(index)
+}
+
+/// Parse a bracketed 16-bit address: [0x8000], or [NAME] for a constant
+fn parse_direct_address(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+) -> Result<String, SourceError> {
+    *i += 1; // Consume "["
+
+    let address = match tokens.get(*i) {
+        // A constant, resolved once all constants are known
+        Some(Token::Identifier(name)) => name.clone(),
+        Some(token) if numeric_literal(token).is_some() => {
+            let address = numeric_literal(token).unwrap();
+            validate_hex(&address, true).map_err(|e| {
+                error_at(
+                    spans,
+                    *i,
+                    format!("Address {} at {}", e, position(spans, *i)),
+                )
+            })?;
+            address
+        }
+        _ => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected an address or constant inside '[...]' at {}.",
+                    describe(tokens, spans, *i)
+                ),
+            ));
+        }
+    };
+    *i += 1;
+
+    if tokens.get(*i) != Some(&Token::RBracket) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected ']' after address at {}.",
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "]"
+
+    Ok(address)
+}
+
+/// Direct memory access moves A (LDA/STA) or HL (LHLD/SHLD)
+fn validate_direct(register: &str) -> Result<(), String> {
+    match register {
+        "A" | "HL" => Ok(()),
+        _ => Err(format!(
+            "Direct memory access needs A (LDA/STA) or HL (LHLD/SHLD), got {}",
+            register
+        )),
+    }
+}
+
+/// Parse a parenthesized 8-bit port number: (0x01)
+fn parse_port(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+    keyword: &str,
+) -> Result<String, SourceError> {
+    if tokens.get(*i) != Some(&Token::LParen) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected '(' after '{}' at {}.",
+                keyword,
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "("
+
+    let port = match tokens.get(*i).and_then(numeric_literal) {
+        Some(port) => port,
+        None => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected a port number inside {}() at {}.{}",
+                    keyword,
+                    describe(tokens, spans, *i),
+                    token_hex_hint(tokens.get(*i))
+                ),
+            ));
+        }
+    };
+    validate_hex(&port, false)
+        .map_err(|e| error_at(spans, *i, format!("Port {} at {}", e, position(spans, *i))))?;
+    *i += 1;
+
+    if tokens.get(*i) != Some(&Token::RParen) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected ')' after port number at {}.",
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume ")"
+
+    Ok(port)
+}
+
+/// Parse a parenthesized repeat count from 1 to 255: (0x0A)
+fn parse_count(tokens: &[Token], spans: &[Span], i: &mut usize) -> Result<String, SourceError> {
+    if tokens.get(*i) != Some(&Token::LParen) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected '(' after 'repeat' at {}.",
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "("
+
+    let count = match tokens.get(*i).and_then(numeric_literal) {
+        Some(count) => count,
+        None => {
+            return Err(error_at(
+                spans,
+                *i,
+                format!(
+                    "Expected a numeric count inside repeat() at {}.{}",
+                    describe(tokens, spans, *i),
+                    token_hex_hint(tokens.get(*i))
+                ),
+            ));
+        }
+    };
+    validate_hex(&count, false).map_err(|e| {
+        error_at(
+            spans,
+            *i,
+            format!("Repeat count {} at {}", e, position(spans, *i)),
+        )
+    })?;
+    // The counter is decremented before it's tested, so 0 would mean 256
+    if hex_value(&count) == Some(0) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "repeat() needs a count of at least 1 at {}.",
+                position(spans, *i)
+            ),
+        ));
+    }
+    *i += 1;
+
+    if tokens.get(*i) != Some(&Token::RParen) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected ')' after repeat count at {}.",
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume ")"
+
+    Ok(count)
+}
+
+/// Parse a brace-delimited body: { ... }
+fn parse_braced_block(
+    tokens: &[Token],
+    spans: &[Span],
+    i: &mut usize,
+    keyword: &str,
+    lines: &mut Vec<usize>,
+    errors: &mut Vec<SourceError>,
+) -> Result<Vec<Statement>, SourceError> {
+    if tokens.get(*i) != Some(&Token::LBrace) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected '{{' after '{}' at {}.",
+                keyword,
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "{"
+
+    let mut body = Vec::new();
+    parse_block(tokens, spans, i, &mut body, &mut Vec::new(), lines, errors);
+
+    if tokens.get(*i) != Some(&Token::RBrace) {
+        return Err(error_at(
+            spans,
+            *i,
+            format!(
+                "Expected '}}' to close {} block at {}.",
+                keyword,
+                describe(tokens, spans, *i)
+            ),
+        ));
+    }
+    *i += 1; // Consume "}"
+
+    Ok(body)
+}
@@ -0,0 +1,109 @@
+// src/peephole.rs
+
+use crate::asm::{Address, AsmInstruction};
+use std::ops::Range;
+
+/// Removes moves that can't change any register, memory or flag:
+/// - `MOV X,X`
+/// - `MOV Y,X` (or a repeated `MOV X,Y`) right after `MOV X,Y`
+/// - `LDA a` after `STA a`, and `LHLD a` after `SHLD a`, with only moves
+///   into other registers in between, when `a` is a data label or in
+///   `data_region`; any other address may be a memory-mapped device
+/// - `MVI r,v` or `LXI rp,v` when the register already holds `v` from the
+///   same load, with only stores and moves out of it in between, as in the
+///   code for `a = b = 0x05;`
+///
+/// Comments and source markers are skipped over, but a label ends the run:
+/// code jumping to it may arrive with different values in the registers.
+pub fn optimize(instructions: Vec<AsmInstruction>, data_region: Range<u16>) -> Vec<AsmInstruction> {
+    let mut optimized: Vec<AsmInstruction> = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        let previous = optimized.iter().rev().find(|earlier| {
+            !matches!(
+                earlier,
+                AsmInstruction::Comment(_) | AsmInstruction::Source(_)
+            )
+        });
+        if is_redundant(&instruction, previous)
+            || reloads_stored_value(&instruction, &optimized, &data_region)
+            || reloads_same_value(&instruction, &optimized)
+        {
+            continue;
+        }
+        optimized.push(instruction);
+    }
+    optimized
+}
+
+fn is_redundant(instruction: &AsmInstruction, previous: Option<&AsmInstruction>) -> bool {
+    match (previous, instruction) {
+        (_, AsmInstruction::Mov { dest, src }) if dest == src => true,
+        // Both registers already hold the same value
+        (
+            Some(AsmInstruction::Mov {
+                dest: previous_dest,
+                src: previous_src,
+            }),
+            AsmInstruction::Mov { dest, src },
+        ) => {
+            (dest == previous_dest && src == previous_src)
+                || (dest == previous_src && src == previous_dest)
+        }
+        _ => false,
+    }
+}
+
+/// The value just stored is still in A or HL
+fn reloads_stored_value(
+    instruction: &AsmInstruction,
+    earlier: &[AsmInstruction],
+    data_region: &Range<u16>,
+) -> bool {
+    let (registers, loaded): (&[&str], &Address) = match instruction {
+        AsmInstruction::Lda(address) => (&["A"], address),
+        AsmInstruction::Lhld(address) => (&["H", "L"], address),
+        _ => return false,
+    };
+    if let Address::Fixed(address) = loaded
+        && !data_region.contains(address)
+    {
+        return false;
+    }
+    for previous in earlier.iter().rev() {
+        match previous {
+            AsmInstruction::Sta(stored) if registers == ["A"] => return stored == loaded,
+            AsmInstruction::Shld(stored) if registers == ["H", "L"] => return stored == loaded,
+            AsmInstruction::Comment(_) | AsmInstruction::Source(_) => {}
+            // MOV M,r writes memory, which may be the stored byte
+            AsmInstruction::Mov { dest, .. }
+                if dest != "M" && !registers.contains(&dest.as_str()) => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
+fn reloads_same_value(instruction: &AsmInstruction, earlier: &[AsmInstruction]) -> bool {
+    let registers: &[&str] = match instruction {
+        AsmInstruction::Mvi { dest, .. } => &[dest.as_str()],
+        AsmInstruction::Lxi { pair, .. } => match pair.as_str() {
+            "H" | "HL" => &["H", "L"],
+            "D" | "DE" => &["D", "E"],
+            "B" | "BC" => &["B", "C"],
+            _ => return false,
+        },
+        _ => return false,
+    };
+    for previous in earlier.iter().rev() {
+        match previous {
+            _ if previous == instruction => return true,
+            AsmInstruction::Comment(_)
+            | AsmInstruction::Source(_)
+            | AsmInstruction::Sta(_)
+            | AsmInstruction::Shld(_) => {}
+            AsmInstruction::Mov { dest, .. } if !registers.contains(&dest.as_str()) => {}
+            _ => return false,
+        }
+    }
+    false
+}
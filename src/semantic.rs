@@ -0,0 +1,1216 @@
+// src/semantic.rs
+
+use crate::codegen::guard_text;
+use crate::parser::{
+    Condition, Guard, Operand, Statement, bare_hex_hint, collect_constants, hex_value,
+    is_8bit_register,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Checks if a name is one of the fixed 8085 registers or register pairs
+fn is_register(name: &str) -> bool {
+    matches!(
+        name,
+        "A" | "B" | "C" | "D" | "E" | "H" | "L" | "HL" | "BC" | "DE" | "SP"
+    )
+}
+
+/// Validates the AST before code generation: every name used as an operand
+/// must be a register, a constant or a variable assigned earlier in the
+/// program, and constants are never written. Every call must also target a
+/// function defined exactly once, and every goto a label defined exactly once.
+/// No statement may follow a goto unless a label makes it reachable again,
+/// and break and continue must be inside a loop.
+pub fn check(statements: &[Statement]) -> Result<(), String> {
+    let mut functions = HashSet::new();
+    collect_functions(statements, &mut functions)?;
+
+    let mut labels = HashSet::new();
+    collect_labels(statements, &functions, &mut labels)?;
+    check_gotos(statements, &labels)?;
+    check_reachable(statements)?;
+    check_loop_exits(statements, false)?;
+    check_returns(statements, false)?;
+    check_pins(statements, &mut Vec::new())?;
+
+    let mut constants = HashMap::new();
+    collect_constants(statements, &mut constants)?;
+
+    let mut scope = Scope {
+        constants,
+        declared: HashSet::new(),
+        arrays: HashSet::new(),
+    };
+    check_block(statements, &functions, &mut scope)
+}
+
+/// Rejects a variable pinned to two registers, or two variables pinned to
+/// the same register
+fn check_pins(statements: &[Statement], pins: &mut Vec<(String, String)>) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::RegisterPin { variable, register } => {
+                for (pinned, pinned_register) in pins.iter() {
+                    if pinned == variable && pinned_register != register {
+                        return Err(format!(
+                            "'{}' is pinned to both {} and {}.",
+                            variable, pinned_register, register
+                        ));
+                    }
+                    if pinned != variable && pinned_register == register {
+                        return Err(format!(
+                            "'{}' and '{}' are both pinned to {}; a register can back only one variable.",
+                            pinned, variable, register
+                        ));
+                    }
+                }
+                pins.push((variable.clone(), register.clone()));
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                check_pins(body, pins)?;
+                if let Some(else_body) = else_body {
+                    check_pins(else_body, pins)?;
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                check_pins(body, pins)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The names visible at a point in the program
+struct Scope {
+    constants: HashMap<String, String>,
+    declared: HashSet<String>,
+    arrays: HashSet<String>,
+}
+
+/// Collects function names (calls may come before the definition), rejecting duplicates
+fn collect_functions(
+    statements: &[Statement],
+    functions: &mut HashSet<String>,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::FunctionDef { name, body } => {
+                if !functions.insert(name.clone()) {
+                    return Err(format!("Function '{}' is defined more than once.", name));
+                }
+                collect_functions(body, functions)?;
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_functions(body, functions)?;
+                if let Some(else_body) = else_body {
+                    collect_functions(else_body, functions)?;
+                }
+            }
+            Statement::While { body, .. } | Statement::Repeat { body, .. } => {
+                collect_functions(body, functions)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Collects label names (gotos may jump forward), rejecting duplicates and
+/// names that would clash with other labels in the assembly
+fn collect_labels(
+    statements: &[Statement],
+    functions: &HashSet<String>,
+    labels: &mut HashSet<String>,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::LabelDef { name } => {
+                if functions.contains(name) {
+                    return Err(format!("Label '{}' has the same name as a function.", name));
+                }
+                if !labels.insert(name.clone()) {
+                    return Err(format!("Label '{}' is defined more than once.", name));
+                }
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_labels(body, functions, labels)?;
+                if let Some(else_body) = else_body {
+                    collect_labels(else_body, functions, labels)?;
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                collect_labels(body, functions, labels)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_gotos(statements: &[Statement], labels: &HashSet<String>) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::Goto { name } if !labels.contains(name) => {
+                return Err(format!("goto targets undefined label '{}'.", name));
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                check_gotos(body, labels)?;
+                if let Some(else_body) = else_body {
+                    check_gotos(else_body, labels)?;
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                check_gotos(body, labels)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Rejects break and continue outside a loop. A function body starts outside
+/// any loop, even when it is defined inside one.
+fn check_loop_exits(statements: &[Statement], in_loop: bool) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::Break if !in_loop => {
+                return Err("'break' is only allowed inside a while or repeat loop.".to_string());
+            }
+            Statement::Continue if !in_loop => {
+                return Err("'continue' is only allowed inside a while or repeat loop.".to_string());
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                check_loop_exits(body, in_loop)?;
+                if let Some(else_body) = else_body {
+                    check_loop_exits(else_body, in_loop)?;
+                }
+            }
+            Statement::While { body, .. } | Statement::Repeat { body, .. } => {
+                check_loop_exits(body, true)?
+            }
+            Statement::FunctionDef { body, .. } => check_loop_exits(body, false)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Rejects return inside a function: it halts the whole program rather than
+/// going back to the caller, which a function does at its end.
+fn check_returns(statements: &[Statement], in_function: bool) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::Return { value } if in_function => {
+                return Err(format!(
+                    "'return {}' is only allowed in main, where it halts the program. A function returns to its caller at the end of its body.",
+                    value
+                ));
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                check_returns(body, in_function)?;
+                if let Some(else_body) = else_body {
+                    check_returns(else_body, in_function)?;
+                }
+            }
+            Statement::While { body, .. } | Statement::Repeat { body, .. } => {
+                check_returns(body, in_function)?
+            }
+            Statement::FunctionDef { body, .. } => check_returns(body, true)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Rejects code after an unconditional jump in the same block. Code only
+/// becomes reachable again at a label; declarations emit no code here.
+fn check_reachable(statements: &[Statement]) -> Result<(), String> {
+    let mut after = None;
+    for statement in statements {
+        match statement {
+            Statement::LabelDef { .. } => after = None,
+            Statement::ConstDef { .. }
+            | Statement::RegisterPin { .. }
+            | Statement::ArrayDecl { .. }
+            | Statement::DataBytes { .. }
+            | Statement::FunctionDef { .. }
+            | Statement::AssertFits { .. } => {}
+            _ => {
+                if let Some(jump) = after {
+                    return Err(format!(
+                        "Unreachable code after {}. Remove it, or add a label before it to jump to.",
+                        jump
+                    ));
+                }
+            }
+        }
+
+        match statement {
+            Statement::If {
+                body, else_body, ..
+            } => {
+                check_reachable(body)?;
+                if let Some(else_body) = else_body {
+                    check_reachable(else_body)?;
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                check_reachable(body)?;
+            }
+            _ => {}
+        }
+
+        if after.is_none() {
+            after = terminator(statement);
+        }
+    }
+    Ok(())
+}
+
+/// Describes how a statement unconditionally leaves its block, if it does:
+/// a goto, break, continue, return or jump_hl(), or an if whose branches both
+/// end that way
+fn terminator(statement: &Statement) -> Option<String> {
+    match statement {
+        Statement::Goto { name } => Some(format!("'goto {}'", name)),
+        Statement::Break => Some("'break'".to_string()),
+        Statement::Continue => Some("'continue'".to_string()),
+        Statement::JumpHl => Some("'jump_hl()'".to_string()),
+        Statement::Return { value } => Some(format!("'return {}'", value)),
+        Statement::If {
+            body,
+            else_body: Some(else_body),
+            ..
+        } if block_terminates(body) && block_terminates(else_body) => {
+            Some("an if whose branches both jump away".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Whether the end of a block can't be reached by falling through it
+fn block_terminates(statements: &[Statement]) -> bool {
+    let mut terminated = false;
+    for statement in statements {
+        if matches!(statement, Statement::LabelDef { .. }) {
+            terminated = false;
+        } else if terminator(statement).is_some() {
+            terminated = true;
+        }
+    }
+    terminated
+}
+
+fn check_block(
+    statements: &[Statement],
+    functions: &HashSet<String>,
+    scope: &mut Scope,
+) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::StaticAssignment { variable, .. } => {
+                check_target(variable, scope)?;
+                scope.declared.insert(variable.clone());
+            }
+            Statement::BinaryOp {
+                register,
+                second: Operand::Reg(second),
+                ..
+            } if register == "HL" => {
+                // 16-bit addition, so any constant fits
+                check_name(second, scope)?;
+            }
+            Statement::BinaryOp {
+                register, second, ..
+            } => {
+                check_target(register, scope)?;
+                check_operand(register, scope)?;
+                if let Operand::Reg(second) = second {
+                    check_operand(second, scope)?;
+                }
+            }
+            // An array or data table loads its address
+            Statement::LoadPair { variable, .. } if scope.arrays.contains(variable) => {}
+            Statement::LoadPair { variable, .. } => {
+                check_name(variable, scope)?;
+            }
+            Statement::Rotate { register, .. } => {
+                check_target(register, scope)?;
+                check_operand(register, scope)?;
+            }
+            Statement::PortOut { register, .. } | Statement::Print { register } => {
+                check_operand(register, scope)?;
+            }
+            Statement::PortIn { register, .. } => {
+                check_target(register, scope)?;
+            }
+            Statement::ArrayDecl { name, .. } | Statement::DataBytes { label: name, .. } => {
+                if scope.arrays.contains(name) || scope.declared.contains(name) {
+                    return Err(format!("'{}' is already declared.", name));
+                }
+                check_target(name, scope)?;
+                scope.arrays.insert(name.clone());
+            }
+            Statement::ArrayLoad {
+                register,
+                array,
+                index,
+            } => {
+                check_array(array, scope)?;
+                check_operand(index, scope)?;
+                check_target(register, scope)?;
+            }
+            Statement::ArrayStore {
+                array,
+                index,
+                register,
+            } => {
+                check_array(array, scope)?;
+                check_operand(index, scope)?;
+                check_operand(register, scope)?;
+                // The element's address is built in HL
+                if register == "H" || register == "L" {
+                    return Err(format!(
+                        "Can't store {} into {}[{}]: HL holds the element's address.",
+                        register, array, index
+                    ));
+                }
+            }
+            Statement::If {
+                guard,
+                body,
+                else_body,
+            } => {
+                check_guard(guard, scope)?;
+                check_block(body, functions, scope)?;
+                if let Some(else_body) = else_body {
+                    check_block(else_body, functions, scope)?;
+                }
+            }
+            Statement::ConditionalAssign {
+                condition_left,
+                condition,
+                condition_right,
+                ..
+            } => {
+                check_guard(
+                    &comparison(condition_left, condition, condition_right),
+                    scope,
+                )?;
+            }
+            Statement::ComparisonAssign {
+                variable,
+                condition_left,
+                condition,
+                condition_right,
+            } => {
+                check_guard(
+                    &comparison(condition_left, condition, condition_right),
+                    scope,
+                )?;
+                check_target(variable, scope)?;
+                scope.declared.insert(variable.clone());
+            }
+            Statement::While { guard, body } => {
+                check_guard(guard, scope)?;
+                check_block(body, functions, scope)?;
+            }
+            Statement::Repeat { body, .. } => {
+                check_block(body, functions, scope)?;
+            }
+            Statement::FunctionDef { body, .. } => {
+                check_block(body, functions, scope)?;
+            }
+            Statement::Call { name } if !functions.contains(name) => {
+                return Err(format!("Call to undefined function '{}'.", name));
+            }
+            Statement::AssertFits { name, bound } => {
+                check_name(name, scope)?;
+                // A constant's value is always known; variables are checked while folding
+                if let Some(value) = scope.constants.get(name)
+                    && hex_value(value) > hex_value(bound)
+                {
+                    return Err(format!(
+                        "assert_fits({}, {}) failed: constant '{}' is {}.",
+                        name, bound, name, value
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn check_guard(guard: &Guard, scope: &Scope) -> Result<(), String> {
+    check_operands(guard, scope)?;
+
+    // Each comparison is done in A, so a comparison between two other
+    // operands overwrites A for every comparison after it
+    let mut comparisons = Vec::new();
+    flatten_guard(guard, &mut comparisons);
+    let mut clobbered = false;
+    let accumulator = Operand::Reg("A".to_string());
+    for (left, right) in comparisons {
+        let uses_accumulator = *left == accumulator || *right == accumulator;
+        if uses_accumulator && clobbered {
+            return Err("Register A can't be compared after an earlier comparison in the same condition has loaded another value into A.".to_string());
+        }
+        clobbered |= !uses_accumulator;
+    }
+    Ok(())
+}
+
+/// The guard of a conditional assignment, to check it like an if's
+fn comparison(left: &Operand, condition: &Condition, right: &Operand) -> Guard {
+    Guard::Compare {
+        left: left.clone(),
+        condition: condition.clone(),
+        right: right.clone(),
+    }
+}
+
+/// The operands of each comparison in the order they are evaluated
+fn flatten_guard<'a>(guard: &'a Guard, comparisons: &mut Vec<(&'a Operand, &'a Operand)>) {
+    match guard {
+        Guard::Compare { left, right, .. } => comparisons.push((left, right)),
+        Guard::And(first, second) | Guard::Or(first, second) => {
+            flatten_guard(first, comparisons);
+            flatten_guard(second, comparisons);
+        }
+    }
+}
+
+fn check_operands(guard: &Guard, scope: &Scope) -> Result<(), String> {
+    match guard {
+        Guard::Compare { left, right, .. } => {
+            // Wide constants are allowed here; they make a 16-bit comparison
+            for operand in [left, right] {
+                if let Operand::Reg(name) = operand {
+                    check_name(name, scope)?;
+                }
+            }
+            Ok(())
+        }
+        Guard::And(first, second) | Guard::Or(first, second) => {
+            check_operands(first, scope)?;
+            check_operands(second, scope)
+        }
+    }
+}
+
+/// Checks an 8-bit operand
+fn check_operand(name: &str, scope: &Scope) -> Result<(), String> {
+    match scope.constants.get(name) {
+        // Constants become immediates of 8-bit instructions
+        Some(value) if hex_value(value).unwrap_or(0) > 0xFF => Err(format!(
+            "Constant '{}' ({}) doesn't fit in an 8-bit operand.",
+            name, value
+        )),
+        _ => check_name(name, scope),
+    }
+}
+
+/// Checks that a name read as a value refers to something that exists
+fn check_name(name: &str, scope: &Scope) -> Result<(), String> {
+    if scope.arrays.contains(name) {
+        return Err(format!(
+            "Array '{}' can't be used as a value. Index it with {}[...] instead.",
+            name, name
+        ));
+    }
+    if scope.constants.contains_key(name) || is_register(name) || scope.declared.contains(name) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Use of undeclared variable '{}'. Assign it a value before using it.{}",
+            name,
+            bare_hex_hint(name)
+        ))
+    }
+}
+
+/// Rejects writes to a constant or to a whole array
+fn check_target(name: &str, scope: &Scope) -> Result<(), String> {
+    if scope.constants.contains_key(name) {
+        Err(format!("Cannot assign to constant '{}'.", name))
+    } else if scope.arrays.contains(name) {
+        Err(format!(
+            "Cannot assign to array '{}'. Assign its elements with {}[...] = ... instead.",
+            name, name
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_array(name: &str, scope: &Scope) -> Result<(), String> {
+    if scope.arrays.contains(name) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Use of undeclared array '{}'. Declare it with {}[SIZE]; before indexing it.",
+            name, name
+        ))
+    }
+}
+
+/// Static variables that are assigned but never read, in declaration order.
+/// Each one still takes a data address and possibly one of the five registers.
+pub fn unused_variables(statements: &[Statement]) -> Vec<String> {
+    let mut assigned = Vec::new();
+    let mut used = HashSet::new();
+    collect_uses(statements, &mut assigned, &mut used);
+    assigned.retain(|name| !used.contains(name));
+    assigned
+}
+
+fn collect_uses(statements: &[Statement], assigned: &mut Vec<String>, used: &mut HashSet<String>) {
+    for statement in statements {
+        match statement {
+            Statement::StaticAssignment { variable, .. } if !assigned.contains(variable) => {
+                assigned.push(variable.clone());
+            }
+            Statement::BinaryOp {
+                register, second, ..
+            } => {
+                used.insert(register.clone());
+                if let Operand::Reg(second) = second {
+                    used.insert(second.clone());
+                }
+            }
+            Statement::Rotate { register, .. }
+            | Statement::PortOut { register, .. }
+            | Statement::Print { register } => {
+                used.insert(register.clone());
+            }
+            Statement::ArrayLoad { index, .. } => {
+                used.insert(index.clone());
+            }
+            Statement::LoadPair { variable, .. } => {
+                used.insert(variable.clone());
+            }
+            Statement::ArrayStore {
+                index, register, ..
+            } => {
+                used.insert(index.clone());
+                used.insert(register.clone());
+            }
+            Statement::If {
+                guard,
+                body,
+                else_body,
+            } => {
+                collect_guard_uses(guard, used);
+                collect_uses(body, assigned, used);
+                if let Some(else_body) = else_body {
+                    collect_uses(else_body, assigned, used);
+                }
+            }
+            Statement::ConditionalAssign {
+                condition_left,
+                condition,
+                condition_right,
+                ..
+            } => {
+                collect_guard_uses(
+                    &comparison(condition_left, condition, condition_right),
+                    used,
+                );
+            }
+            Statement::ComparisonAssign {
+                variable,
+                condition_left,
+                condition,
+                condition_right,
+            } => {
+                collect_guard_uses(
+                    &comparison(condition_left, condition, condition_right),
+                    used,
+                );
+                if !assigned.contains(variable) {
+                    assigned.push(variable.clone());
+                }
+            }
+            Statement::While { guard, body } => {
+                collect_guard_uses(guard, used);
+                collect_uses(body, assigned, used);
+            }
+            Statement::Repeat { body, .. } | Statement::FunctionDef { body, .. } => {
+                collect_uses(body, assigned, used);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Finds variables that a value above 0xFF widens to 16 bits but that are
+/// also used as 8-bit operands, where only their low byte is seen. Returns one
+/// message per variable.
+pub fn width_conflicts(statements: &[Statement]) -> Vec<String> {
+    let mut wide = Vec::new();
+    let mut narrow = HashSet::new();
+    collect_widths(statements, &mut wide, &mut narrow);
+    wide.into_iter()
+        .filter(|(name, _)| narrow.contains(name))
+        .map(|(name, value)| format!("Variable '{}' is widened to 16 bits by the value {} but is also used as an 8-bit operand, which only sees its low byte.", name, value))
+        .collect()
+}
+
+/// Collects the first 16-bit value of each variable, and the names used
+/// where an 8-bit value is expected
+fn collect_widths(
+    statements: &[Statement],
+    wide: &mut Vec<(String, String)>,
+    narrow: &mut HashSet<String>,
+) {
+    for statement in statements {
+        match statement {
+            Statement::StaticAssignment {
+                variable,
+                value,
+                is_16bit: true,
+            } if !wide.iter().any(|(name, _)| name == variable) => {
+                wide.push((variable.clone(), value.clone()));
+            }
+            // HL + total; is a 16-bit addition
+            Statement::BinaryOp { register, .. } if register == "HL" => {}
+            Statement::BinaryOp {
+                register, second, ..
+            } => {
+                narrow.insert(register.clone());
+                if let Operand::Reg(second) = second {
+                    narrow.insert(second.clone());
+                }
+            }
+            Statement::Rotate { register, .. }
+            | Statement::PortOut { register, .. }
+            | Statement::Print { register } => {
+                narrow.insert(register.clone());
+            }
+            Statement::ArrayLoad {
+                register, index, ..
+            }
+            | Statement::ArrayStore {
+                register, index, ..
+            } => {
+                narrow.insert(register.clone());
+                narrow.insert(index.clone());
+            }
+            Statement::If {
+                guard,
+                body,
+                else_body,
+            } => {
+                collect_guard_widths(guard, narrow);
+                collect_widths(body, wide, narrow);
+                if let Some(else_body) = else_body {
+                    collect_widths(else_body, wide, narrow);
+                }
+            }
+            Statement::ConditionalAssign {
+                condition_left,
+                condition,
+                condition_right,
+                ..
+            }
+            | Statement::ComparisonAssign {
+                condition_left,
+                condition,
+                condition_right,
+                ..
+            } => {
+                collect_guard_widths(
+                    &comparison(condition_left, condition, condition_right),
+                    narrow,
+                );
+            }
+            Statement::While { guard, body } => {
+                collect_guard_widths(guard, narrow);
+                collect_widths(body, wide, narrow);
+            }
+            Statement::Repeat { body, .. } | Statement::FunctionDef { body, .. } => {
+                collect_widths(body, wide, narrow);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A variable compared with an 8-bit register is used as an 8-bit value
+fn collect_guard_widths(guard: &Guard, narrow: &mut HashSet<String>) {
+    let mut comparisons = Vec::new();
+    flatten_guard(guard, &mut comparisons);
+    for (left, right) in comparisons {
+        for (operand, other) in [(left, right), (right, left)] {
+            if let (Operand::Reg(name), Operand::Reg(other)) = (operand, other)
+                && is_8bit_register(other)
+            {
+                narrow.insert(name.clone());
+            }
+        }
+    }
+}
+
+/// Finds while loops whose condition is known at compile time: loops that
+/// never run their body, and loops that never end because the condition is
+/// always true and the body writes none of its operands. Returns one message
+/// per loop.
+pub fn constant_loops(statements: &[Statement]) -> Vec<String> {
+    let mut constants = HashMap::new();
+    // Duplicate constants have already been rejected by the parser
+    let _ = collect_constants(statements, &mut constants);
+    let mut warnings = Vec::new();
+    collect_constant_loops(statements, statements, &constants, &mut warnings);
+    warnings
+}
+
+fn collect_constant_loops(
+    statements: &[Statement],
+    program: &[Statement],
+    constants: &HashMap<String, String>,
+    warnings: &mut Vec<String>,
+) {
+    for statement in statements {
+        match statement {
+            Statement::While { guard, body } => {
+                match guard_value(guard, constants) {
+                    Some(false) => warnings.push(format!("The condition of 'while({})' is always false, so the loop body never runs.", guard_text(guard))),
+                    Some(true) if !writes_operand(guard, body, program) && !breaks_out(body) => {
+                        warnings.push(format!("The condition of 'while({})' is always true and the loop body never changes it, so the loop never ends.", guard_text(guard)));
+                    }
+                    _ => {}
+                }
+                collect_constant_loops(body, program, constants, warnings);
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_constant_loops(body, program, constants, warnings);
+                if let Some(else_body) = else_body {
+                    collect_constant_loops(else_body, program, constants, warnings);
+                }
+            }
+            Statement::Repeat { body, .. } | Statement::FunctionDef { body, .. } => {
+                collect_constant_loops(body, program, constants, warnings);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether a loop body has a break for this loop, rather than for a loop
+/// nested in it, or a return, which ends the program
+fn breaks_out(body: &[Statement]) -> bool {
+    body.iter().any(|statement| match statement {
+        Statement::Break | Statement::Return { .. } => true,
+        Statement::If {
+            body, else_body, ..
+        } => breaks_out(body) || else_body.as_deref().is_some_and(breaks_out),
+        _ => false,
+    })
+}
+
+/// The value of a condition, when it doesn't depend on anything that runs:
+/// two literals or constants, or a name compared with itself
+fn guard_value(guard: &Guard, constants: &HashMap<String, String>) -> Option<bool> {
+    match guard {
+        Guard::Compare {
+            left,
+            condition,
+            right,
+        } => {
+            let value = |operand: &Operand| match operand {
+                Operand::Imm(value) => hex_value(value),
+                Operand::Reg(name) => constants.get(name).and_then(|value| hex_value(value)),
+            };
+            let (left, right) = match (left, right) {
+                (Operand::Reg(left), Operand::Reg(right)) if left == right => (0, 0),
+                _ => (value(left)?, value(right)?),
+            };
+            Some(match condition {
+                Condition::Greater => left > right,
+                Condition::Less => left < right,
+                Condition::Equal => left == right,
+                Condition::GreaterEqual => left >= right,
+                Condition::LessEqual => left <= right,
+                Condition::NotEqual => left != right,
+            })
+        }
+        Guard::And(first, second) => match (
+            guard_value(first, constants),
+            guard_value(second, constants),
+        ) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+        Guard::Or(first, second) => match (
+            guard_value(first, constants),
+            guard_value(second, constants),
+        ) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+    }
+}
+
+/// Whether a loop body, or a function it calls, may write a register or
+/// variable its condition reads
+fn writes_operand(guard: &Guard, body: &[Statement], program: &[Statement]) -> bool {
+    let mut comparisons = Vec::new();
+    flatten_guard(guard, &mut comparisons);
+    let (_, written) = block_effects(body, program);
+    comparisons
+        .into_iter()
+        .flat_map(|(left, right)| [left, right])
+        .any(|operand| matches!(operand, Operand::Reg(name) if written.contains(name)))
+}
+
+/// The names a block touches and the registers and variables it writes,
+/// including those of every function it calls, directly or through other
+/// functions.
+pub(crate) fn block_effects(
+    block: &[Statement],
+    program: &[Statement],
+) -> (Vec<String>, Vec<String>) {
+    let mut bodies = Vec::new();
+    collect_function_bodies(program, &mut bodies);
+
+    let mut calls = Vec::new();
+    let mut touched = Vec::new();
+    let mut written = Vec::new();
+    collect_touches(block, &mut calls, &mut touched);
+    collect_writes(block, &mut written);
+    // Calls found along the way are appended, so this visits each function once
+    let mut next = 0;
+    while next < calls.len() {
+        if let Some((_, body)) = bodies.iter().find(|(name, _)| *name == calls[next]) {
+            collect_touches(body, &mut calls, &mut touched);
+            collect_writes(body, &mut written);
+        }
+        next += 1;
+    }
+    (touched, written)
+}
+
+/// Collects the registers and variables a block writes, leaving out the
+/// functions it calls
+fn collect_writes(statements: &[Statement], written: &mut Vec<String>) {
+    for statement in statements {
+        match statement {
+            Statement::MoveImmediate { register: name, .. }
+            | Statement::StaticAssignment { variable: name, .. }
+            | Statement::ComparisonAssign { variable: name, .. }
+            | Statement::RegisterMove { dest: name, .. }
+            | Statement::BinaryOp { register: name, .. }
+            | Statement::Rotate { register: name, .. }
+            | Statement::PortIn { register: name, .. }
+            | Statement::ArrayLoad { register: name, .. }
+            | Statement::LoadIndirect { register: name, .. }
+            | Statement::LoadDirect { register: name, .. }
+            | Statement::Complement { register: name } => add(written, name),
+            Statement::DecimalAdjust | Statement::InterruptMask { set: false } => add(written, "A"),
+            // Pairs are written through their two registers
+            Statement::LoadImmediateExtended {
+                register_pair: pair,
+                ..
+            }
+            | Statement::LoadPair {
+                register_pair: pair,
+                ..
+            }
+            | Statement::ConditionalAssign { dest: pair, .. }
+            | Statement::PointerIncDec {
+                register_pair: pair,
+                ..
+            }
+            | Statement::StackOp {
+                register_pair: pair,
+                is_push: false,
+            } => {
+                let registers = if pair == "PSW" { "A" } else { pair.as_str() };
+                for register in registers.chars() {
+                    add(written, &register.to_string());
+                }
+            }
+            Statement::Swap { .. } => {
+                for register in ["H", "L", "D", "E"] {
+                    add(written, register);
+                }
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_writes(body, written);
+                if let Some(else_body) = else_body {
+                    collect_writes(else_body, written);
+                }
+            }
+            Statement::While { body, .. } => collect_writes(body, written),
+            // The loop counts down in B
+            Statement::Repeat { body, .. } => {
+                add(written, "B");
+                collect_writes(body, written);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What main or a function calls, and which variables and arrays it touches
+#[derive(Debug, PartialEq)]
+pub struct Dependencies {
+    pub function: String,
+    pub calls: Vec<String>,
+    pub variables: Vec<String>,
+}
+
+/// Builds the dependencies of main and then of each function in definition
+/// order. Each name is listed once, in the order it first appears.
+pub fn dependencies(statements: &[Statement]) -> Vec<Dependencies> {
+    let mut variables = HashSet::new();
+    collect_variables(statements, &mut variables);
+
+    let mut bodies = vec![("main".to_string(), statements)];
+    collect_function_bodies(statements, &mut bodies);
+    bodies
+        .into_iter()
+        .map(|(function, body)| {
+            let mut calls = Vec::new();
+            let mut names = Vec::new();
+            collect_touches(body, &mut calls, &mut names);
+            names.retain(|name| variables.contains(name));
+            Dependencies {
+                function,
+                calls,
+                variables: names,
+            }
+        })
+        .collect()
+}
+
+/// Collects every variable and array name in the program
+fn collect_variables(statements: &[Statement], variables: &mut HashSet<String>) {
+    for statement in statements {
+        match statement {
+            Statement::StaticAssignment { variable: name, .. }
+            | Statement::ComparisonAssign { variable: name, .. }
+            | Statement::ArrayDecl { name, .. }
+            | Statement::DataBytes { label: name, .. } => {
+                variables.insert(name.clone());
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_variables(body, variables);
+                if let Some(else_body) = else_body {
+                    collect_variables(else_body, variables);
+                }
+            }
+            Statement::While { body, .. }
+            | Statement::Repeat { body, .. }
+            | Statement::FunctionDef { body, .. } => {
+                collect_variables(body, variables);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_function_bodies<'a>(
+    statements: &'a [Statement],
+    bodies: &mut Vec<(String, &'a [Statement])>,
+) {
+    for statement in statements {
+        match statement {
+            Statement::FunctionDef { name, body } => {
+                bodies.push((name.clone(), body));
+                collect_function_bodies(body, bodies);
+            }
+            Statement::If {
+                body, else_body, ..
+            } => {
+                collect_function_bodies(body, bodies);
+                if let Some(else_body) = else_body {
+                    collect_function_bodies(else_body, bodies);
+                }
+            }
+            Statement::While { body, .. } | Statement::Repeat { body, .. } => {
+                collect_function_bodies(body, bodies);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The deepest chain of nested calls, starting with main. A function that
+/// can end up calling itself has no bounded depth, so its name is returned
+/// as the error instead.
+pub fn deepest_calls(statements: &[Statement]) -> Result<Vec<String>, String> {
+    let graph: HashMap<String, Vec<String>> = dependencies(statements)
+        .into_iter()
+        .map(|deps| (deps.function, deps.calls))
+        .collect();
+    let mut chain = vec!["main".to_string()];
+    let mut deepest = chain.clone();
+    walk_calls(&graph, &mut chain, &mut deepest)?;
+    Ok(deepest)
+}
+
+fn walk_calls(
+    graph: &HashMap<String, Vec<String>>,
+    chain: &mut Vec<String>,
+    deepest: &mut Vec<String>,
+) -> Result<(), String> {
+    if chain.len() > deepest.len() {
+        *deepest = chain.clone();
+    }
+    let caller = chain.last().cloned().unwrap_or_default();
+    for callee in graph.get(&caller).into_iter().flatten() {
+        if chain.contains(callee) {
+            return Err(callee.clone());
+        }
+        chain.push(callee.clone());
+        walk_calls(graph, chain, deepest)?;
+        chain.pop();
+    }
+    Ok(())
+}
+
+/// Collects the functions a block calls and every name it mentions, leaving
+/// out the bodies of functions defined inside it
+fn collect_touches(statements: &[Statement], calls: &mut Vec<String>, names: &mut Vec<String>) {
+    for statement in statements {
+        match statement {
+            Statement::Call { name } => add(calls, name),
+            Statement::StaticAssignment { variable: name, .. }
+            | Statement::ArrayDecl { name, .. }
+            | Statement::DataBytes { label: name, .. }
+            | Statement::LoadPair { variable: name, .. }
+            | Statement::Rotate { register: name, .. }
+            | Statement::PortIn { register: name, .. }
+            | Statement::PortOut { register: name, .. }
+            | Statement::Print { register: name } => add(names, name),
+            Statement::BinaryOp {
+                register, second, ..
+            } => {
+                add(names, register);
+                if let Operand::Reg(second) = second {
+                    add(names, second);
+                }
+            }
+            Statement::ArrayLoad {
+                register,
+                array,
+                index,
+            }
+            | Statement::ArrayStore {
+                array,
+                index,
+                register,
+            } => {
+                add(names, register);
+                add(names, array);
+                add(names, index);
+            }
+            Statement::If {
+                guard,
+                body,
+                else_body,
+            } => {
+                collect_guard_touches(guard, names);
+                collect_touches(body, calls, names);
+                if let Some(else_body) = else_body {
+                    collect_touches(else_body, calls, names);
+                }
+            }
+            Statement::ConditionalAssign {
+                condition_left,
+                condition,
+                condition_right,
+                ..
+            } => {
+                collect_guard_touches(
+                    &comparison(condition_left, condition, condition_right),
+                    names,
+                );
+            }
+            Statement::ComparisonAssign {
+                variable,
+                condition_left,
+                condition,
+                condition_right,
+            } => {
+                collect_guard_touches(
+                    &comparison(condition_left, condition, condition_right),
+                    names,
+                );
+                add(names, variable);
+            }
+            Statement::While { guard, body } => {
+                collect_guard_touches(guard, names);
+                collect_touches(body, calls, names);
+            }
+            Statement::Repeat { body, .. } => {
+                collect_touches(body, calls, names);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_guard_touches(guard: &Guard, names: &mut Vec<String>) {
+    let mut comparisons = Vec::new();
+    flatten_guard(guard, &mut comparisons);
+    for operand in comparisons
+        .into_iter()
+        .flat_map(|(left, right)| [left, right])
+    {
+        if let Operand::Reg(name) = operand {
+            add(names, name);
+        }
+    }
+}
+
+/// Appends a name unless it is already listed
+fn add(list: &mut Vec<String>, name: &str) {
+    if !list.iter().any(|existing| existing == name) {
+        list.push(name.to_string());
+    }
+}
+
+fn collect_guard_uses(guard: &Guard, used: &mut HashSet<String>) {
+    let mut comparisons = Vec::new();
+    flatten_guard(guard, &mut comparisons);
+    for operand in comparisons
+        .into_iter()
+        .flat_map(|(left, right)| [left, right])
+    {
+        if let Operand::Reg(name) = operand {
+            used.insert(name.clone());
+        }
+    }
+}
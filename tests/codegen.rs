@@ -0,0 +1,387 @@
+// tests/codegen.rs
+
+//! Checks the assembly generated for c85 programs, instruction by
+//! instruction.
+
+use c85c::codegen::{GenerateOptions, Target};
+
+fn compile_with(source: &str, options: GenerateOptions) -> String {
+    c85c::compile_with_options(source, &options).unwrap_or_else(|err| panic!("{}", err))
+}
+
+fn compile(source: &str) -> String {
+    compile_with(source, GenerateOptions::default())
+}
+
+/// The instructions of generated assembly, one per line, without the `;`
+fn instructions(asm_code: &str) -> Vec<&str> {
+    asm_code
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .map(|line| line.trim_end_matches(';'))
+        .collect()
+}
+
+#[test]
+fn program_ends_with_hlt() {
+    let asm_code = compile("main{\n    counter = 0x05;\n    reg A = 0x01;\n}\n");
+    assert_eq!(instructions(&asm_code).last(), Some(&"END"));
+    assert_eq!(instructions(&asm_code).iter().rev().nth(1), Some(&"HLT"));
+}
+
+#[test]
+fn no_halt_falls_through() {
+    let asm_code = compile_with(
+        "main{\n    reg A = 0x01;\n}\n",
+        GenerateOptions {
+            emit_halt: false,
+            ..GenerateOptions::default()
+        },
+    );
+    assert!(!instructions(&asm_code).contains(&"HLT"), "{}", asm_code);
+}
+
+#[test]
+fn greater_skips_on_equal_or_less() {
+    let asm_code = compile(
+        "main{\n    reg B = 0x07;\n    reg C = 0x05;\n    if (B > C) {\n        reg D = 0x01;\n    }\n}\n",
+    );
+    let code = instructions(&asm_code);
+    let compare = code
+        .iter()
+        .position(|line| *line == "CMP C")
+        .expect("a compare");
+    assert_eq!(code[compare + 1..compare + 3], ["JZ _SKIP_0", "JC _SKIP_0"]);
+}
+
+#[test]
+fn less_skips_on_no_carry_alone() {
+    // CMP leaves the carry clear when the operands are equal
+    let asm_code = compile(
+        "main{\n    reg B = 0x07;\n    reg C = 0x05;\n    if (B < C) {\n        reg D = 0x01;\n    }\n}\n",
+    );
+    let code = instructions(&asm_code);
+    let compare = code
+        .iter()
+        .position(|line| *line == "CMP C")
+        .expect("a compare");
+    assert_eq!(code[compare + 1..compare + 3], ["JNC _SKIP_0", "MVI D,01H"]);
+}
+
+#[test]
+fn right_operand_in_a_is_compared_the_other_way_round() {
+    // Loading B into A would lose the right operand, so B > A becomes A < B
+    let asm_code = compile(
+        "main{\n    reg A = 0x05;\n    reg B = 0x07;\n    if (B > A) {\n        reg D = 0x01;\n    }\n}\n",
+    );
+    let code = instructions(&asm_code);
+    let compare = code
+        .iter()
+        .position(|line| *line == "CMP B")
+        .expect("a compare");
+    assert_ne!(code[compare - 1], "MOV A,B", "{}", asm_code);
+    assert_eq!(code[compare + 1], "JNC _SKIP_0");
+}
+
+#[test]
+fn wide_variables_take_two_bytes() {
+    let asm_code =
+        compile("main{\n    first = 0x1234;\n    second = 0x5678;\n    third = 0x01;\n}\n");
+    let code = instructions(&asm_code);
+    assert!(code.contains(&"SHLD 8000H"), "{}", asm_code);
+    assert!(code.contains(&"SHLD 8002H"), "{}", asm_code);
+    assert!(code.contains(&"STA 8004H"), "{}", asm_code);
+}
+
+/// Source with an if/else nested in an if, followed by another if
+const NESTED_IFS: &str = "main{
+    B = in(0x01);
+    C = in(0x02);
+    reg L = 0x05;
+    if (B > L) {
+        if (C > L) {
+            reg D = 0x01;
+        } else {
+            reg D = 0x02;
+        }
+        reg E = 0x03;
+    }
+    if (B == C) {
+        reg H = 0x04;
+    }
+}
+";
+
+#[test]
+fn nested_if_labels_are_unique() {
+    let asm_code = compile(NESTED_IFS);
+    let code = instructions(&asm_code);
+    let labels: Vec<&str> = code
+        .iter()
+        .filter_map(|line| line.strip_suffix(':'))
+        .collect();
+    assert_eq!(labels, ["_SKIP_1", "_END_1", "_SKIP_0", "_SKIP_2"]);
+    // Every jump goes to a label defined once
+    for line in &code {
+        if let Some(target) = line
+            .strip_prefix('J')
+            .and_then(|jump| jump.split_once(' '))
+            .map(|(_, target)| target)
+        {
+            assert_eq!(
+                labels.iter().filter(|label| **label == target).count(),
+                1,
+                "{}",
+                line
+            );
+        }
+    }
+}
+
+#[test]
+fn outer_skip_label_follows_the_inner_if() {
+    let asm_code = compile(NESTED_IFS);
+    let code = instructions(&asm_code);
+    let position = |line: &str| {
+        code.iter()
+            .position(|other| *other == line)
+            .unwrap_or_else(|| panic!("no {} in\n{}", line, asm_code))
+    };
+    assert!(position("_SKIP_1:") < position("_END_1:"));
+    assert!(position("_END_1:") < position("MVI E,03H"));
+    assert!(position("MVI E,03H") < position("_SKIP_0:"));
+    assert!(position("_SKIP_0:") < position("CMP C"));
+}
+
+#[test]
+fn empty_main_halts_and_assembles() {
+    let asm_code = compile("main{}");
+    assert_eq!(
+        instructions(&asm_code),
+        ["ORG 0000H", "LXI SP,0FFFFH", "HLT", "END"]
+    );
+    assert!(c85c::intel_hex(&asm_code).is_ok());
+}
+
+#[test]
+fn reassignment_updates_memory_and_register() {
+    let asm_code = compile(
+        "main{\n    first = 0x07;\n    counter = 0x01;\n    counter = 0x02;\n    out(0x01) = counter;\n}\n",
+    );
+    let code = instructions(&asm_code);
+    let load = code
+        .iter()
+        .position(|line| *line == "MVI A,02H")
+        .expect("a load of the new value");
+    assert_eq!(
+        code[load + 1..load + 3],
+        ["STA 8001H", "MOV C,A"],
+        "{}",
+        asm_code
+    );
+}
+
+#[test]
+fn wide_comparison_checks_the_low_bytes_only_when_the_high_bytes_match() {
+    let asm_code = compile(
+        "main{\n    H = in(0x01);\n    L = in(0x02);\n    D = in(0x03);\n    E = in(0x04);\n    if (HL > DE) {\n        reg C = 0x01;\n    }\n}\n",
+    );
+    let code = instructions(&asm_code);
+    let start = code
+        .iter()
+        .position(|line| *line == "MOV A,H")
+        .expect("a compare of the high bytes");
+    assert_eq!(
+        code[start..start + 8],
+        [
+            "MOV A,H",
+            "CMP D",
+            "JNZ _WIDE_1",
+            "MOV A,L",
+            "CMP E",
+            "_WIDE_1:",
+            "JZ _SKIP_0",
+            "JC _SKIP_0"
+        ],
+        "{}",
+        asm_code
+    );
+}
+
+/// Source whose user label has the name of the label generated for its if
+const USER_SKIP_LABEL: &str = "main{
+    B = in(0x01);
+    if (B > 0x05) {
+        goto SKIP_0;
+    }
+    reg D = 0x01;
+    label SKIP_0;
+    reg E = 0x02;
+}
+";
+
+#[test]
+fn user_label_does_not_collide_with_generated_labels() {
+    let asm_code = compile(USER_SKIP_LABEL);
+    let code = instructions(&asm_code);
+    let labels: Vec<&str> = code
+        .iter()
+        .filter_map(|line| line.strip_suffix(':'))
+        .collect();
+    assert_eq!(labels, ["_SKIP_0", "SKIP_0"]);
+    assert!(
+        code.contains(&"JMP SKIP_0") && code.contains(&"JC _SKIP_0"),
+        "{}",
+        asm_code
+    );
+    assert!(c85c::intel_hex(&asm_code).is_ok());
+}
+
+#[test]
+fn chained_assignment_loads_the_value_once() {
+    let asm_code = compile("main{\n    a = b = c = 0x05;\n}\n");
+    let code = instructions(&asm_code);
+    assert_eq!(
+        code.iter().filter(|line| line.starts_with("MVI")).count(),
+        1,
+        "{}",
+        asm_code
+    );
+    for store in ["STA 8000H", "STA 8001H", "STA 8002H"] {
+        assert!(code.contains(&store), "no {} in\n{}", store, asm_code);
+    }
+}
+
+#[test]
+fn load_of_a_just_stored_variable_is_dropped_past_other_moves() {
+    // `sixth` has no register left, so out() reads it back from memory, but
+    // A still holds it: the move in between doesn't touch A
+    let asm_code = compile(
+        "main{\n    first = 0x01;\n    second = 0x02;\n    third = 0x03;\n    fourth = 0x04;\n    fifth = 0x05;\n    sixth = 0x10;\n    H = B;\n    out(0x01) = sixth;\n}\n",
+    );
+    let code = instructions(&asm_code);
+    let store = code
+        .iter()
+        .position(|line| *line == "STA 8005H")
+        .expect("a store of sixth");
+    assert_eq!(
+        code[store + 1..store + 3],
+        ["MOV H,B", "OUT 01H"],
+        "{}",
+        asm_code
+    );
+}
+
+#[test]
+fn load_of_a_just_stored_device_address_is_kept() {
+    let asm_code =
+        compile("main{\n    A = in(0x01);\n    [0x9000] = A;\n    reg A = [0x9000];\n}\n");
+    let code = instructions(&asm_code);
+    let store = code
+        .iter()
+        .position(|line| *line == "STA 9000H")
+        .expect("a store");
+    assert_eq!(code[store + 1], "LDA 9000H", "{}", asm_code);
+}
+
+/// The instruction that loads A with `literal`
+fn load_of(literal: &str) -> String {
+    let asm_code = compile(&format!("main{{\n    reg A = {};\n}}\n", literal));
+    let code = instructions(&asm_code);
+    code.iter()
+        .find(|line| line.starts_with("MVI A,"))
+        .unwrap_or_else(|| panic!("no MVI in\n{}", asm_code))
+        .to_string()
+}
+
+#[test]
+fn hex_literals_in_any_case_give_the_same_operand() {
+    for literal in ["0Xff", "0xFF", "0xfF"] {
+        assert_eq!(load_of(literal), "MVI A,0FFH", "{}", literal);
+    }
+    for literal in ["0xaB", "0xAB", "0XAb"] {
+        assert_eq!(load_of(literal), "MVI A,0ABH", "{}", literal);
+    }
+}
+
+#[test]
+fn single_digit_hex_is_padded_to_a_byte() {
+    assert_eq!(load_of("0x5"), "MVI A,05H");
+}
+
+#[test]
+fn hex_operand_starting_with_a_letter_gets_a_leading_zero() {
+    // Most assemblers read a bare `AH` as a name; the zero keeps it a number
+    assert_eq!(load_of("0xA"), "MVI A,0AH");
+    assert_eq!(load_of("0xF0"), "MVI A,0F0H");
+    assert_eq!(load_of("0xFF"), "MVI A,0FFH");
+    assert!(c85c::intel_hex(&compile("main{\n    reg A = 0xF0;\n}\n")).is_ok());
+}
+
+#[test]
+fn wide_variable_is_stored_and_read_back_through_hl() {
+    let asm_code = compile(
+        "main{\n    wide = 0x1234;\n    next = 0x05;\n    reg HL = wide;\n    [0x9000] = HL;\n}\n",
+    );
+    let wide: Vec<&str> = instructions(&asm_code)
+        .into_iter()
+        .filter(|line| line.contains(" H,") || line.contains("HLD"))
+        .collect();
+    assert_eq!(
+        wide,
+        ["LXI H,1234H", "SHLD 8000H", "LHLD 8000H", "SHLD 9000H"]
+    );
+}
+
+#[test]
+fn known_operands_are_folded_when_no_flags_are_read() {
+    let asm_code = compile(
+        "main{\n    reg A = 0x02;\n    reg B = 0x03;\n    A += B;\n    reg C = 0x01;\n    A -= C;\n}\n",
+    );
+    let code = instructions(&asm_code);
+    assert!(
+        code.contains(&"MVI A,05H") && code.contains(&"MVI A,04H"),
+        "{}",
+        asm_code
+    );
+    assert!(
+        !code
+            .iter()
+            .any(|line| line.starts_with("ADD") || line.starts_with("SUB")),
+        "{}",
+        asm_code
+    );
+}
+
+#[test]
+fn operation_whose_carry_is_rotated_in_is_not_folded() {
+    let asm_code = compile(
+        "main{\n    reg A = 0xFF;\n    reg B = 0x01;\n    A += B;\n    reg C = 0x05;\n    A <<< 1;\n}\n",
+    );
+    let code = instructions(&asm_code);
+    assert!(code.contains(&"ADD B"), "{}", asm_code);
+    assert!(code.contains(&"RAL"), "{}", asm_code);
+}
+
+#[test]
+fn interrupt_masks_need_the_8085() {
+    let source =
+        "main{\n    read_interrupt_mask();\n    A &= 0x07;\n    set_interrupt_mask();\n}\n";
+    let code = compile(source);
+    assert!(
+        instructions(&code).contains(&"RIM") && instructions(&code).contains(&"SIM"),
+        "{}",
+        code
+    );
+
+    let options = GenerateOptions {
+        target: Target::Intel8080,
+        ..GenerateOptions::default()
+    };
+    let error = c85c::compile_with_options(source, &options).expect_err("RIM is 8085-only");
+    assert_eq!(
+        error.message,
+        "RIM is only available on the 8085, but the target is the 8080"
+    );
+    assert!(c85c::compile_with_options("main{\n    reg A = 0x01;\n}\n", &options).is_ok());
+}
@@ -0,0 +1,416 @@
+// tests/emulator/mod.rs
+
+//! A minimal 8085 interpreter for the end-to-end tests. It runs the machine
+//! code the bundled assembler produces, covering the instructions codegen
+//! emits, and stops at HLT.
+
+/// Instructions run before a program is assumed to loop forever
+const STEP_LIMIT: usize = 100_000;
+
+pub struct Cpu {
+    /// B, C, D, E, H, L, (M), A, indexed the way opcodes encode them
+    registers: [u8; 8],
+    sp: u16,
+    pc: u16,
+    zero: bool,
+    sign: bool,
+    parity: bool,
+    carry: bool,
+    aux_carry: bool,
+    memory: Vec<u8>,
+    /// Values read by IN, by port
+    pub inputs: [u8; 256],
+    /// Every OUT, as (port, value), in order
+    pub outputs: Vec<(u8, u8)>,
+}
+
+impl Cpu {
+    /// Loads an Intel HEX file into an otherwise zeroed memory
+    pub fn load(hex: &str) -> Cpu {
+        let mut memory = vec![0; 0x10000];
+        for record in hex.lines() {
+            let bytes: Vec<u8> = (1..record.len())
+                .step_by(2)
+                .map(|i| {
+                    u8::from_str_radix(&record[i..i + 2], 16).expect("Intel HEX is hex digits")
+                })
+                .collect();
+            let (length, address, record_type) = (
+                bytes[0] as usize,
+                u16::from_be_bytes([bytes[1], bytes[2]]),
+                bytes[3],
+            );
+            if record_type == 0x00 {
+                for (offset, byte) in bytes[4..4 + length].iter().enumerate() {
+                    memory[usize::from(address) + offset] = *byte;
+                }
+            }
+        }
+        Cpu {
+            registers: [0; 8],
+            sp: 0,
+            pc: 0,
+            zero: false,
+            sign: false,
+            parity: false,
+            carry: false,
+            aux_carry: false,
+            memory,
+            inputs: [0; 256],
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Runs from the current PC until HLT, panicking if that never comes
+    pub fn run(&mut self) {
+        for _ in 0..STEP_LIMIT {
+            if !self.step() {
+                return;
+            }
+        }
+        panic!(
+            "no HLT after {} instructions (PC at {:04X}H)",
+            STEP_LIMIT, self.pc
+        );
+    }
+
+    /// An 8-bit register by name, e.g. "A"
+    pub fn register(&self, name: &str) -> u8 {
+        let index = ["B", "C", "D", "E", "H", "L", "M", "A"]
+            .iter()
+            .position(|register| *register == name)
+            .unwrap_or_else(|| panic!("no register {}", name));
+        self.read_register(index as u8)
+    }
+
+    pub fn byte(&self, address: u16) -> u8 {
+        self.memory[usize::from(address)]
+    }
+
+    pub fn word(&self, address: u16) -> u16 {
+        u16::from_le_bytes([self.byte(address), self.byte(address.wrapping_add(1))])
+    }
+
+    fn set_byte(&mut self, address: u16, value: u8) {
+        self.memory[usize::from(address)] = value;
+    }
+
+    fn set_word(&mut self, address: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.set_byte(address, low);
+        self.set_byte(address.wrapping_add(1), high);
+    }
+
+    fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.registers[4], self.registers[5]])
+    }
+
+    /// Register 6 is M, the byte HL points at
+    fn read_register(&self, index: u8) -> u8 {
+        match index {
+            6 => self.byte(self.hl()),
+            _ => self.registers[usize::from(index)],
+        }
+    }
+
+    fn write_register(&mut self, index: u8, value: u8) {
+        match index {
+            6 => self.set_byte(self.hl(), value),
+            _ => self.registers[usize::from(index)] = value,
+        }
+    }
+
+    /// Pair 0-2 is BC, DE, HL; 3 is SP
+    fn pair(&self, index: u8) -> u16 {
+        match index {
+            3 => self.sp,
+            _ => u16::from_be_bytes([
+                self.registers[usize::from(index) * 2],
+                self.registers[usize::from(index) * 2 + 1],
+            ]),
+        }
+    }
+
+    fn set_pair(&mut self, index: u8, value: u16) {
+        match index {
+            3 => self.sp = value,
+            _ => {
+                let [high, low] = value.to_be_bytes();
+                self.registers[usize::from(index) * 2] = high;
+                self.registers[usize::from(index) * 2 + 1] = low;
+            }
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        u8::from(self.sign) << 7
+            | u8::from(self.zero) << 6
+            | u8::from(self.aux_carry) << 4
+            | u8::from(self.parity) << 2
+            | 0x02
+            | u8::from(self.carry)
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.sign = flags & 0x80 != 0;
+        self.zero = flags & 0x40 != 0;
+        self.aux_carry = flags & 0x10 != 0;
+        self.parity = flags & 0x04 != 0;
+        self.carry = flags & 0x01 != 0;
+    }
+
+    /// Sets Z, S and P from a result
+    fn set_result_flags(&mut self, value: u8) {
+        self.zero = value == 0;
+        self.sign = value & 0x80 != 0;
+        self.parity = value.count_ones().is_multiple_of(2);
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let byte = self.byte(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch();
+        let high = self.fetch();
+        u16::from_le_bytes([low, high])
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.set_word(self.sp, value);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let value = self.word(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
+    }
+
+    /// The condition in bits 5-3 of a conditional jump, call or return
+    fn condition(&self, code: u8) -> bool {
+        match code {
+            0 => !self.zero,
+            1 => self.zero,
+            2 => !self.carry,
+            3 => self.carry,
+            4 => !self.parity,
+            5 => self.parity,
+            6 => !self.sign,
+            _ => self.sign,
+        }
+    }
+
+    /// ADD, ADC, SUB, SBB, ANA, XRA, ORA or CMP (operation 0-7) on A
+    fn alu(&mut self, operation: u8, value: u8) {
+        let a = self.registers[7];
+        let carry = u8::from(self.carry);
+        let result = match operation {
+            0 | 1 => {
+                let carry = if operation == 1 { carry } else { 0 };
+                let sum = u16::from(a) + u16::from(value) + u16::from(carry);
+                self.aux_carry = (a & 0x0F) + (value & 0x0F) + carry > 0x0F;
+                self.carry = sum > 0xFF;
+                sum as u8
+            }
+            2 | 3 | 7 => {
+                let borrow = if operation == 3 { carry } else { 0 };
+                let difference = i16::from(a) - i16::from(value) - i16::from(borrow);
+                self.aux_carry = (a & 0x0F) >= (value & 0x0F) + borrow;
+                self.carry = difference < 0;
+                difference as u8
+            }
+            4 => {
+                self.aux_carry = true;
+                self.carry = false;
+                a & value
+            }
+            5 => {
+                self.aux_carry = false;
+                self.carry = false;
+                a ^ value
+            }
+            _ => {
+                self.aux_carry = false;
+                self.carry = false;
+                a | value
+            }
+        };
+        self.set_result_flags(result);
+        // CMP only sets the flags
+        if operation != 7 {
+            self.registers[7] = result;
+        }
+    }
+
+    /// Executes one instruction; returns false at HLT
+    fn step(&mut self) -> bool {
+        let opcode = self.fetch();
+        let (high, middle, low) = (opcode >> 6, (opcode >> 3) & 0x07, opcode & 0x07);
+        let pair = middle >> 1;
+        match opcode {
+            0x76 => return false,
+            0x00 | 0x30 | 0xF3 | 0xFB => {} // NOP, SIM, DI, EI
+            0x20 => self.registers[7] = 0,  // RIM: no interrupts pending
+            _ if high == 1 => self.write_register(middle, self.read_register(low)),
+            _ if high == 2 => self.alu(middle, self.read_register(low)),
+            _ if high == 3 && low == 6 => {
+                let value = self.fetch();
+                self.alu(middle, value);
+            }
+            _ if high == 0 && low == 6 => {
+                let value = self.fetch();
+                self.write_register(middle, value);
+            }
+            _ if high == 0 && (low == 4 || low == 5) => {
+                let value = self.read_register(middle);
+                let result = if low == 4 {
+                    value.wrapping_add(1)
+                } else {
+                    value.wrapping_sub(1)
+                };
+                self.aux_carry = if low == 4 {
+                    value & 0x0F == 0x0F
+                } else {
+                    value & 0x0F != 0
+                };
+                self.set_result_flags(result);
+                self.write_register(middle, result);
+            }
+            0x01 | 0x11 | 0x21 | 0x31 => {
+                let value = self.fetch_word();
+                self.set_pair(pair, value);
+            }
+            0x03 | 0x13 | 0x23 | 0x33 => self.set_pair(pair, self.pair(pair).wrapping_add(1)),
+            0x0B | 0x1B | 0x2B | 0x3B => self.set_pair(pair, self.pair(pair).wrapping_sub(1)),
+            0x09 | 0x19 | 0x29 | 0x39 => {
+                let sum = u32::from(self.hl()) + u32::from(self.pair(pair));
+                self.carry = sum > 0xFFFF;
+                self.set_pair(2, sum as u16);
+            }
+            0x02 | 0x12 => self.set_byte(self.pair(pair), self.registers[7]),
+            0x0A | 0x1A => self.registers[7] = self.byte(self.pair(pair)),
+            0x22 => {
+                let address = self.fetch_word();
+                self.set_word(address, self.hl());
+            }
+            0x2A => {
+                let address = self.fetch_word();
+                self.set_pair(2, self.word(address));
+            }
+            0x32 => {
+                let address = self.fetch_word();
+                self.set_byte(address, self.registers[7]);
+            }
+            0x3A => {
+                let address = self.fetch_word();
+                self.registers[7] = self.byte(address);
+            }
+            0x07 => {
+                let a = self.registers[7];
+                self.carry = a & 0x80 != 0;
+                self.registers[7] = a.rotate_left(1);
+            }
+            0x0F => {
+                let a = self.registers[7];
+                self.carry = a & 0x01 != 0;
+                self.registers[7] = a.rotate_right(1);
+            }
+            0x17 => {
+                let a = self.registers[7];
+                self.registers[7] = a << 1 | u8::from(self.carry);
+                self.carry = a & 0x80 != 0;
+            }
+            0x1F => {
+                let a = self.registers[7];
+                self.registers[7] = a >> 1 | u8::from(self.carry) << 7;
+                self.carry = a & 0x01 != 0;
+            }
+            0x27 => {
+                let a = self.registers[7];
+                let mut correction = 0;
+                let mut carry = self.carry;
+                if a & 0x0F > 9 || self.aux_carry {
+                    correction |= 0x06;
+                }
+                if a > 0x99 || self.carry {
+                    correction |= 0x60;
+                    carry = true;
+                }
+                self.alu(0, correction);
+                self.carry = carry;
+            }
+            0x2F => self.registers[7] = !self.registers[7],
+            0x37 => self.carry = true,
+            0x3F => self.carry = !self.carry,
+            0xC3 => self.pc = self.fetch_word(),
+            _ if high == 3 && low == 2 => {
+                let target = self.fetch_word();
+                if self.condition(middle) {
+                    self.pc = target;
+                }
+            }
+            0xCD => {
+                let target = self.fetch_word();
+                self.push(self.pc);
+                self.pc = target;
+            }
+            _ if high == 3 && low == 4 => {
+                let target = self.fetch_word();
+                if self.condition(middle) {
+                    self.push(self.pc);
+                    self.pc = target;
+                }
+            }
+            0xC9 => self.pc = self.pop(),
+            _ if high == 3 && low == 0 => {
+                if self.condition(middle) {
+                    self.pc = self.pop();
+                }
+            }
+            _ if high == 3 && low == 7 => {
+                self.push(self.pc);
+                self.pc = u16::from(middle) * 8;
+            }
+            0xF5 => self.push(u16::from_be_bytes([self.registers[7], self.flags()])),
+            0xC5 | 0xD5 | 0xE5 => self.push(self.pair(pair)),
+            0xF1 => {
+                let [a, flags] = self.pop().to_be_bytes();
+                self.registers[7] = a;
+                self.set_flags(flags);
+            }
+            0xC1 | 0xD1 | 0xE1 => {
+                let value = self.pop();
+                self.set_pair(pair, value);
+            }
+            0xE3 => {
+                let top = self.word(self.sp);
+                self.set_word(self.sp, self.hl());
+                self.set_pair(2, top);
+            }
+            0xE9 => self.pc = self.hl(),
+            0xEB => {
+                let (de, hl) = (self.pair(1), self.hl());
+                self.set_pair(1, hl);
+                self.set_pair(2, de);
+            }
+            0xF9 => self.sp = self.hl(),
+            0xD3 => {
+                let port = self.fetch();
+                self.outputs.push((port, self.registers[7]));
+            }
+            0xDB => {
+                let port = self.fetch();
+                self.registers[7] = self.inputs[usize::from(port)];
+            }
+            _ => panic!(
+                "unsupported opcode {:02X}H at {:04X}H",
+                opcode,
+                self.pc.wrapping_sub(1)
+            ),
+        }
+        true
+    }
+}
@@ -0,0 +1,568 @@
+// tests/end_to_end.rs
+
+//! Compiles c85 programs, assembles them with the bundled assembler and runs
+//! the machine code on a small 8085 interpreter, checking the registers,
+//! memory and port output the program ends with.
+
+mod emulator;
+
+use emulator::Cpu;
+
+/// Compiles and runs `source` until HLT, with `inputs` as (port, value) pairs
+/// for `in()` to read
+fn run(source: &str, inputs: &[(u8, u8)]) -> Cpu {
+    let asm_code = c85c::compile(source).unwrap_or_else(|err| panic!("{}", err.render(source)));
+    let hex = c85c::intel_hex(&asm_code).unwrap_or_else(|err| panic!("{}\n{}", err, asm_code));
+    let mut cpu = Cpu::load(&hex);
+    for &(port, value) in inputs {
+        cpu.inputs[usize::from(port)] = value;
+    }
+    cpu.run();
+    cpu
+}
+
+#[test]
+fn variables_are_stored_and_backed_by_registers() {
+    let cpu = run("main{\n    first = 0x12;\n    second = 0x34;\n}\n", &[]);
+    assert_eq!(cpu.byte(0x8000), 0x12);
+    assert_eq!(cpu.byte(0x8001), 0x34);
+    assert_eq!(cpu.register("B"), 0x12);
+    assert_eq!(cpu.register("C"), 0x34);
+}
+
+#[test]
+fn arithmetic_on_values_read_at_run_time() {
+    // IN reads through A, so B is read first
+    let source = "main{
+    B = in(0x02);
+    A = in(0x01);
+    A += B;
+    out(0x10) = A;
+    A -= 0x05;
+    out(0x11) = A;
+    A &= 0x0F;
+    out(0x12) = A;
+}
+";
+    let cpu = run(source, &[(0x01, 0x30), (0x02, 0x0C)]);
+    assert_eq!(cpu.outputs, vec![(0x10, 0x3C), (0x11, 0x37), (0x12, 0x07)]);
+}
+
+#[test]
+fn comparison_results_match_for_every_operator() {
+    let source = "main{
+    H = in(0x01);
+    L = in(0x02);
+    greater = (H > L);
+    less = (H < L);
+    equal = (H == L);
+    not_equal = (H != L);
+    greater_equal = (H >= L);
+    less_equal = (H <= L);
+}
+";
+    for (left, right) in [
+        (0x03, 0x05),
+        (0x05, 0x03),
+        (0x04, 0x04),
+        (0x00, 0xFF),
+        (0xFF, 0x00),
+        (0x80, 0x7F),
+    ] {
+        let cpu = run(source, &[(0x01, left), (0x02, right)]);
+        let expected = [
+            left > right,
+            left < right,
+            left == right,
+            left != right,
+            left >= right,
+            left <= right,
+        ];
+        for (offset, holds) in expected.into_iter().enumerate() {
+            assert_eq!(
+                cpu.byte(0x8000 + offset as u16),
+                u8::from(holds),
+                "comparison {} of {:02X}H and {:02X}H",
+                offset,
+                left,
+                right
+            );
+        }
+    }
+}
+
+#[test]
+fn comparison_result_keeps_the_accumulator() {
+    let source = "main{
+    A = in(0x01);
+    reg C = 0x10;
+    flag = (A > C);
+    out(0x02) = A;
+}
+";
+    let cpu = run(source, &[(0x01, 0x20)]);
+    assert_eq!(cpu.byte(0x8000), 0x01);
+    assert_eq!(cpu.outputs, vec![(0x02, 0x20)]);
+}
+
+#[test]
+fn if_else_takes_the_right_branch() {
+    let source = "main{
+    A = in(0x01);
+    if (A >= 0x10) {
+        reg C = 0xAA;
+    } else {
+        reg C = 0xBB;
+    }
+}
+";
+    assert_eq!(run(source, &[(0x01, 0x10)]).register("C"), 0xAA);
+    assert_eq!(run(source, &[(0x01, 0x0F)]).register("C"), 0xBB);
+}
+
+#[test]
+fn while_loop_sums_a_countdown() {
+    let source = "main{
+    reg D = 0x00;
+    C = in(0x01);
+    while (C > 0x00) {
+        D += C;
+        C -= 0x01;
+    }
+}
+";
+    assert_eq!(run(source, &[(0x01, 0x05)]).register("D"), 0x0F);
+    assert_eq!(run(source, &[(0x01, 0x00)]).register("D"), 0x00);
+}
+
+#[test]
+fn variable_increment_counts_a_while_loop_up() {
+    let source = "main{
+    counter = 0x01;
+    result = 0x05;
+    while (counter < result) {
+        counter++;
+    }
+    out(0x01) = counter;
+    out(0x02) = result;
+}
+";
+    assert_eq!(run(source, &[]).outputs, vec![(0x01, 0x05), (0x02, 0x05)]);
+}
+
+#[test]
+fn repeat_runs_its_body_count_times() {
+    let cpu = run(
+        "main{\n    reg A = 0x00;\n    repeat(0x04) {\n        A += 0x03;\n    }\n}\n",
+        &[],
+    );
+    assert_eq!(cpu.register("A"), 0x0C);
+}
+
+#[test]
+fn repeat_body_reads_a_variable() {
+    // B counts the loop down, so `n` is kept in C
+    let source = "main{
+    n = 0x05;
+    repeat(0x03) {
+        out(0x01) = n;
+    }
+}
+";
+    assert_eq!(run(source, &[]).outputs, vec![(0x01, 0x05); 3]);
+}
+
+#[test]
+fn variable_keeps_its_value_across_a_repeat() {
+    let source = "main{
+    n = 0x05;
+    n += 0x01;
+    repeat(0x03) {
+        nop();
+    }
+    out(0x01) = n;
+}
+";
+    assert_eq!(run(source, &[]).outputs, vec![(0x01, 0x06)]);
+}
+
+#[test]
+fn functions_return_to_the_caller() {
+    let source = "main{
+    A = in(0x01);
+    double();
+    double();
+    out(0x02) = A;
+}
+fn double {
+    A += A;
+}
+";
+    let cpu = run(source, &[(0x01, 0x03)]);
+    assert_eq!(cpu.outputs, vec![(0x02, 0x0C)]);
+}
+
+#[test]
+fn lookup_table_is_indexed_by_a_register() {
+    let source = "main{
+    segments: db 0x3F, 0x06, 0x5B, 0x4F;
+    B = in(0x01);
+    A = segments[B];
+    out(0x02) = A;
+}
+";
+    for (digit, pattern) in [(0x00, 0x3F), (0x01, 0x06), (0x02, 0x5B), (0x03, 0x4F)] {
+        assert_eq!(run(source, &[(0x01, digit)]).outputs, vec![(0x02, pattern)]);
+    }
+}
+
+#[test]
+fn wide_variable_round_trips_through_memory() {
+    let source = "main{
+    wide = 0x1234;
+    next = 0x05;
+    reg HL = wide;
+    [0x9000] = HL;
+}
+";
+    let cpu = run(source, &[]);
+    assert_eq!(cpu.word(0x8000), 0x1234);
+    assert_eq!(cpu.byte(0x8002), 0x05);
+    assert_eq!(cpu.word(0x9000), 0x1234);
+}
+
+#[test]
+fn wide_variable_is_stored_low_byte_first() {
+    let source = "main{
+    wide = 0x1234;
+    next = 0x05;
+    reg HL = wide;
+    [0x9000] = HL;
+}
+";
+    let cpu = run(source, &[]);
+    // SHLD writes L, then H at the next address
+    assert_eq!((cpu.byte(0x8000), cpu.byte(0x8001)), (0x34, 0x12));
+    assert_eq!((cpu.byte(0x9000), cpu.byte(0x9001)), (0x34, 0x12));
+    assert_eq!((cpu.register("H"), cpu.register("L")), (0x12, 0x34));
+}
+
+#[test]
+fn return_halts_with_the_status_in_a() {
+    let source = "main{
+    A = in(0x01);
+    if (A == 0x00) {
+        return 0x01;
+    }
+    return 0x2A;
+}
+";
+    assert_eq!(run(source, &[(0x01, 0x00)]).register("A"), 0x01);
+    assert_eq!(run(source, &[(0x01, 0x07)]).register("A"), 0x2A);
+}
+
+#[test]
+fn if_branches_on_every_operator() {
+    for operator in [">", "<", "==", "!=", ">=", "<="] {
+        let source = format!(
+            "main{{\n    B = in(0x01);\n    C = in(0x02);\n    reg D = 0x00;\n    if (B {} C) {{\n        reg D = 0x01;\n    }}\n}}\n",
+            operator
+        );
+        for (left, right) in [
+            (0x03u8, 0x05u8),
+            (0x05, 0x03),
+            (0x04, 0x04),
+            (0x00, 0xFF),
+            (0xFF, 0x00),
+        ] {
+            let holds = match operator {
+                ">" => left > right,
+                "<" => left < right,
+                "==" => left == right,
+                "!=" => left != right,
+                ">=" => left >= right,
+                _ => left <= right,
+            };
+            let cpu = run(&source, &[(0x01, left), (0x02, right)]);
+            assert_eq!(
+                cpu.register("D"),
+                u8::from(holds),
+                "{:02X}H {} {:02X}H",
+                left,
+                operator,
+                right
+            );
+        }
+    }
+}
+
+#[test]
+fn spilled_operand_keeps_a_pointer_in_hl() {
+    // The first four variables take B-E, so `fifth` lives only in memory
+    let source = "main{
+    first = 0x01;
+    second = 0x02;
+    third = 0x03;
+    fourth = 0x04;
+    fifth = 0x10;
+    reg HL = 0x9000;
+    A = in(0x01);
+    A += fifth;
+    *HL = A;
+    if (A > fifth) {
+        HL++;
+    }
+    *HL = A;
+}
+";
+    let cpu = run(source, &[(0x01, 0x05)]);
+    assert_eq!(cpu.byte(0x9000), 0x15);
+    assert_eq!(cpu.byte(0x9001), 0x15);
+}
+
+#[test]
+fn spilled_variable_is_read_back_after_its_store() {
+    // `fifth` lives only in memory, and its load is dropped after the store
+    let source = "main{
+    first = 0x01;
+    second = 0x02;
+    third = 0x03;
+    fourth = 0x04;
+    fifth = 0x2A;
+    out(0x02) = fifth;
+    out(0x03) = first;
+    out(0x03) = second;
+    out(0x03) = third;
+    out(0x03) = fourth;
+}
+";
+    let cpu = run(source, &[]);
+    assert_eq!(cpu.byte(0x8004), 0x2A);
+    assert_eq!(cpu.outputs[0], (0x02, 0x2A));
+}
+
+#[test]
+fn folding_keeps_the_carry_for_flag_readers() {
+    // 0xFF + 0x01 is known to be 0x00, but the ADD has to run for its carry
+    let cases = [
+        ("complement_carry();\n    A <<< 1;", "A", 0x00),
+        ("decimal_adjust();", "A", 0x66),
+        ("A >>> 1;", "A", 0x80),
+        ("push(PSW);\n    pop(BC);", "C", 0x57),
+    ];
+    for (after, register, expected) in cases {
+        let source = format!(
+            "main{{\n    reg A = 0xFF;\n    reg B = 0x01;\n    A += B;\n    {}\n}}\n",
+            after
+        );
+        assert_eq!(run(&source, &[]).register(register), expected, "{}", after);
+    }
+}
+
+#[test]
+fn folding_keeps_the_carry_past_flag_neutral_statements() {
+    let cases = ["A <<< 1;", "reg C = 0x05;\n    D = C;\n    A <<< 1;"];
+    for after in cases {
+        let source = format!(
+            "main{{\n    reg A = 0xFF;\n    reg B = 0x01;\n    A += B;\n    {}\n}}\n",
+            after
+        );
+        assert_eq!(run(&source, &[]).register("A"), 0x01, "{}", after);
+    }
+    let source = "main{\n    reg A = 0xFF;\n    reg B = 0x01;\n    A += B;\n    adjust();\n}\nfn adjust {\n    decimal_adjust();\n}\n";
+    assert_eq!(run(source, &[]).register("A"), 0x66);
+}
+
+#[test]
+fn folded_register_is_the_one_written_out() {
+    // A = 0x05; once made a variable named A, so out() read the stale copy in B
+    let error = c85c::compile("main{\n    A = 0x05;\n    A += 0x01;\n    out(0x01) = A;\n}\n")
+        .expect_err("A is a register");
+    assert!(error.message.contains("'reg A = ...;'"), "{}", error);
+
+    let source = "main{\n    reg A = 0x05;\n    A += 0x01;\n    out(0x01) = A;\n}\n";
+    assert_eq!(run(source, &[]).outputs, vec![(0x01, 0x06)]);
+}
+
+#[test]
+fn nested_ifs_take_the_right_branches() {
+    let source = "main{
+    B = in(0x01);
+    C = in(0x02);
+    reg D = 0x00;
+    reg E = 0x00;
+    if (B > 0x05) {
+        if (C > 0x05) {
+            reg D = 0x01;
+        } else {
+            reg D = 0x02;
+        }
+        reg E = 0x03;
+    }
+}
+";
+    for (b, c, d, e) in [
+        (0x06, 0x06, 0x01, 0x03),
+        (0x06, 0x05, 0x02, 0x03),
+        (0x05, 0x06, 0x00, 0x00),
+    ] {
+        let cpu = run(source, &[(0x01, b), (0x02, c)]);
+        assert_eq!(
+            (cpu.register("D"), cpu.register("E")),
+            (d, e),
+            "B={:02X}H C={:02X}H",
+            b,
+            c
+        );
+    }
+}
+
+#[test]
+fn goto_user_label_named_like_a_generated_one() {
+    let source = "main{
+    B = in(0x01);
+    reg D = 0x00;
+    if (B > 0x05) {
+        goto SKIP_0;
+    }
+    reg D = 0x01;
+    label SKIP_0;
+    reg E = 0x02;
+}
+";
+    for (b, d) in [(0x06, 0x00), (0x05, 0x01)] {
+        let cpu = run(source, &[(0x01, b)]);
+        assert_eq!(
+            (cpu.register("D"), cpu.register("E")),
+            (d, 0x02),
+            "B={:02X}H",
+            b
+        );
+    }
+}
+
+#[test]
+fn chained_assignment_sets_every_target() {
+    let cpu = run("main{\n    a = b = c = 0x05;\n}\n", &[]);
+    for address in 0x8000..0x8003 {
+        assert_eq!(cpu.byte(address), 0x05, "{:04X}H", address);
+    }
+    for register in ["B", "C", "D"] {
+        assert_eq!(cpu.register(register), 0x05, "{}", register);
+    }
+}
+
+#[test]
+fn reassignment_updates_the_backing_register() {
+    let source = "main{
+    counter = 0x01;
+    counter = 0x02;
+    A = in(0x01);
+    A += counter;
+    out(0x02) = A;
+}
+";
+    let cpu = run(source, &[(0x01, 0x10)]);
+    assert_eq!(cpu.register("B"), 0x02);
+    assert_eq!(cpu.byte(0x8000), 0x02);
+    assert_eq!(cpu.outputs, vec![(0x02, 0x12)]);
+}
+
+#[test]
+fn wide_comparisons_branch_on_the_full_value() {
+    let pairs: [(u16, u16); 6] = [
+        (0x1234, 0x1234),
+        (0x1235, 0x1234),
+        (0x1234, 0x1235),
+        (0x0100, 0x00FF),
+        (0x00FF, 0x0100),
+        (0xFF00, 0x00FF),
+    ];
+    for operator in [">", "<", "==", "!=", ">=", "<="] {
+        let source = format!(
+            "main{{
+    H = in(0x01);
+    L = in(0x02);
+    D = in(0x03);
+    E = in(0x04);
+    reg C = 0x00;
+    if (HL {} DE) {{
+        reg C = 0x01;
+    }}
+}}
+",
+            operator
+        );
+        for (left, right) in pairs {
+            let holds = match operator {
+                ">" => left > right,
+                "<" => left < right,
+                "==" => left == right,
+                "!=" => left != right,
+                ">=" => left >= right,
+                _ => left <= right,
+            };
+            let [left_high, left_low] = left.to_be_bytes();
+            let [right_high, right_low] = right.to_be_bytes();
+            let cpu = run(
+                &source,
+                &[
+                    (0x01, left_high),
+                    (0x02, left_low),
+                    (0x03, right_high),
+                    (0x04, right_low),
+                ],
+            );
+            assert_eq!(
+                cpu.register("C"),
+                u8::from(holds),
+                "{:04X}H {} {:04X}H",
+                left,
+                operator,
+                right
+            );
+        }
+    }
+}
+
+#[test]
+fn wide_variable_compares_against_hl() {
+    let source = "main{
+    total = 0x1234;
+    H = in(0x01);
+    L = in(0x02);
+    reg C = 0x00;
+    if (HL > total) {
+        reg C = 0x01;
+    }
+}
+";
+    for (high, low, greater) in [
+        (0x12, 0x35, 0x01),
+        (0x12, 0x34, 0x00),
+        (0x11, 0xFF, 0x00),
+        (0x13, 0x00, 0x01),
+    ] {
+        let cpu = run(source, &[(0x01, high), (0x02, low)]);
+        assert_eq!(cpu.register("C"), greater, "{:02X}{:02X}H", high, low);
+        assert_eq!((cpu.register("H"), cpu.register("L")), (high, low));
+    }
+}
+
+#[test]
+fn register_is_never_a_variable_name() {
+    // C = D; copies the register, so C = 0x05; can't make a variable named C
+    let error =
+        c85c::compile("main{\n    C = 0x05;\n    D = 0x06;\n    C = D;\n    out(0x01) = C;\n}\n")
+            .expect_err("C is a register");
+    assert!(error.message.contains("'reg C = ...;'"), "{}", error);
+
+    let source = "main{
+    reg C = 0x05;
+    reg D = 0x06;
+    C = D;
+    out(0x01) = C;
+}
+";
+    assert_eq!(run(source, &[]).outputs, vec![(0x01, 0x06)]);
+}
@@ -0,0 +1,139 @@
+// tests/errors.rs
+
+//! Checks the phase, message and source position of compile errors.
+
+use c85c::codegen::GenerateOptions;
+use c85c::lexer::Span;
+use c85c::{CompileError, Phase};
+
+fn error(source: &str) -> CompileError {
+    c85c::compile(source).expect_err("the source has an error")
+}
+
+#[test]
+fn lexer_error_points_at_the_character() {
+    let error = error("main{\n    reg A = 0x01;\n    A += $;\n}\n");
+    assert_eq!(error.phase, Phase::Lex);
+    assert_eq!(
+        error.span,
+        Some(Span {
+            line: 3,
+            column: 10
+        })
+    );
+}
+
+#[test]
+fn hex_prefix_without_digits_is_rejected() {
+    let error = error("main{\n    reg A = 0x;\n}\n");
+    assert_eq!(error.phase, Phase::Lex);
+    assert_eq!(
+        error.span,
+        Some(Span {
+            line: 2,
+            column: 13
+        })
+    );
+    assert_eq!(
+        error.message,
+        "Invalid hex literal '0x' at line 2, column 13. Expected digits after 0x."
+    );
+}
+
+#[test]
+fn parse_error_points_at_the_token() {
+    let error = error("main{\n    reg A = 0x01\n}\n");
+    assert_eq!(error.phase, Phase::Parse);
+    assert_eq!(error.span, Some(Span { line: 3, column: 1 }));
+    assert_eq!(
+        error.to_string(),
+        "Parsing Error: Expected ';' at the end of the statement at line 3, column 1, found RBrace."
+    );
+}
+
+#[test]
+fn error_at_the_end_of_input_has_no_position() {
+    let error = error("main{\n    reg A = 0x01;\n");
+    assert_eq!(error.phase, Phase::Parse);
+    assert_eq!(error.span, None);
+}
+
+#[test]
+fn semantic_error_has_no_position() {
+    let error = error("main{\n    A += missing;\n}\n");
+    assert_eq!(error.phase, Phase::Semantic);
+    assert_eq!(error.span, None);
+}
+
+#[test]
+fn every_parse_error_keeps_its_position() {
+    let errors = c85c::parse_errors("main{\n    reg A = ;\n    reg B = 0x01;\n    reg C = ;\n}\n");
+    let spans: Vec<Option<Span>> = errors.iter().map(|error| error.span).collect();
+    assert_eq!(
+        spans,
+        [
+            Some(Span {
+                line: 2,
+                column: 13
+            }),
+            Some(Span {
+                line: 4,
+                column: 13
+            })
+        ]
+    );
+}
+
+#[test]
+fn render_underlines_the_column() {
+    let source = "main{\n    reg A = 0x01;\n    A += $;\n}\n";
+    let rendered = error(source).render(source);
+    assert!(
+        rendered.ends_with("3 |     A += $;\n  |          ^"),
+        "{}",
+        rendered
+    );
+}
+
+#[test]
+fn main_without_closing_brace_is_rejected() {
+    for source in ["main{\n    reg A = 0x01;\n", "main{"] {
+        let error = error(source);
+        assert!(
+            error
+                .message
+                .starts_with("Expected '}' to close main block"),
+            "{}",
+            error
+        );
+    }
+}
+
+#[test]
+fn print_routine_inside_the_code_is_rejected() {
+    // 30 loads take 60 bytes from ORG 0000H, past the RST 7 vector at 0038H
+    let loads: String = (0..30)
+        .map(|value| format!("    reg C = 0x{:02X};\n", value))
+        .collect();
+    let source = format!("main{{\n{}    print(C);\n}}\n", loads);
+    let error = error(&source);
+    assert_eq!(error.phase, Phase::Codegen);
+    assert!(
+        error
+            .message
+            .starts_with("The print routine at 0038H is inside the code at 0000H-"),
+        "{}",
+        error
+    );
+    assert!(
+        error.message.contains("--print-routine") && error.message.contains("--org"),
+        "{}",
+        error
+    );
+
+    let options = GenerateOptions {
+        print_routine: 0x0500,
+        ..GenerateOptions::default()
+    };
+    assert!(c85c::compile_with_options(&source, &options).is_ok());
+}
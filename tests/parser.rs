@@ -0,0 +1,51 @@
+// tests/parser.rs
+
+//! Checks which programs parse, and the errors for those that don't.
+
+/// Checks `statement` as the body of a main that has set up B
+fn check(statement: &str) -> Result<(), String> {
+    c85c::check(&format!(
+        "main{{\n    reg B = 0x01;\n    {}\n}}\n",
+        statement
+    ))
+    .map_err(|err| err.message)
+}
+
+#[test]
+fn adjacent_operators_parse_or_fail_precisely() {
+    for statement in [
+        "A+B;", "A+=B;", "A++;", "A--;", "A-=B;", "A^B;", "A&=B;", "A<<1;", "A<<<1;", "A>>>1;",
+        "HL++;",
+    ] {
+        assert_eq!(check(statement), Ok(()), "{}", statement);
+    }
+    let errors = [
+        (
+            "A++B;",
+            "Malformed 'A ++ B' at line 3, column 6. '++' takes no operand (HL++;); did you mean 'A += B'?",
+        ),
+        (
+            "A--B;",
+            "Malformed 'A -- B' at line 3, column 6. '--' takes no operand (HL--;); did you mean 'A -= B'?",
+        ),
+        (
+            "A++0x01;",
+            "Malformed 'A ++ ...' at line 3, column 6. '++' takes no operand (HL++;).",
+        ),
+        (
+            "A+++B;",
+            "Ambiguous operator sequence '+++' at line 3, column 6. Separate the operators, e.g. write 'A += B' for a binary operation.",
+        ),
+        (
+            "A---B;",
+            "Ambiguous operator sequence '---' at line 3, column 6. Separate the operators, e.g. write 'A -= B' for a binary operation.",
+        ),
+        (
+            "A+ +B;",
+            "Unexpected operator '+' after '+' at line 3, column 8. A binary operation takes exactly one operator, e.g. A + B.",
+        ),
+    ];
+    for (statement, message) in errors {
+        assert_eq!(check(statement), Err(message.to_string()), "{}", statement);
+    }
+}
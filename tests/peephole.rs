@@ -0,0 +1,68 @@
+// tests/peephole.rs
+
+//! Checks that the peephole pass drops redundant instructions and keeps the
+//! rest.
+
+use c85c::asm::AsmInstruction;
+use c85c::peephole;
+
+fn mov(dest: &str, src: &str) -> AsmInstruction {
+    AsmInstruction::Mov {
+        dest: dest.to_string(),
+        src: src.to_string(),
+    }
+}
+
+/// Optimizes with the default data region
+fn optimize(instructions: Vec<AsmInstruction>) -> Vec<AsmInstruction> {
+    peephole::optimize(instructions, 0x8000..0x8010)
+}
+
+#[test]
+fn move_to_itself_is_dropped() {
+    assert_eq!(
+        optimize(vec![mov("B", "B"), mov("C", "A")]),
+        [mov("C", "A")]
+    );
+}
+
+#[test]
+fn move_back_is_dropped() {
+    assert_eq!(
+        optimize(vec![mov("A", "B"), mov("B", "A")]),
+        [mov("A", "B")]
+    );
+}
+
+#[test]
+fn repeated_move_is_dropped() {
+    assert_eq!(
+        optimize(vec![mov("A", "B"), mov("A", "B"), AsmInstruction::Hlt]),
+        [mov("A", "B"), AsmInstruction::Hlt]
+    );
+}
+
+#[test]
+fn comments_between_moves_are_skipped() {
+    let comment = AsmInstruction::Comment("B = A".to_string());
+    assert_eq!(
+        optimize(vec![mov("A", "B"), comment.clone(), mov("B", "A")]),
+        [mov("A", "B"), comment]
+    );
+}
+
+#[test]
+fn label_between_moves_keeps_both() {
+    let instructions = vec![
+        mov("A", "B"),
+        AsmInstruction::Label("again".to_string()),
+        mov("B", "A"),
+    ];
+    assert_eq!(optimize(instructions.clone()), instructions);
+}
+
+#[test]
+fn moves_of_different_registers_are_kept() {
+    let instructions = vec![mov("A", "B"), mov("C", "A"), mov("B", "C")];
+    assert_eq!(optimize(instructions.clone()), instructions);
+}